@@ -28,7 +28,7 @@ impl From<CanId> for u32 {
             | ((id.group as u32 & 0x3F) << 22)
             | ((id.device_type as u32 & 0x3F) << 16)
             | ((id.device_id as u32) << 8)
-            | id.msg_type as u32 & 0xFF
+            | u8::from(id.msg_type) as u32
     }
 }
 
@@ -71,6 +71,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::unnecessary_fallible_conversions)]
     fn test_device_init() {
         let can_id = CanId::new(0x01, 0x01, CanMessageType::Nightlight);
         let can_id_u32: u32 = can_id.into();
@@ -83,4 +84,15 @@ mod tests {
         let can_id_ext_back = TryInto::<CanId>::try_into(can_id_ext.unwrap());
         assert!(can_id_ext_back.is_ok())
     }
+
+    #[test]
+    fn test_roundtrip() {
+        let can_id = CanId::new(0x01, 0x01, CanMessageType::Nightlight);
+        let can_id_u32: u32 = can_id.into();
+        assert_eq!(CanId::from(can_id_u32), can_id);
+
+        let can_id_ext: ExtendedId = can_id.into();
+        assert_eq!(can_id_ext.as_raw(), can_id_u32);
+        assert_eq!(CanId::from(can_id_ext), can_id)
+    }
 }
@@ -0,0 +1,904 @@
+use crate::can_id::CanId;
+use crate::can_message_type::{CanMessageType, UnknownType};
+use crate::error_report::ErrorReport;
+use crate::relais_message::{
+    from_millis24, millis24, PowerOn, RelaisLimits, RelaisMessage, RelaisStats, RelaisStatus,
+    ShutterStatus,
+};
+use crate::transport::{is_segmented, Frame, TransportError};
+use crate::update::BootState;
+use embassy_time::Duration;
+use heapless::{String, Vec};
+
+/// Raw payload of a single classic CAN frame
+pub type Payload = Vec<u8, 8>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidLength,
+    InvalidValue,
+    InvalidUtf8,
+    UnknownMessageType,
+}
+
+impl From<TransportError> for DecodeError {
+    fn from(_: TransportError) -> Self {
+        DecodeError::InvalidValue
+    }
+}
+
+/// Typed representation of a single frame.
+///
+/// Message types without a specified payload layout carry the raw bytes, long
+/// strings are segmented with the [`transport`](crate::transport) frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanMessage {
+    /// RTR frame asking the receiver for its current value of the given type
+    Request(CanMessageType),
+    /// `announce` is set in the frame sent after boot, ping replies are empty
+    Available {
+        announce: bool,
+    },
+    DeviceError(ErrorReport),
+    Restart,
+    DeviceUid0(u64),
+    DeviceUid1(u64),
+    DeviceIdType {
+        id: u8,
+        device_type: u8,
+    },
+    DeviceGroup(u8),
+    ApplicationVersion(String<8>),
+    Baudrate(u8),
+    /// Uptime in minutes
+    Uptime(u32),
+    CustomString(Frame),
+    /// `CustomString` of older nodes, the text without segmentation and
+    /// padded with zeros. Told apart by [`is_segmented`].
+    LegacyCustomString(String<8>),
+    PwmFrequency(Payload),
+    RequestParameter,
+    ApplicationVersionString(Frame),
+    UpdateSilence(bool),
+    FlashStart {
+        size: u32,
+        crc: u32,
+    },
+    /// Selects the slot the next update is written to, echoed by the device
+    FlashSelect {
+        slot: u8,
+    },
+    /// Erases a slot, echoed by the device when done
+    FlashErase {
+        slot: u8,
+    },
+    /// Asks for `len` bytes of the target slot, answered with `FlashData`
+    FlashRead {
+        offset: u32,
+        len: u8,
+    },
+    /// Four bytes of the target slot, sent as `FlashRead` with a full frame
+    FlashData {
+        offset: u32,
+        data: [u8; 4],
+    },
+    FlashWrite(Payload),
+    /// CRC-32 of the written image read back from flash, answer to an RTR
+    FlashVerify {
+        crc: u32,
+        valid: bool,
+    },
+    /// Acknowledges `FlashStart` and every [`ACK_INTERVAL`](crate::update::ACK_INTERVAL) bytes
+    FlashProgress {
+        written: u32,
+        total: u32,
+    },
+    /// Outcome of the last update, sent after `Available` and on request.
+    /// `slot` is `None` when running the factory image.
+    BootStatus {
+        slot: Option<u8>,
+        state: BootState,
+        attempts: u8,
+    },
+    /// Confirms a pending image, answered with `BootStatus`
+    BootConfirm,
+    /// Switches the started update to multicast, echoed by the device once the
+    /// rest of the slot is erased
+    FlashJoin,
+    /// Four bytes of a multicast image at `index * 4`, counted like `FlashWrite`
+    /// including the header
+    FlashChunk {
+        index: u32,
+        data: [u8; 4],
+    },
+    /// Chunks still missing, bit `n` of `missing` stands for chunk `base + n`.
+    /// Answer to an RTR, followed by `FlashProgress`.
+    FlashGaps {
+        base: u32,
+        missing: u32,
+    },
+    /// Size and CRC-32 of the image, the next `FlashStart` carries it
+    /// compressed. Echoed by the device.
+    FlashCompressed {
+        size: u32,
+        crc: u32,
+    },
+    ButtonEvent(Payload),
+    TemperatureSensor(Payload),
+    HwRev(u8),
+    ExtensionMode(u8),
+    LampGroup(Payload),
+    PirSensor(Payload),
+    HumiditySensor(Payload),
+    Relais(RelaisMessage),
+    RelaisState(RelaisStatus),
+    Rollershutter(RelaisMessage),
+    RollershutterState(ShutterStatus),
+    RelaisMode(u8),
+    /// Moves a shutter to `percent` closed, see [`shutter`](crate::shutter)
+    ShutterPosition {
+        num: u8,
+        bank: u8,
+        percent: u8,
+    },
+    /// Full travel times of a shutter, zero if unknown. Durations have 24
+    /// bits in milliseconds like in [`RelaisMessage`]. An RTR is answered
+    /// with one frame per shutter.
+    ShutterTiming {
+        num: u8,
+        bank: u8,
+        up: Duration,
+        down: Duration,
+    },
+    /// Turns the slats of a venetian blind to `percent` closed
+    ShutterTilt {
+        num: u8,
+        bank: u8,
+        percent: u8,
+    },
+    /// Time the slats need to turn from open to closed, zero for shutters
+    /// without slats. 24 bits in milliseconds, an RTR is answered with one
+    /// frame per shutter.
+    ShutterSlatTiming {
+        num: u8,
+        bank: u8,
+        turn: Duration,
+    },
+    /// Pause of all shutter motors between opposite directions, 16 bits in
+    /// milliseconds. Applies at once and is kept across restarts.
+    ShutterDeadTime(Duration),
+    /// What a relay does after power-on, kept across restarts. An RTR is
+    /// answered with one frame per channel.
+    RelaisPowerOn {
+        num: u8,
+        bank: u8,
+        power_on: PowerOn,
+    },
+    /// Safety limits of a channel, kept across restarts. An RTR is answered
+    /// with one frame per channel.
+    RelaisLimits {
+        num: u8,
+        bank: u8,
+        limits: RelaisLimits,
+    },
+    /// Switching cycles as u32 and on-time in whole hours as u16 of a
+    /// channel. An RTR is answered with one frame per channel, a frame sets
+    /// the counters, e.g. to zero after the relay was replaced.
+    RelaisStats {
+        num: u8,
+        bank: u8,
+        stats: RelaisStats,
+    },
+    /// Cycles after which a channel reports its relay as worn, 0 for never.
+    /// Applies to all channels and is kept across restarts.
+    RelaisCycleLimit(u32),
+    /// Segment of a [`RelaisMap`](crate::relais_map::RelaisMap) in its
+    /// byte layout. A written map takes effect on the next start.
+    RelaisMap(Frame),
+    AmbientLightSensor(Payload),
+    AmbientLightSensorWhite(Payload),
+    Nightlight(Payload),
+    PressureSensor(Payload),
+    Co2Equivalent(Payload),
+    VocBreath(Payload),
+    AirQuality(Payload),
+    LogDownload(Frame),
+    Ping,
+    PingDisable(Payload),
+    /// Frame of a message type this crate does not know, kept for forwarding
+    Unknown {
+        msg_type: UnknownType,
+        data: Payload,
+    },
+}
+
+impl CanMessage {
+    pub fn msg_type(&self) -> CanMessageType {
+        use CanMessage::*;
+        match self {
+            Request(msg_type) => *msg_type,
+            Available { .. } => CanMessageType::Available,
+            DeviceError(_) => CanMessageType::DeviceError,
+            Restart => CanMessageType::Restart,
+            DeviceUid0(_) => CanMessageType::DeviceUid0,
+            DeviceUid1(_) => CanMessageType::DeviceUid1,
+            DeviceIdType { .. } => CanMessageType::DeviceIdType,
+            DeviceGroup(_) => CanMessageType::DeviceGroup,
+            ApplicationVersion(_) => CanMessageType::ApplicationVersion,
+            Baudrate(_) => CanMessageType::Baudrate,
+            Uptime(_) => CanMessageType::Uptime,
+            CustomString(_) | LegacyCustomString(_) => CanMessageType::CustomString,
+            PwmFrequency(_) => CanMessageType::PwmFrequency,
+            RequestParameter => CanMessageType::RequestParameter,
+            ApplicationVersionString(_) => CanMessageType::ApplicationVersionString,
+            UpdateSilence(_) => CanMessageType::UpdateSilence,
+            FlashStart { .. } => CanMessageType::FlashStart,
+            FlashSelect { .. } => CanMessageType::FlashSelect,
+            FlashErase { .. } => CanMessageType::FlashErase,
+            FlashRead { .. } | FlashData { .. } => CanMessageType::FlashRead,
+            FlashWrite(_) => CanMessageType::FlashWrite,
+            FlashVerify { .. } => CanMessageType::FlashVerify,
+            FlashProgress { .. } => CanMessageType::FlashProgress,
+            BootStatus { .. } => CanMessageType::BootStatus,
+            BootConfirm => CanMessageType::BootConfirm,
+            FlashJoin => CanMessageType::FlashJoin,
+            FlashChunk { .. } => CanMessageType::FlashChunk,
+            FlashGaps { .. } => CanMessageType::FlashGaps,
+            FlashCompressed { .. } => CanMessageType::FlashCompressed,
+            ButtonEvent(_) => CanMessageType::ButtonEvent,
+            TemperatureSensor(_) => CanMessageType::TemperatureSensor,
+            HwRev(_) => CanMessageType::HwRev,
+            ExtensionMode(_) => CanMessageType::ExtensionMode,
+            LampGroup(_) => CanMessageType::LampGroup,
+            PirSensor(_) => CanMessageType::PirSensor,
+            HumiditySensor(_) => CanMessageType::HumiditySensor,
+            Relais(_) => CanMessageType::Relais,
+            RelaisState(_) => CanMessageType::RelaisState,
+            Rollershutter(_) => CanMessageType::Rollershutter,
+            RollershutterState(_) => CanMessageType::RollershutterState,
+            RelaisMode(_) => CanMessageType::RelaisMode,
+            ShutterPosition { .. } => CanMessageType::ShutterPosition,
+            ShutterTiming { .. } => CanMessageType::ShutterTiming,
+            ShutterTilt { .. } => CanMessageType::ShutterTilt,
+            ShutterSlatTiming { .. } => CanMessageType::ShutterSlatTiming,
+            ShutterDeadTime(_) => CanMessageType::ShutterDeadTime,
+            RelaisPowerOn { .. } => CanMessageType::RelaisPowerOn,
+            RelaisLimits { .. } => CanMessageType::RelaisLimits,
+            RelaisStats { .. } => CanMessageType::RelaisStats,
+            RelaisCycleLimit(_) => CanMessageType::RelaisCycleLimit,
+            RelaisMap(_) => CanMessageType::RelaisMap,
+            AmbientLightSensor(_) => CanMessageType::AmbientLightSensor,
+            AmbientLightSensorWhite(_) => CanMessageType::AmbientLightSensorWhite,
+            Nightlight(_) => CanMessageType::Nightlight,
+            PressureSensor(_) => CanMessageType::PressureSensor,
+            Co2Equivalent(_) => CanMessageType::Co2Equivalent,
+            VocBreath(_) => CanMessageType::VocBreath,
+            AirQuality(_) => CanMessageType::AirQuality,
+            LogDownload(_) => CanMessageType::LogDownload,
+            Ping => CanMessageType::Ping,
+            PingDisable(_) => CanMessageType::PingDisable,
+            Unknown { msg_type, .. } => CanMessageType::Unknown(*msg_type),
+        }
+    }
+
+    /// Whether the message has to be sent as RTR frame
+    pub fn is_remote(&self) -> bool {
+        matches!(self, CanMessage::Request(_))
+    }
+
+    pub fn payload(&self) -> Payload {
+        use CanMessage::*;
+        let mut payload = Payload::new();
+        match self {
+            Request(_) | Restart | RequestParameter | BootConfirm | FlashJoin | Ping => {}
+            Available { announce } => {
+                if *announce {
+                    push(&mut payload, &[1]);
+                }
+            }
+            DeviceError(report) => push(&mut payload, &report.to_bytes()),
+            DeviceUid0(uid) | DeviceUid1(uid) => push(&mut payload, &uid.to_le_bytes()),
+            DeviceIdType { id, device_type } => push(&mut payload, &[*id, *device_type]),
+            DeviceGroup(val) | Baudrate(val) | HwRev(val) | ExtensionMode(val)
+            | RelaisMode(val) => push(&mut payload, &[*val]),
+            ApplicationVersion(s) | LegacyCustomString(s) => push(&mut payload, s.as_bytes()),
+            CustomString(frame)
+            | ApplicationVersionString(frame)
+            | LogDownload(frame)
+            | RelaisMap(frame) => payload = frame.to_bytes(),
+            Uptime(minutes) => push(&mut payload, &minutes.to_le_bytes()),
+            UpdateSilence(silence) => push(&mut payload, &[*silence as u8]),
+            FlashStart { size, crc } | FlashCompressed { size, crc } => {
+                push(&mut payload, &size.to_le_bytes());
+                push(&mut payload, &crc.to_le_bytes());
+            }
+            FlashSelect { slot } | FlashErase { slot } => push(&mut payload, &[*slot]),
+            FlashRead { offset, len } => {
+                push(&mut payload, &offset.to_le_bytes());
+                push(&mut payload, &[*len]);
+            }
+            FlashData { offset, data } => {
+                push(&mut payload, &offset.to_le_bytes());
+                push(&mut payload, data);
+            }
+            FlashChunk { index, data } => {
+                push(&mut payload, &index.to_le_bytes());
+                push(&mut payload, data);
+            }
+            FlashGaps { base, missing } => {
+                push(&mut payload, &base.to_le_bytes());
+                push(&mut payload, &missing.to_le_bytes());
+            }
+            FlashVerify { crc, valid } => {
+                push(&mut payload, &crc.to_le_bytes());
+                push(&mut payload, &[*valid as u8]);
+            }
+            FlashProgress { written, total } => {
+                push(&mut payload, &written.to_le_bytes());
+                push(&mut payload, &total.to_le_bytes());
+            }
+            BootStatus {
+                slot,
+                state,
+                attempts,
+            } => push(
+                &mut payload,
+                &[slot.unwrap_or(0xFF), *state as u8, *attempts],
+            ),
+            Relais(msg) | Rollershutter(msg) => push(&mut payload, &msg.to_bytes()),
+            RelaisState(status) => push(&mut payload, &status.to_bytes()),
+            RollershutterState(status) => push(&mut payload, &status.to_bytes()),
+            ShutterPosition { num, bank, percent } | ShutterTilt { num, bank, percent } => {
+                push(&mut payload, &[*num, *bank, *percent])
+            }
+            ShutterTiming {
+                num,
+                bank,
+                up,
+                down,
+            } => {
+                push(&mut payload, &[*num, *bank]);
+                push(&mut payload, &millis24(*up));
+                push(&mut payload, &millis24(*down));
+            }
+            ShutterDeadTime(dead_time) => {
+                let ms = dead_time.as_millis().min(u16::MAX as u64) as u16;
+                push(&mut payload, &ms.to_le_bytes())
+            }
+            ShutterSlatTiming { num, bank, turn } => {
+                push(&mut payload, &[*num, *bank]);
+                push(&mut payload, &millis24(*turn));
+            }
+            RelaisPowerOn {
+                num,
+                bank,
+                power_on,
+            } => push(&mut payload, &[*num, *bank, *power_on as u8]),
+            RelaisLimits { num, bank, limits } => {
+                push(&mut payload, &[*num, *bank]);
+                push(&mut payload, &limits.to_bytes());
+            }
+            RelaisStats { num, bank, stats } => {
+                let hours = stats.on_time.as_secs() / 3600;
+                push(&mut payload, &[*num, *bank]);
+                push(&mut payload, &stats.cycles.to_le_bytes());
+                push(
+                    &mut payload,
+                    &(hours.min(u16::MAX as u64) as u16).to_le_bytes(),
+                );
+            }
+            RelaisCycleLimit(cycles) => push(&mut payload, &cycles.to_le_bytes()),
+            PwmFrequency(raw)
+            | FlashWrite(raw)
+            | ButtonEvent(raw)
+            | TemperatureSensor(raw)
+            | LampGroup(raw)
+            | PirSensor(raw)
+            | HumiditySensor(raw)
+            | AmbientLightSensor(raw)
+            | AmbientLightSensorWhite(raw)
+            | Nightlight(raw)
+            | PressureSensor(raw)
+            | Co2Equivalent(raw)
+            | VocBreath(raw)
+            | AirQuality(raw)
+            | PingDisable(raw)
+            | Unknown { data: raw, .. } => payload = raw.clone(),
+        }
+        payload
+    }
+
+    /// Builds the id and payload of the frame sent by (or to) the given device
+    pub fn encode(&self, device_type: u8, device_id: u8) -> (CanId, Payload) {
+        (
+            CanId::new(device_type, device_id, self.msg_type()),
+            self.payload(),
+        )
+    }
+
+    pub fn decode(id: CanId, data: &[u8], rtr: bool) -> Result<Self, DecodeError> {
+        use CanMessageType as T;
+        if rtr {
+            if id.msg_type == T::InvalidMessage {
+                return Err(DecodeError::UnknownMessageType);
+            }
+            return Ok(CanMessage::Request(id.msg_type));
+        }
+
+        let msg = match id.msg_type {
+            T::Available => CanMessage::Available {
+                announce: data == [1],
+            },
+            T::DeviceError => CanMessage::DeviceError(
+                ErrorReport::try_from(data).map_err(|_| DecodeError::InvalidLength)?,
+            ),
+            T::Restart => CanMessage::Restart,
+            T::DeviceUid0 => CanMessage::DeviceUid0(u64::from_le_bytes(exact(data)?)),
+            T::DeviceUid1 => CanMessage::DeviceUid1(u64::from_le_bytes(exact(data)?)),
+            T::DeviceIdType => {
+                let [id, device_type] = exact(data)?;
+                CanMessage::DeviceIdType { id, device_type }
+            }
+            T::DeviceGroup => CanMessage::DeviceGroup(byte(data)?),
+            T::ApplicationVersion => CanMessage::ApplicationVersion(string(data)?),
+            T::Baudrate => CanMessage::Baudrate(byte(data)?),
+            T::Uptime => CanMessage::Uptime(u32::from_le_bytes(exact(data)?)),
+            T::CustomString if is_segmented(data) => {
+                CanMessage::CustomString(Frame::from_bytes(data)?)
+            }
+            T::CustomString => CanMessage::LegacyCustomString(string(data)?),
+            T::PwmFrequency => CanMessage::PwmFrequency(raw(data)?),
+            T::RequestParameter => CanMessage::RequestParameter,
+            T::ApplicationVersionString => {
+                CanMessage::ApplicationVersionString(Frame::from_bytes(data)?)
+            }
+            T::UpdateSilence => match byte(data)? {
+                0 => CanMessage::UpdateSilence(false),
+                1 => CanMessage::UpdateSilence(true),
+                _ => return Err(DecodeError::InvalidValue),
+            },
+            T::FlashStart => {
+                let (size, crc) = u32_pair(data)?;
+                CanMessage::FlashStart { size, crc }
+            }
+            T::FlashSelect => CanMessage::FlashSelect { slot: byte(data)? },
+            T::FlashErase => CanMessage::FlashErase { slot: byte(data)? },
+            T::FlashRead => match data.len() {
+                5 => CanMessage::FlashRead {
+                    offset: u32::from_le_bytes(data[0..4].try_into().unwrap()),
+                    len: data[4],
+                },
+                8 => CanMessage::FlashData {
+                    offset: u32::from_le_bytes(data[0..4].try_into().unwrap()),
+                    data: data[4..8].try_into().unwrap(),
+                },
+                _ => return Err(DecodeError::InvalidLength),
+            },
+            T::FlashWrite => CanMessage::FlashWrite(raw(data)?),
+            T::FlashVerify => {
+                let [c0, c1, c2, c3, valid] = exact(data)?;
+                CanMessage::FlashVerify {
+                    crc: u32::from_le_bytes([c0, c1, c2, c3]),
+                    valid: valid == 1,
+                }
+            }
+            T::FlashProgress => {
+                let (written, total) = u32_pair(data)?;
+                CanMessage::FlashProgress { written, total }
+            }
+            T::BootStatus => {
+                let [slot, state, attempts] = exact(data)?;
+                CanMessage::BootStatus {
+                    slot: (slot != 0xFF).then_some(slot),
+                    state: BootState::try_from(state).map_err(|_| DecodeError::InvalidValue)?,
+                    attempts,
+                }
+            }
+            T::BootConfirm => CanMessage::BootConfirm,
+            T::FlashJoin => CanMessage::FlashJoin,
+            T::FlashChunk => {
+                let [i0, i1, i2, i3, d0, d1, d2, d3] = exact(data)?;
+                CanMessage::FlashChunk {
+                    index: u32::from_le_bytes([i0, i1, i2, i3]),
+                    data: [d0, d1, d2, d3],
+                }
+            }
+            T::FlashGaps => {
+                let (base, missing) = u32_pair(data)?;
+                CanMessage::FlashGaps { base, missing }
+            }
+            T::FlashCompressed => {
+                let (size, crc) = u32_pair(data)?;
+                CanMessage::FlashCompressed { size, crc }
+            }
+            T::ButtonEvent => CanMessage::ButtonEvent(raw(data)?),
+            T::TemperatureSensor => CanMessage::TemperatureSensor(raw(data)?),
+            T::HwRev => CanMessage::HwRev(byte(data)?),
+            T::ExtensionMode => CanMessage::ExtensionMode(byte(data)?),
+            T::LampGroup => CanMessage::LampGroup(raw(data)?),
+            T::PirSensor => CanMessage::PirSensor(raw(data)?),
+            T::HumiditySensor => CanMessage::HumiditySensor(raw(data)?),
+            T::Relais => CanMessage::Relais(RelaisMessage::from_bytes(data)?),
+            T::RelaisState => CanMessage::RelaisState(RelaisStatus::from_bytes(data)?),
+            T::Rollershutter => CanMessage::Rollershutter(RelaisMessage::from_bytes(data)?),
+            T::RollershutterState => {
+                CanMessage::RollershutterState(ShutterStatus::from_bytes(data)?)
+            }
+            T::RelaisMode => CanMessage::RelaisMode(byte(data)?),
+            T::ShutterPosition => {
+                let [num, bank, percent] = exact(data)?;
+                CanMessage::ShutterPosition { num, bank, percent }
+            }
+            T::ShutterTiming => {
+                let [num, bank, u0, u1, u2, d0, d1, d2] = exact(data)?;
+                CanMessage::ShutterTiming {
+                    num,
+                    bank,
+                    up: from_millis24([u0, u1, u2]),
+                    down: from_millis24([d0, d1, d2]),
+                }
+            }
+            T::ShutterTilt => {
+                let [num, bank, percent] = exact(data)?;
+                CanMessage::ShutterTilt { num, bank, percent }
+            }
+            T::ShutterSlatTiming => {
+                let [num, bank, t0, t1, t2] = exact(data)?;
+                CanMessage::ShutterSlatTiming {
+                    num,
+                    bank,
+                    turn: from_millis24([t0, t1, t2]),
+                }
+            }
+            T::ShutterDeadTime => {
+                let ms = u16::from_le_bytes(exact(data)?);
+                CanMessage::ShutterDeadTime(Duration::from_millis(ms as u64))
+            }
+            T::RelaisPowerOn => {
+                let [num, bank, power_on] = exact(data)?;
+                CanMessage::RelaisPowerOn {
+                    num,
+                    bank,
+                    power_on: PowerOn::try_from(power_on).map_err(|_| DecodeError::InvalidValue)?,
+                }
+            }
+            T::RelaisLimits => {
+                let [num, bank, limits @ ..]: [u8; 6] = exact(data)?;
+                CanMessage::RelaisLimits {
+                    num,
+                    bank,
+                    limits: RelaisLimits::from_bytes(&limits)?,
+                }
+            }
+            T::RelaisStats => {
+                let [num, bank, c0, c1, c2, c3, h0, h1] = exact(data)?;
+                let hours = u16::from_le_bytes([h0, h1]) as u64;
+                CanMessage::RelaisStats {
+                    num,
+                    bank,
+                    stats: RelaisStats {
+                        cycles: u32::from_le_bytes([c0, c1, c2, c3]),
+                        on_time: Duration::from_secs(hours * 3600),
+                    },
+                }
+            }
+            T::RelaisCycleLimit => CanMessage::RelaisCycleLimit(u32::from_le_bytes(exact(data)?)),
+            T::RelaisMap => CanMessage::RelaisMap(Frame::from_bytes(data)?),
+            T::AmbientLightSensor => CanMessage::AmbientLightSensor(raw(data)?),
+            T::AmbientLightSensorWhite => CanMessage::AmbientLightSensorWhite(raw(data)?),
+            T::Nightlight => CanMessage::Nightlight(raw(data)?),
+            T::PressureSensor => CanMessage::PressureSensor(raw(data)?),
+            T::Co2Equivalent => CanMessage::Co2Equivalent(raw(data)?),
+            T::VocBreath => CanMessage::VocBreath(raw(data)?),
+            T::AirQuality => CanMessage::AirQuality(raw(data)?),
+            T::LogDownload => CanMessage::LogDownload(Frame::from_bytes(data)?),
+            T::Ping => CanMessage::Ping,
+            T::PingDisable => CanMessage::PingDisable(raw(data)?),
+            T::InvalidMessage => return Err(DecodeError::UnknownMessageType),
+            T::Unknown(msg_type) => CanMessage::Unknown {
+                msg_type,
+                data: raw(data)?,
+            },
+        };
+        Ok(msg)
+    }
+}
+
+fn push(payload: &mut Payload, bytes: &[u8]) {
+    // all typed payloads fit into a single frame
+    payload.extend_from_slice(bytes).unwrap();
+}
+
+fn exact<const N: usize>(data: &[u8]) -> Result<[u8; N], DecodeError> {
+    data.try_into().map_err(|_| DecodeError::InvalidLength)
+}
+
+fn byte(data: &[u8]) -> Result<u8, DecodeError> {
+    let [val] = exact(data)?;
+    Ok(val)
+}
+
+fn u32_pair(data: &[u8]) -> Result<(u32, u32), DecodeError> {
+    if data.len() < 8 {
+        return Err(DecodeError::InvalidLength);
+    }
+    Ok((
+        u32::from_le_bytes(data[0..4].try_into().unwrap()),
+        u32::from_le_bytes(data[4..8].try_into().unwrap()),
+    ))
+}
+
+fn raw(data: &[u8]) -> Result<Payload, DecodeError> {
+    Payload::from_slice(data).map_err(|_| DecodeError::InvalidLength)
+}
+
+/// Strings are zero padded to the full frame length
+fn string(data: &[u8]) -> Result<String<8>, DecodeError> {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let s = core::str::from_utf8(&data[..len]).map_err(|_| DecodeError::InvalidUtf8)?;
+    String::try_from(s).map_err(|_| DecodeError::InvalidLength)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_report::{Component, ErrorCode, Severity};
+    use crate::relais_message::RelaisState;
+    use crate::transport::FlowStatus;
+
+    fn roundtrip(msg: CanMessage) {
+        let (id, payload) = msg.encode(0x12, 0x34);
+        assert_eq!(id.device_type, 0x12);
+        assert_eq!(id.device_id, 0x34);
+
+        let raw: u32 = id.into();
+        let decoded = CanMessage::decode(CanId::from(raw), &payload, msg.is_remote());
+        assert_eq!(decoded, Ok(msg));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let relais = RelaisMessage {
+            num: 3,
+            state: RelaisState::Up,
+            duration: Duration::from_millis(12_000),
+            bank: 1,
+            flags: 0,
+        };
+
+        roundtrip(CanMessage::Request(CanMessageType::Uptime));
+        roundtrip(CanMessage::Available { announce: true });
+        roundtrip(CanMessage::Available { announce: false });
+        roundtrip(CanMessage::DeviceError(ErrorReport::new(
+            Component::Ota,
+            ErrorCode::InvalidData,
+            Severity::RecoverableError,
+            4,
+            &[1, 2, 3, 4],
+        )));
+        roundtrip(CanMessage::Restart);
+        roundtrip(CanMessage::DeviceUid0(0x0011_2233_4455_6677));
+        roundtrip(CanMessage::DeviceUid1(u64::MAX));
+        roundtrip(CanMessage::DeviceIdType {
+            id: 7,
+            device_type: 2,
+        });
+        roundtrip(CanMessage::DeviceGroup(5));
+        roundtrip(CanMessage::ApplicationVersion(
+            String::try_from("v1.2-3").unwrap(),
+        ));
+        roundtrip(CanMessage::Baudrate(2));
+        roundtrip(CanMessage::Uptime(60 * 24));
+        roundtrip(CanMessage::CustomString(Frame::Single(
+            Vec::from_slice(b"kitchen").unwrap(),
+        )));
+        roundtrip(CanMessage::LegacyCustomString(
+            String::try_from("0 floor").unwrap(),
+        ));
+        roundtrip(CanMessage::ApplicationVersionString(Frame::First {
+            len: 20,
+            data: Vec::from_slice(b"v0.1.0").unwrap(),
+        }));
+        roundtrip(CanMessage::RequestParameter);
+        roundtrip(CanMessage::UpdateSilence(true));
+        roundtrip(CanMessage::FlashStart {
+            size: 0x10_0000,
+            crc: 0xDEAD_BEEF,
+        });
+        roundtrip(CanMessage::FlashProgress {
+            written: 64,
+            total: 0x10_0000,
+        });
+        roundtrip(CanMessage::FlashSelect { slot: 1 });
+        roundtrip(CanMessage::FlashErase { slot: 0 });
+        roundtrip(CanMessage::FlashRead {
+            offset: 0x1234,
+            len: 16,
+        });
+        roundtrip(CanMessage::FlashData {
+            offset: 0x1234,
+            data: [0xE9, 3, 2, 0x20],
+        });
+        roundtrip(CanMessage::FlashVerify {
+            crc: 0xCBF4_3926,
+            valid: true,
+        });
+        roundtrip(CanMessage::FlashWrite(
+            Payload::from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap(),
+        ));
+        roundtrip(CanMessage::BootStatus {
+            slot: Some(1),
+            state: BootState::Pending,
+            attempts: 2,
+        });
+        roundtrip(CanMessage::BootStatus {
+            slot: None,
+            state: BootState::RolledBack,
+            attempts: 3,
+        });
+        roundtrip(CanMessage::BootConfirm);
+        roundtrip(CanMessage::FlashJoin);
+        roundtrip(CanMessage::FlashCompressed {
+            size: 0x0F_0000,
+            crc: 0x1234_5678,
+        });
+        roundtrip(CanMessage::FlashChunk {
+            index: 0x4_0001,
+            data: [1, 2, 3, 4],
+        });
+        roundtrip(CanMessage::FlashGaps {
+            base: 64,
+            missing: 0x8000_0001,
+        });
+        roundtrip(CanMessage::HwRev(3));
+        roundtrip(CanMessage::ExtensionMode(1));
+        roundtrip(CanMessage::Relais(relais));
+        roundtrip(CanMessage::Rollershutter(relais));
+        roundtrip(CanMessage::RelaisMode(2));
+        roundtrip(CanMessage::RollershutterState(ShutterStatus {
+            num: 3,
+            state: RelaisState::Down,
+            position: None,
+            bank: 1,
+            tilt: Some(30),
+            remaining: Duration::from_millis(2_500),
+        }));
+        roundtrip(CanMessage::RelaisState(RelaisStatus {
+            num: 7,
+            state: RelaisState::On,
+            remaining: Duration::from_millis(60_000),
+            bank: 0,
+        }));
+        roundtrip(CanMessage::ShutterPosition {
+            num: 3,
+            bank: 1,
+            percent: 40,
+        });
+        roundtrip(CanMessage::ShutterTiming {
+            num: 3,
+            bank: 1,
+            up: Duration::from_millis(23_500),
+            down: Duration::from_millis(21_000),
+        });
+        roundtrip(CanMessage::ShutterTilt {
+            num: 3,
+            bank: 1,
+            percent: 75,
+        });
+        roundtrip(CanMessage::ShutterDeadTime(Duration::from_millis(800)));
+        roundtrip(CanMessage::ShutterSlatTiming {
+            num: 3,
+            bank: 1,
+            turn: Duration::from_millis(1_400),
+        });
+        roundtrip(CanMessage::RelaisPowerOn {
+            num: 5,
+            bank: 1,
+            power_on: PowerOn::Last,
+        });
+        roundtrip(CanMessage::RelaisLimits {
+            num: 3,
+            bank: 0,
+            limits: RelaisLimits {
+                max_on: Duration::from_secs(7200),
+                min_off: Duration::from_secs(180),
+            },
+        });
+        roundtrip(CanMessage::RelaisStats {
+            num: 7,
+            bank: 1,
+            stats: RelaisStats {
+                cycles: 123_456,
+                on_time: Duration::from_secs(42 * 3600),
+            },
+        });
+        roundtrip(CanMessage::RelaisCycleLimit(100_000));
+        roundtrip(CanMessage::RelaisMap(Frame::Consecutive {
+            seq: 3,
+            data: Vec::from_slice(&[0x20, 0x21, 0, 1, 2, 3, 4]).unwrap(),
+        }));
+        roundtrip(CanMessage::LogDownload(Frame::FlowControl {
+            status: FlowStatus::ContinueToSend,
+            block_size: 0,
+            st_min: 0,
+        }));
+        roundtrip(CanMessage::Ping);
+        roundtrip(CanMessage::Unknown {
+            msg_type: UnknownType::new(200).unwrap(),
+            data: Payload::from_slice(&[0xAA, 0x55]).unwrap(),
+        });
+        roundtrip(CanMessage::Request(CanMessageType::Unknown(
+            UnknownType::new(201).unwrap(),
+        )));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let id = |msg_type| CanId::new(1, 1, msg_type);
+
+        assert_eq!(
+            CanMessage::decode(id(CanMessageType::DeviceUid0), &[1, 2, 3], false),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            CanMessage::decode(id(CanMessageType::Relais), &[1, 1], false),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            CanMessage::decode(id(CanMessageType::FlashRead), &[0, 0, 0, 0], false),
+            Err(DecodeError::InvalidLength)
+        );
+        assert_eq!(
+            CanMessage::decode(id(CanMessageType::UpdateSilence), &[2], false),
+            Err(DecodeError::InvalidValue)
+        );
+        assert_eq!(
+            CanMessage::decode(id(CanMessageType::RelaisPowerOn), &[0, 0, 3], false),
+            Err(DecodeError::InvalidValue)
+        );
+        assert_eq!(
+            CanMessage::decode(id(CanMessageType::ApplicationVersion), &[0xFF], false),
+            Err(DecodeError::InvalidUtf8)
+        );
+        assert_eq!(
+            CanMessage::decode(id(CanMessageType::InvalidMessage), &[], false),
+            Err(DecodeError::UnknownMessageType)
+        );
+    }
+
+    #[test]
+    fn test_decode_padded_string() {
+        let id = CanId::new(1, 1, CanMessageType::ApplicationVersion);
+        let msg = CanMessage::decode(id, b"v0.1\0\0\0\0", false);
+        assert_eq!(
+            msg,
+            Ok(CanMessage::ApplicationVersion(
+                String::try_from("v0.1").unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_decode_legacy_custom_string() {
+        let id = CanId::new(1, 1, CanMessageType::CustomString);
+        // old nodes answer with the text padded to 8 bytes
+        assert_eq!(
+            CanMessage::decode(id, b"hallway ", false),
+            Ok(CanMessage::LegacyCustomString(
+                String::try_from("hallway").unwrap()
+            ))
+        );
+        assert_eq!(
+            CanMessage::decode(id, b"1st room", false),
+            Ok(CanMessage::LegacyCustomString(
+                String::try_from("1st room").unwrap()
+            ))
+        );
+        assert_eq!(
+            CanMessage::decode(id, &[0x83, b'a', b'b', b'c'], false),
+            Ok(CanMessage::CustomString(Frame::Single(
+                Vec::from_slice(b"abc").unwrap()
+            )))
+        );
+    }
+}
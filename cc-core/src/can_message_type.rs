@@ -1,51 +1,91 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
+/// Message type encoded in the lowest byte of the [`CanId`](crate::can_id::CanId).
+///
+/// Codes without a dedicated variant are kept as `Unknown`, so converting any
+/// `u8` to a `CanMessageType` and back yields the original value. Every code
+/// has exactly one representation, see [`UnknownType`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CanMessageType {
-    Available = 0,
-    DeviceError = 1,
-    Restart = 2,
-    DeviceUid0 = 3,
-    DeviceUid1 = 4,
-    DeviceIdType = 5,
-    DeviceGroup = 6,
-    ApplicationVersion = 7,
-    Baudrate = 8,
-    Uptime = 9,
-    CustomString = 10,
-    PwmFrequency = 11,
-    RequestParameter = 12,
-    ApplicationVersionString = 13,
-    UpdateSilence = 14,
-    FlashStart = 15,
-    FlashSelect = 16,
-    FlashErase = 17,
-    FlashRead = 18,
-    FlashWrite = 19,
-    FlashVerify = 20,
-    FlashProgress = 21,
-    ButtonEvent = 30,
-    TemperatureSensor = 31,
-    HwRev = 41,
-    ExtensionMode = 42,
-    LampGroup = 90,
-    PirSensor = 128,
-    HumiditySensor = 129,
-    Relais = 130,
-    RelaisState = 131,
-    Rollershutter = 132,
-    RollershutterState = 133,
-    RelaisMode = 134,
-    AmbientLightSensor = 140,
-    AmbientLightSensorWhite = 141,
-    Nightlight = 150,
-    PressureSensor = 151,
-    Co2Equivalent = 152,
-    VocBreath = 153,
-    AirQuality = 154,
-    LogDownload = 155,
-    Ping = 156,
-    PingDisable = 157,
-    InvalidMessage = 255,
+    Available,
+    DeviceError,
+    Restart,
+    DeviceUid0,
+    DeviceUid1,
+    DeviceIdType,
+    DeviceGroup,
+    ApplicationVersion,
+    Baudrate,
+    Uptime,
+    CustomString,
+    PwmFrequency,
+    RequestParameter,
+    ApplicationVersionString,
+    UpdateSilence,
+    FlashStart,
+    FlashSelect,
+    FlashErase,
+    FlashRead,
+    FlashWrite,
+    FlashVerify,
+    FlashProgress,
+    BootStatus,
+    BootConfirm,
+    FlashJoin,
+    FlashChunk,
+    FlashGaps,
+    FlashCompressed,
+    ButtonEvent,
+    TemperatureSensor,
+    HwRev,
+    ExtensionMode,
+    LampGroup,
+    PirSensor,
+    HumiditySensor,
+    Relais,
+    RelaisState,
+    Rollershutter,
+    RollershutterState,
+    RelaisMode,
+    ShutterPosition,
+    ShutterTiming,
+    ShutterTilt,
+    ShutterSlatTiming,
+    ShutterDeadTime,
+    RelaisPowerOn,
+    RelaisLimits,
+    RelaisStats,
+    RelaisCycleLimit,
+    RelaisMap,
+    AmbientLightSensor,
+    AmbientLightSensorWhite,
+    Nightlight,
+    PressureSensor,
+    Co2Equivalent,
+    VocBreath,
+    AirQuality,
+    LogDownload,
+    Ping,
+    PingDisable,
+    InvalidMessage,
+    Unknown(UnknownType),
+}
+
+/// Code without a named [`CanMessageType`]. It can only be built from such
+/// codes, so a named type is never encoded through `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UnknownType(u8);
+
+impl UnknownType {
+    /// `None` if `code` has a named variant
+    pub fn new(code: u8) -> Option<Self> {
+        match CanMessageType::from(code) {
+            CanMessageType::Unknown(unknown) => Some(unknown),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self.0
+    }
 }
 
 impl From<u8> for CanMessageType {
@@ -67,11 +107,19 @@ impl From<u8> for CanMessageType {
             12 => RequestParameter,
             13 => ApplicationVersionString,
             14 => UpdateSilence,
+            15 => FlashStart,
             16 => FlashSelect,
             17 => FlashErase,
             18 => FlashRead,
             19 => FlashWrite,
             20 => FlashVerify,
+            21 => FlashProgress,
+            22 => BootStatus,
+            23 => BootConfirm,
+            24 => FlashJoin,
+            25 => FlashChunk,
+            26 => FlashGaps,
+            27 => FlashCompressed,
             30 => ButtonEvent,
             31 => TemperatureSensor,
             41 => HwRev,
@@ -84,6 +132,16 @@ impl From<u8> for CanMessageType {
             132 => Rollershutter,
             133 => RollershutterState,
             134 => RelaisMode,
+            135 => ShutterPosition,
+            136 => ShutterTiming,
+            137 => ShutterTilt,
+            138 => ShutterSlatTiming,
+            139 => ShutterDeadTime,
+            142 => RelaisPowerOn,
+            143 => RelaisLimits,
+            144 => RelaisStats,
+            145 => RelaisCycleLimit,
+            146 => RelaisMap,
             140 => AmbientLightSensor,
             141 => AmbientLightSensorWhite,
             150 => Nightlight,
@@ -94,7 +152,123 @@ impl From<u8> for CanMessageType {
             155 => LogDownload,
             156 => Ping,
             157 => PingDisable,
-            _ => InvalidMessage,
+            255 => InvalidMessage,
+            other => Unknown(UnknownType(other)),
+        }
+    }
+}
+
+impl From<CanMessageType> for u8 {
+    fn from(value: CanMessageType) -> Self {
+        use CanMessageType::*;
+        match value {
+            Available => 0,
+            DeviceError => 1,
+            Restart => 2,
+            DeviceUid0 => 3,
+            DeviceUid1 => 4,
+            DeviceIdType => 5,
+            DeviceGroup => 6,
+            ApplicationVersion => 7,
+            Baudrate => 8,
+            Uptime => 9,
+            CustomString => 10,
+            PwmFrequency => 11,
+            RequestParameter => 12,
+            ApplicationVersionString => 13,
+            UpdateSilence => 14,
+            FlashStart => 15,
+            FlashSelect => 16,
+            FlashErase => 17,
+            FlashRead => 18,
+            FlashWrite => 19,
+            FlashVerify => 20,
+            FlashProgress => 21,
+            BootStatus => 22,
+            BootConfirm => 23,
+            FlashJoin => 24,
+            FlashChunk => 25,
+            FlashGaps => 26,
+            FlashCompressed => 27,
+            ButtonEvent => 30,
+            TemperatureSensor => 31,
+            HwRev => 41,
+            ExtensionMode => 42,
+            LampGroup => 90,
+            PirSensor => 128,
+            HumiditySensor => 129,
+            Relais => 130,
+            RelaisState => 131,
+            Rollershutter => 132,
+            RollershutterState => 133,
+            RelaisMode => 134,
+            ShutterPosition => 135,
+            ShutterTiming => 136,
+            ShutterTilt => 137,
+            ShutterSlatTiming => 138,
+            ShutterDeadTime => 139,
+            RelaisPowerOn => 142,
+            RelaisLimits => 143,
+            RelaisStats => 144,
+            RelaisCycleLimit => 145,
+            RelaisMap => 146,
+            AmbientLightSensor => 140,
+            AmbientLightSensorWhite => 141,
+            Nightlight => 150,
+            PressureSensor => 151,
+            Co2Equivalent => 152,
+            VocBreath => 153,
+            AirQuality => 154,
+            LogDownload => 155,
+            Ping => 156,
+            PingDisable => 157,
+            InvalidMessage => 255,
+            Unknown(UnknownType(other)) => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_all_values() {
+        for value in 0..=u8::MAX {
+            let msg_type = CanMessageType::from(value);
+            assert_eq!(u8::from(msg_type), value);
+        }
+    }
+
+    #[test]
+    fn test_unknown_values() {
+        let known = (0..=u8::MAX)
+            .filter(|&value| !matches!(CanMessageType::from(value), CanMessageType::Unknown(_)))
+            .count();
+        assert_eq!(known, 61);
+
+        assert_eq!(CanMessageType::from(15), CanMessageType::FlashStart);
+        assert_eq!(CanMessageType::from(21), CanMessageType::FlashProgress);
+        assert_eq!(CanMessageType::from(23), CanMessageType::BootConfirm);
+        assert_eq!(CanMessageType::from(26), CanMessageType::FlashGaps);
+        assert_eq!(
+            CanMessageType::from(28),
+            CanMessageType::Unknown(UnknownType::new(28).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_unknown_is_canonical() {
+        assert_eq!(UnknownType::new(22), None);
+        assert_eq!(UnknownType::new(255), None);
+        for value in 0..=u8::MAX {
+            if let Some(unknown) = UnknownType::new(value) {
+                assert_eq!(unknown.code(), value);
+                assert_eq!(
+                    CanMessageType::from(value),
+                    CanMessageType::Unknown(unknown)
+                );
+            }
         }
     }
 }
@@ -0,0 +1,131 @@
+//! Chunks received by a device in a multicast update.
+//!
+//! The device marks every stored `FlashChunk`, the missing ones are reported
+//! with `FlashGaps` in windows of [`WINDOW`] chunks starting at a multiple of
+//! it. The bitmap has a fixed size so it can live in a static on the device.
+
+/// Chunks per `FlashGaps` frame
+pub const WINDOW: u32 = 32;
+
+pub struct ChunkMap<const WORDS: usize> {
+    words: [u32; WORDS],
+    len: u32,
+    /// Every chunk below is received
+    contiguous: u32,
+}
+
+impl<const WORDS: usize> ChunkMap<WORDS> {
+    pub const CAPACITY: u32 = WORDS as u32 * WINDOW;
+
+    pub const fn new() -> Self {
+        Self {
+            words: [0; WORDS],
+            len: 0,
+            contiguous: 0,
+        }
+    }
+
+    /// Starts over with `len` chunks of which the first `received` are already
+    /// stored. `false` if `len` exceeds [`CAPACITY`](Self::CAPACITY).
+    pub fn reset(&mut self, len: u32, received: u32) -> bool {
+        if len > Self::CAPACITY {
+            return false;
+        }
+        let received = received.min(len);
+        for (i, word) in self.words.iter_mut().enumerate() {
+            let base = i as u32 * WINDOW;
+            *word = mask(received.saturating_sub(base));
+        }
+        self.len = len;
+        self.contiguous = received;
+        true
+    }
+
+    /// Marks a chunk, `false` if it was already there or is out of range
+    pub fn insert(&mut self, index: u32) -> bool {
+        if index >= self.len || self.contains(index) {
+            return false;
+        }
+        self.words[(index / WINDOW) as usize] |= 1 << (index % WINDOW);
+        while self.contiguous < self.len && self.contains(self.contiguous) {
+            self.contiguous += 1;
+        }
+        true
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        index < self.len && self.words[(index / WINDOW) as usize] & (1 << (index % WINDOW)) != 0
+    }
+
+    /// Number of chunks received without a gap
+    pub fn contiguous(&self) -> u32 {
+        self.contiguous
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.contiguous == self.len
+    }
+
+    /// Windows with missing chunks as `(base, missing)` like in `FlashGaps`
+    pub fn gaps(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let used = self.len.div_ceil(WINDOW) as usize;
+        let first = (self.contiguous / WINDOW) as usize;
+        self.words[first..used]
+            .iter()
+            .zip(first..)
+            .filter_map(move |(&word, i)| {
+                let base = i as u32 * WINDOW;
+                let missing = !word & mask(self.len - base);
+                (missing != 0).then_some((base, missing))
+            })
+    }
+}
+
+impl<const WORDS: usize> Default for ChunkMap<WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bits of the first `n` chunks of a window
+fn mask(n: u32) -> u32 {
+    if n >= WINDOW {
+        u32::MAX
+    } else {
+        (1 << n) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gaps() {
+        let mut map = ChunkMap::<4>::new();
+        assert!(!map.reset(200, 0));
+        assert!(map.reset(100, 40));
+        assert_eq!(map.contiguous(), 40);
+
+        for index in (40..100).filter(|i| i % 10 != 0) {
+            assert!(map.insert(index));
+        }
+        assert!(!map.insert(41));
+        assert!(!map.insert(100));
+        assert_eq!(map.contiguous(), 40);
+        // the last window only has 4 chunks, all received
+        assert!(map.gaps().eq([
+            (32, 1 << 8 | 1 << 18 | 1 << 28),
+            (64, 1 << 6 | 1 << 16 | 1 << 26),
+        ]));
+
+        for index in [40, 50, 60, 70, 80] {
+            map.insert(index);
+        }
+        assert_eq!(map.contiguous(), 90);
+        assert!(map.gaps().eq([(64, 1 << 26)]));
+        map.insert(90);
+        assert!(map.is_complete());
+        assert_eq!(map.gaps().count(), 0);
+    }
+}
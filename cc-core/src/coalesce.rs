@@ -0,0 +1,78 @@
+//! Bounds how often a value that changes a lot is written to flash.
+//!
+//! Every change marks the value as dirty. It is written at once if the last
+//! write is at least one interval ago, otherwise when the interval is over,
+//! with all changes in between in one write.
+
+use embassy_time::{Duration, Instant};
+
+pub struct WriteCoalescer {
+    interval: Duration,
+    dirty: bool,
+    last_write: Option<Instant>,
+}
+
+impl WriteCoalescer {
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            dirty: false,
+            last_write: None,
+        }
+    }
+
+    /// The value changed and has to be written
+    pub fn changed(&mut self) {
+        self.dirty = true;
+    }
+
+    /// When the next write is due, `None` if the stored value is current
+    pub fn due(&self) -> Option<Instant> {
+        self.dirty.then(|| {
+            self.last_write
+                .map_or(Instant::MIN, |last_write| last_write + self.interval)
+        })
+    }
+
+    /// Whether the value has to be written now. The caller marks it as
+    /// changed again if the write fails.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.due().is_none_or(|due| due > now) {
+            return false;
+        }
+        self.dirty = false;
+        self.last_write = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> Instant {
+        Instant::from_secs(secs)
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut coalescer = WriteCoalescer::new(Duration::from_secs(30));
+        assert_eq!(coalescer.due(), None);
+        assert!(!coalescer.poll(at(1)));
+
+        coalescer.changed();
+        assert!(coalescer.poll(at(1)));
+        assert!(!coalescer.poll(at(2)));
+
+        // changes within the interval end up in one write
+        coalescer.changed();
+        coalescer.changed();
+        assert_eq!(coalescer.due(), Some(at(31)));
+        assert!(!coalescer.poll(at(30)));
+        assert!(coalescer.poll(at(31)));
+        assert_eq!(coalescer.due(), None);
+
+        coalescer.changed();
+        assert!(coalescer.poll(at(100)));
+    }
+}
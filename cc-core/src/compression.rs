@@ -0,0 +1,164 @@
+//! Compressed firmware stream.
+//!
+//! After `FlashCompressed` the next `FlashStart` carries a compressed stream
+//! instead of the image: the [`ImageHeader`](crate::image_header::ImageHeader)
+//! as is, then one frame per [`BLOCK_LEN`] bytes of the image. A frame is the
+//! payload length as `u16`, the LZ4 block or with [`STORED`] set the bytes
+//! themselves, and zero padding up to the next multiple of
+//! [`ACK_INTERVAL`] in the stream. Frames therefore start where the host may
+//! continue an interrupted update, and each one fills a whole flash sector.
+//!
+//! `FlashStart` holds size and CRC-32 of the stream, `FlashCompressed` those of
+//! the image. The device checks both.
+use crate::update::{ACK_INTERVAL, RESUME_INTERVAL};
+
+/// Image bytes per frame, one flash sector
+pub const BLOCK_LEN: usize = RESUME_INTERVAL as usize;
+/// Flag in the length of a frame whose payload is not compressed
+pub const STORED: u16 = 0x8000;
+const LEN_PREFIX: usize = 2;
+/// Longest frame: a stored block with the worst padding
+pub const MAX_FRAME_LEN: usize = LEN_PREFIX + BLOCK_LEN + ACK_INTERVAL as usize - 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompressionError {
+    /// The frame is longer than a block allows
+    InvalidLength,
+    /// The payload does not expand to the expected block
+    Corrupt,
+}
+
+/// Length of a frame starting at stream offset `start` with `payload` bytes
+pub fn frame_len(start: u32, payload: usize) -> usize {
+    let start = start as usize;
+    (start + LEN_PREFIX + payload).next_multiple_of(ACK_INTERVAL as usize) - start
+}
+
+/// Collects the frames of a compressed stream and expands them
+pub struct Decoder {
+    frame: [u8; MAX_FRAME_LEN],
+    fill: usize,
+    start: u32,
+    block: [u8; BLOCK_LEN],
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            frame: [0; MAX_FRAME_LEN],
+            fill: 0,
+            start: 0,
+            block: [0; BLOCK_LEN],
+        }
+    }
+
+    /// Drops a partial frame, the next one starts at stream offset `start`
+    pub fn reset(&mut self, start: u32) {
+        self.start = start;
+        self.fill = 0;
+    }
+
+    /// Stream offset of the frame being collected
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// Appends bytes of the stream, `true` once the frame is complete
+    pub fn push(&mut self, data: &[u8]) -> Result<bool, CompressionError> {
+        let end = self.fill + data.len();
+        if end > MAX_FRAME_LEN {
+            return Err(CompressionError::InvalidLength);
+        }
+        self.frame[self.fill..end].copy_from_slice(data);
+        self.fill = end;
+        let Some((_, payload)) = self.payload() else {
+            return Ok(false);
+        };
+        if payload > BLOCK_LEN {
+            return Err(CompressionError::InvalidLength);
+        }
+        let len = frame_len(self.start, payload);
+        if self.fill > len {
+            return Err(CompressionError::InvalidLength);
+        }
+        Ok(self.fill == len)
+    }
+
+    /// The complete frame as received, part of the stream CRC
+    pub fn frame(&self) -> &[u8] {
+        &self.frame[..self.fill]
+    }
+
+    /// Expands the complete frame to `len` bytes of the image and moves on to
+    /// the next frame
+    pub fn decode(&mut self, len: usize) -> Result<&[u8], CompressionError> {
+        let (stored, payload) = self.payload().ok_or(CompressionError::InvalidLength)?;
+        self.start += self.fill as u32;
+        self.fill = 0;
+        let data = &self.frame[LEN_PREFIX..LEN_PREFIX + payload];
+        if stored {
+            return if payload == len {
+                Ok(data)
+            } else {
+                Err(CompressionError::Corrupt)
+            };
+        }
+        match lz4_flex::block::decompress_into(data, &mut self.block[..len]) {
+            Ok(n) if n == len => Ok(&self.block[..len]),
+            _ => Err(CompressionError::Corrupt),
+        }
+    }
+
+    /// Stored flag and payload length, once the length is there
+    fn payload(&self) -> Option<(bool, usize)> {
+        let prefix = self
+            .frame
+            .get(..LEN_PREFIX)
+            .filter(|_| self.fill >= LEN_PREFIX)?;
+        let raw = u16::from_le_bytes([prefix[0], prefix[1]]);
+        Some((raw & STORED != 0, (raw & !STORED) as usize))
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut decoder = Decoder::new();
+        decoder.reset(8);
+        // 5 stored bytes, padded to the end of the first block
+        let mut frame = [0u8; 56];
+        frame[..7].copy_from_slice(&[5, 0x80, 1, 2, 3, 4, 5]);
+        assert_eq!(frame_len(8, 5), 56);
+        assert_eq!(decoder.push(&frame[..8]), Ok(false));
+        for chunk in frame[8..].chunks(8) {
+            decoder.push(chunk).unwrap();
+        }
+        assert_eq!(decoder.frame(), &frame);
+        assert_eq!(decoder.decode(5), Ok(&[1, 2, 3, 4, 5][..]));
+        assert_eq!(decoder.start(), 64);
+
+        // the literal `abc`, then 9 bytes copied from 3 back
+        let lz4 = [0x35, b'a', b'b', b'c', 3, 0, 0x10, b'x'];
+        let mut frame = [0u8; 64];
+        frame[..2].copy_from_slice(&(lz4.len() as u16).to_le_bytes());
+        frame[2..10].copy_from_slice(&lz4);
+        assert_eq!(decoder.push(&frame), Ok(true));
+        assert_eq!(decoder.decode(13), Ok(&b"abcabcabcabcx"[..]));
+
+        decoder.push(&frame).unwrap();
+        assert_eq!(decoder.decode(12), Err(CompressionError::Corrupt));
+        assert_eq!(
+            decoder.push(&[0xFF, 0x7F]),
+            Err(CompressionError::InvalidLength)
+        );
+    }
+}
@@ -0,0 +1,49 @@
+/// CRC-32 (IEEE) as used by `esp-hal-ota` to check a complete image
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues `crc` over `buf`, start with 0. Same as `esp_hal_ota::crc32::calc_crc32`.
+pub fn update(crc: u32, buf: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in buf {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub fn crc32(buf: &[u8]) -> u32 {
+    update(0, buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let chained = data.chunks(8).fold(0, update);
+        assert_eq!(chained, crc32(data));
+        assert_eq!(chained, 0x414F_A339);
+    }
+}
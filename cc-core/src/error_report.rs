@@ -0,0 +1,201 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub component: Component,
+    pub code: ErrorCode,
+    pub severity: Severity,
+    pub local_code: u8,
+    pub details: [u8; 4],
+}
+
+impl ErrorReport {
+    pub fn new(
+        component: Component,
+        code: ErrorCode,
+        severity: Severity,
+        local_code: u8,
+        details: &[u8],
+    ) -> Self {
+        let mut d = [0u8; 4];
+        d[..details.len().min(4)].copy_from_slice(&details[..details.len().min(4)]);
+        Self {
+            component,
+            code,
+            severity,
+            local_code,
+            details: d,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 8] {
+        [
+            self.component as u8,
+            self.code as u8,
+            self.severity as u8,
+            self.local_code,
+            self.details[0],
+            self.details[1],
+            self.details[2],
+            self.details[3],
+        ]
+    }
+}
+
+impl TryFrom<&[u8]> for ErrorReport {
+    type Error = ();
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() != 8 {
+            return Err(());
+        }
+
+        Ok(Self {
+            component: Component::from(value[0]),
+            code: ErrorCode::from(value[1]),
+            severity: Severity::from(value[2]),
+            local_code: value[3],
+            details: [value[4], value[5], value[6], value[7]],
+        })
+    }
+}
+
+/// Report kept in the device log, sent on `LogDownload` back to back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogEntry {
+    /// Seconds since the start of the device
+    pub uptime: u32,
+    pub report: ErrorReport,
+}
+
+impl LogEntry {
+    pub const LEN: usize = 12;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[..4].copy_from_slice(&self.uptime.to_le_bytes());
+        data[4..].copy_from_slice(&self.report.to_bytes());
+        data
+    }
+
+    /// Splits a downloaded log, an incomplete trailing entry is dropped
+    pub fn parse(data: &[u8]) -> impl Iterator<Item = LogEntry> + '_ {
+        data.chunks_exact(Self::LEN).filter_map(|entry| {
+            Some(LogEntry {
+                uptime: u32::from_le_bytes(entry[..4].try_into().ok()?),
+                report: ErrorReport::try_from(&entry[4..]).ok()?,
+            })
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Unknown = 0,
+    InvalidData = 1,
+}
+
+impl From<u8> for ErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ErrorCode::InvalidData,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    Unknown = 0,
+    Warning = 1,
+    RecoverableError = 2,
+    RepeatingError = 3,
+    Error = 4,
+    CriticalError = 5,
+}
+
+impl From<u8> for Severity {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Severity::Warning,
+            2 => Severity::RecoverableError,
+            3 => Severity::RepeatingError,
+            4 => Severity::Error,
+            5 => Severity::CriticalError,
+            _ => Severity::Unknown,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Component {
+    Unknown = 0,
+    Can = 1,
+    Device = 2,
+    Update = 3,
+    Storage = 4,
+    Ota = 5,
+    Relais = 6,
+}
+
+impl From<u8> for Component {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Component::Can,
+            2 => Component::Device,
+            3 => Component::Update,
+            4 => Component::Storage,
+            5 => Component::Ota,
+            6 => Component::Relais,
+            _ => Component::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_severity_roundtrip() {
+        for value in 0..=5u8 {
+            assert_eq!(Severity::from(value) as u8, value);
+        }
+    }
+
+    #[test]
+    fn test_log_entries() {
+        let entries = [
+            LogEntry {
+                uptime: 17,
+                report: ErrorReport::new(
+                    Component::Can,
+                    ErrorCode::InvalidData,
+                    Severity::Warning,
+                    2,
+                    &[155],
+                ),
+            },
+            LogEntry {
+                uptime: 86_400,
+                report: ErrorReport::new(
+                    Component::Relais,
+                    ErrorCode::Unknown,
+                    Severity::Error,
+                    9,
+                    &[1, 2, 3, 4],
+                ),
+            },
+        ];
+        let mut data = [0u8; 2 * LogEntry::LEN + 5];
+        data[..12].copy_from_slice(&entries[0].to_bytes());
+        data[12..24].copy_from_slice(&entries[1].to_bytes());
+        let parsed: [LogEntry; 2] = {
+            let mut it = LogEntry::parse(&data);
+            [it.next().unwrap(), it.next().unwrap()]
+        };
+        assert_eq!(parsed, entries);
+        assert_eq!(LogEntry::parse(&data).count(), 2);
+    }
+}
@@ -0,0 +1,230 @@
+//! Driver for the 8-bit I2C I/O expanders switching the relays.
+//!
+//! The expanders have the registers of a TCA9554: input, output latch,
+//! polarity and configuration. Every write is read back, a register that
+//! reads back differently is a fault. After a brown-out an expander comes
+//! back with all pins as inputs, so every switch first checks the
+//! configuration and sets the expander up again if it was lost.
+
+use embedded_hal_async::i2c::I2c;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    /// Level of the pins
+    Input = 0,
+    /// Level the output pins are driven to
+    Output = 1,
+    Polarity = 2,
+    /// 1 for an input pin, 0 for an output
+    Config = 3,
+}
+
+/// Configuration with every pin driving a relay
+const ALL_OUTPUTS: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpanderError {
+    /// No acknowledge or a stuck bus, the expander may be missing
+    Bus,
+    /// The register reads back a different value than written
+    Mismatch {
+        register: Register,
+        written: u8,
+        read: u8,
+    },
+}
+
+/// One expander and the outputs last written to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expander {
+    address: u8,
+    outputs: u8,
+}
+
+impl Expander {
+    /// All outputs are off until the first [`set`](Self::set)
+    pub const fn new(address: u8) -> Self {
+        Self {
+            address,
+            outputs: 0,
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Output latch as last written, also if that write failed
+    pub fn outputs(&self) -> u8 {
+        self.outputs
+    }
+
+    pub async fn read<I: I2c>(&self, i2c: &mut I, register: Register) -> Result<u8, ExpanderError> {
+        let mut value = [0];
+        i2c.write_read(self.address, &[register as u8], &mut value)
+            .await
+            .map_err(|_| ExpanderError::Bus)?;
+        Ok(value[0])
+    }
+
+    /// Writes a register and reads it back
+    pub async fn write<I: I2c>(
+        &self,
+        i2c: &mut I,
+        register: Register,
+        value: u8,
+    ) -> Result<(), ExpanderError> {
+        i2c.write(self.address, &[register as u8, value])
+            .await
+            .map_err(|_| ExpanderError::Bus)?;
+        let read = self.read(i2c, register).await?;
+        if read != value {
+            return Err(ExpanderError::Mismatch {
+                register,
+                written: value,
+                read,
+            });
+        }
+        Ok(())
+    }
+
+    /// Makes every pin an output. The latch is written first, so no relay
+    /// switches while the pins turn into outputs.
+    pub async fn init<I: I2c>(&mut self, i2c: &mut I) -> Result<(), ExpanderError> {
+        self.write(i2c, Register::Output, self.outputs).await?;
+        self.write(i2c, Register::Config, ALL_OUTPUTS).await
+    }
+
+    /// Switches the relay on `bit`. Returns `true` if the expander had lost
+    /// its configuration and was set up again.
+    pub async fn set<I: I2c>(
+        &mut self,
+        i2c: &mut I,
+        bit: u8,
+        on: bool,
+    ) -> Result<bool, ExpanderError> {
+        let reset = self.read(i2c, Register::Config).await? != ALL_OUTPUTS;
+        let mask = 1 << bit;
+        if on {
+            self.outputs |= mask;
+        } else {
+            self.outputs &= !mask;
+        }
+        if reset {
+            self.init(i2c).await?;
+        } else {
+            self.write(i2c, Register::Output, self.outputs).await?;
+        }
+        Ok(reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+
+    const ADDRESS: u8 = 0x26;
+    /// Registers after power-on, all pins are inputs
+    const POWER_ON: [u8; 4] = [0xFF, 0xFF, 0x00, 0xFF];
+
+    struct MockI2c {
+        registers: [u8; 4],
+        pointer: usize,
+        fitted: bool,
+        /// Bits of the output latch that never change
+        stuck: u8,
+    }
+
+    impl MockI2c {
+        fn new() -> Self {
+            Self {
+                registers: POWER_ON,
+                pointer: 0,
+                fitted: true,
+                stuck: 0,
+            }
+        }
+    }
+
+    impl ErrorType for MockI2c {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockI2c {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            if address != ADDRESS || !self.fitted {
+                return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+            }
+            for operation in operations {
+                match operation {
+                    Operation::Write(data) => {
+                        self.pointer = data[0] as usize & 0x03;
+                        if let Some(&value) = data.get(1) {
+                            let current = self.registers[self.pointer];
+                            self.registers[self.pointer] = match self.pointer {
+                                1 => value & !self.stuck | current & self.stuck,
+                                _ => value,
+                            };
+                        }
+                    }
+                    Operation::Read(buffer) => buffer[0] = self.registers[self.pointer],
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_set_after_reset() {
+        let mut i2c = MockI2c::new();
+        let mut expander = Expander::new(ADDRESS);
+        block_on(expander.init(&mut i2c)).unwrap();
+        assert_eq!(i2c.registers[Register::Config as usize], 0x00);
+        assert_eq!(i2c.registers[Register::Output as usize], 0x00);
+
+        assert_eq!(block_on(expander.set(&mut i2c, 3, true)), Ok(false));
+        assert_eq!(block_on(expander.set(&mut i2c, 6, true)), Ok(false));
+        assert_eq!(i2c.registers[Register::Output as usize], 0x48);
+
+        // brown-out, the outputs come back as they were before
+        i2c.registers = POWER_ON;
+        assert_eq!(block_on(expander.set(&mut i2c, 3, false)), Ok(true));
+        assert_eq!(i2c.registers[Register::Config as usize], 0x00);
+        assert_eq!(i2c.registers[Register::Output as usize], 0x40);
+        assert_eq!(expander.outputs(), 0x40);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut i2c = MockI2c::new();
+        let mut expander = Expander::new(ADDRESS);
+        block_on(expander.init(&mut i2c)).unwrap();
+
+        i2c.stuck = 0x02;
+        assert_eq!(
+            block_on(expander.set(&mut i2c, 1, true)),
+            Err(ExpanderError::Mismatch {
+                register: Register::Output,
+                written: 0x02,
+                read: 0x00
+            })
+        );
+
+        i2c.fitted = false;
+        assert_eq!(
+            block_on(expander.set(&mut i2c, 0, true)),
+            Err(ExpanderError::Bus)
+        );
+        assert_eq!(
+            block_on(Expander::new(0x27).read(&mut MockI2c::new(), Register::Input)),
+            Err(ExpanderError::Bus)
+        );
+    }
+}
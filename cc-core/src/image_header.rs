@@ -0,0 +1,129 @@
+//! Compatibility header in front of a firmware image.
+//!
+//! The first `FlashWrite` chunk of an image is an [`ImageHeader`]: [`MAGIC`],
+//! the protocol version, the range of supported `HwRev` values and up to
+//! [`MAX_DEVICE_TYPES`] device types, unused entries are 0xFF. The device
+//! checks it before erasing anything and does not write it to the slot, the
+//! bootloader expects the application at the start. CRC and signature cover
+//! the header as well.
+use crate::update::{CHUNK_LEN, PROTOCOL_VERSION};
+use heapless::Vec;
+
+pub const HEADER_LEN: usize = CHUNK_LEN;
+pub const MAGIC: u8 = 0xCC;
+pub const MAX_DEVICE_TYPES: usize = 4;
+const UNUSED: u8 = 0xFF;
+
+/// Why an image does not fit, sent as detail of `UpdateErrorCode::Incompatible`
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The image does not start with a header
+    Missing = 0,
+    Protocol = 1,
+    DeviceType = 2,
+    HwRev = 3,
+}
+
+impl TryFrom<u8> for HeaderError {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(HeaderError::Missing),
+            1 => Ok(HeaderError::Protocol),
+            2 => Ok(HeaderError::DeviceType),
+            3 => Ok(HeaderError::HwRev),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// [`PROTOCOL_VERSION`] the image speaks
+    pub protocol: u8,
+    pub hw_rev_min: u8,
+    pub hw_rev_max: u8,
+    pub device_types: Vec<u8, MAX_DEVICE_TYPES>,
+}
+
+impl ImageHeader {
+    /// Header for the given device types and any hardware revision
+    pub fn new(device_types: &[u8]) -> Option<Self> {
+        Some(Self {
+            protocol: PROTOCOL_VERSION,
+            hw_rev_min: 0,
+            hw_rev_max: u8::MAX,
+            device_types: Vec::from_slice(device_types).ok()?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [UNUSED; HEADER_LEN];
+        buf[0] = MAGIC;
+        buf[1] = self.protocol;
+        buf[2] = self.hw_rev_min;
+        buf[3] = self.hw_rev_max;
+        buf[4..4 + self.device_types.len()].copy_from_slice(&self.device_types);
+        buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, HeaderError> {
+        if data.len() < HEADER_LEN || data[0] != MAGIC {
+            return Err(HeaderError::Missing);
+        }
+        let device_types = data[4..HEADER_LEN]
+            .iter()
+            .copied()
+            .filter(|&device_type| device_type != UNUSED)
+            .collect();
+        Ok(Self {
+            protocol: data[1],
+            hw_rev_min: data[2],
+            hw_rev_max: data[3],
+            device_types,
+        })
+    }
+
+    /// Whether the image runs on a device of this type and hardware revision
+    pub fn check(&self, device_type: u8, hw_rev: u8) -> Result<(), HeaderError> {
+        if self.protocol != PROTOCOL_VERSION {
+            return Err(HeaderError::Protocol);
+        }
+        if !self.device_types.contains(&device_type) {
+            return Err(HeaderError::DeviceType);
+        }
+        if !(self.hw_rev_min..=self.hw_rev_max).contains(&hw_rev) {
+            return Err(HeaderError::HwRev);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let mut header = ImageHeader::new(&[4, 5]).unwrap();
+        header.hw_rev_min = 2;
+        header.hw_rev_max = 3;
+        let bytes = header.to_bytes();
+        assert_eq!(bytes, [MAGIC, PROTOCOL_VERSION, 2, 3, 4, 5, 0xFF, 0xFF]);
+        assert_eq!(ImageHeader::from_bytes(&bytes), Ok(header.clone()));
+
+        assert_eq!(header.check(5, 2), Ok(()));
+        assert_eq!(header.check(6, 2), Err(HeaderError::DeviceType));
+        assert_eq!(header.check(4, 4), Err(HeaderError::HwRev));
+        header.protocol += 1;
+        assert_eq!(header.check(4, 3), Err(HeaderError::Protocol));
+
+        assert_eq!(
+            ImageHeader::from_bytes(&[0xE9, 0, 0, 0, 0, 0, 0, 0]),
+            Err(HeaderError::Missing)
+        );
+        assert!(ImageHeader::new(&[1, 2, 3, 4, 5]).is_none());
+    }
+}
@@ -1,5 +1,19 @@
 #![no_std]
 pub mod can_id;
+pub mod can_message;
 pub mod can_message_type;
+pub mod chunk_map;
+pub mod coalesce;
+pub mod compression;
+pub mod crc32;
 pub mod device_message;
+pub mod error_report;
+pub mod expander;
+pub mod image_header;
+pub mod relais_manager;
+pub mod relais_map;
 pub mod relais_message;
+pub mod shutter;
+pub mod signature;
+pub mod transport;
+pub mod update;
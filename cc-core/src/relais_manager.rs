@@ -0,0 +1,791 @@
+use crate::relais_message::{RelaisLimits, RelaisState, RelaisStats};
+use crate::shutter::{Shutter, ShutterTiming};
+use embassy_time::{Duration, Instant};
+use heapless::{Entry, FnvIndexMap};
+
+const ZERO: Duration = Duration::from_millis(0);
+/// Pause of a shutter motor between opposite directions if none is configured
+pub const DEFAULT_DEAD_TIME: Duration = Duration::from_millis(500);
+/// Shorter dead times are raised to this
+pub const MIN_DEAD_TIME: Duration = Duration::from_millis(100);
+/// Cycle limit if none is configured, the electrical life of common relays
+pub const DEFAULT_CYCLE_LIMIT: u32 = 100_000;
+
+/// Limit a channel ran into, see [`RelaisLimits`] and
+/// [`set_cycle_limit`](RelayManager::set_cycle_limit)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// Forced off after `max_on`
+    MaxOnTime,
+    /// Switching on waits for the rest of `min_off`
+    MinOffTime,
+    /// The relay switched as often as the cycle limit
+    CycleLimit,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ActiveRelais {
+    pub current: RelaisState,
+    pub scheduled: Option<(Instant, RelaisState)>,
+    /// Command waiting for the dead time of a motor reversal
+    deferred: Option<(Instant, RelaisState, Duration)>,
+    /// Direction the motor last ran in and when it stopped
+    stopped: Option<(RelaisState, Instant)>,
+    limits: RelaisLimits,
+    /// When the channel last switched on or off, `None` since start
+    changed: Option<Instant>,
+    violation: Option<Violation>,
+    /// On-time without the current switch-on
+    stats: RelaisStats,
+    cycle_limit: Option<u32>,
+}
+
+impl ActiveRelais {
+    /// Executes a command. Returns the state if the output has to be written.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        command: RelaisState,
+        duration: embassy_time::Duration,
+    ) -> Option<RelaisState> {
+        use RelaisState::*;
+        let timed = duration != ZERO;
+        let (state, scheduled) = match command {
+            Toggle if self.current == Off => (On, timed.then_some((now + duration, Off))),
+            Toggle => (Off, None),
+            // back afterwards, a running timer is dropped
+            Pulse if timed => (On, Some((now + duration, self.current))),
+            Pulse => return None,
+            DelayedOn | DelayedOff if timed => {
+                let target = if command == DelayedOn { On } else { Off };
+                self.scheduled = Some((now + duration, target));
+                return None;
+            }
+            DelayedOn => (On, None),
+            DelayedOff => (Off, None),
+            state => (state, timed.then_some((now + duration, Off))),
+        };
+        let changed = state != self.current || timed;
+        self.set_current(state, now);
+        self.scheduled = scheduled;
+        changed.then_some(state)
+    }
+
+    /// Like [`update`](Self::update), but a motor only reverses after it
+    /// stood still for `dead_time`. Until then it stops and the command
+    /// waits, any later command replaces it.
+    pub fn command(
+        &mut self,
+        now: Instant,
+        command: RelaisState,
+        duration: Duration,
+        dead_time: Option<Duration>,
+    ) -> Option<RelaisState> {
+        self.deferred = None;
+        let reversal = dead_time.and_then(|dead_time| self.reversal(command, now, dead_time));
+        let Some(until) = reversal.max(self.recovery(command, duration, now)) else {
+            return self.update(now, command, duration);
+        };
+        self.deferred = Some((until, command, duration));
+        self.scheduled = None;
+        if !is_moving(self.current) {
+            return None;
+        }
+        self.set_current(RelaisState::Off, now);
+        Some(RelaisState::Off)
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Option<RelaisState> {
+        if let Some((when, command, duration)) = self.deferred {
+            if now >= when {
+                self.deferred = None;
+                return self.update(now, command, duration);
+            }
+        }
+        if let Some((when, action)) = self.scheduled {
+            if now >= when {
+                self.scheduled = None;
+                if let Some(until) = self.recovery(action, ZERO, now) {
+                    self.deferred = Some((until, action, ZERO));
+                    return None;
+                }
+                self.set_current(action, now);
+                return Some(action);
+            }
+        }
+        None
+    }
+
+    /// Switches off once the channel was on for `max_on`, a running timer
+    /// is dropped. Returns `true` if it did.
+    fn enforce_max_on(&mut self, now: Instant) -> bool {
+        if self.forced_off().is_none_or(|when| now < when) {
+            return false;
+        }
+        self.scheduled = None;
+        self.set_current(RelaisState::Off, now);
+        self.violation = Some(Violation::MaxOnTime);
+        true
+    }
+
+    /// When `max_on` runs out for a channel that is on
+    fn forced_off(&self) -> Option<Instant> {
+        let max_on = self.limits.max_on;
+        if max_on == ZERO || self.current == RelaisState::Off {
+            return None;
+        }
+        self.changed.map(|on| on + max_on)
+    }
+
+    /// End of `min_off` if `command` switches the channel on before it
+    fn recovery(
+        &mut self,
+        command: RelaisState,
+        duration: Duration,
+        now: Instant,
+    ) -> Option<Instant> {
+        use RelaisState::*;
+        let timed = duration != ZERO;
+        let switches_on = match command {
+            On | Up | Down | Toggle => true,
+            Pulse => timed,
+            DelayedOn => !timed,
+            Off | DelayedOff => false,
+        };
+        let min_off = self.limits.min_off;
+        if !switches_on || min_off == ZERO || self.current != Off {
+            return None;
+        }
+        let until = self.changed? + min_off;
+        (until > now).then(|| {
+            self.violation = Some(Violation::MinOffTime);
+            until
+        })
+    }
+
+    /// Time since the channel switched on, zero while off
+    fn on_since(&self, now: Instant) -> Duration {
+        match self.changed {
+            Some(on) if self.current != RelaisState::Off => now.saturating_duration_since(on),
+            _ => ZERO,
+        }
+    }
+
+    fn next_event(&self) -> Option<Instant> {
+        let deferred = self.deferred.map(|(when, _, _)| when);
+        let scheduled = self.scheduled.map(|(when, _)| when);
+        deferred
+            .into_iter()
+            .chain(scheduled)
+            .chain(self.forced_off())
+            .min()
+    }
+
+    /// End of the dead time if `command` reverses the motor
+    fn reversal(&self, command: RelaisState, now: Instant, dead_time: Duration) -> Option<Instant> {
+        let opposite = match command {
+            RelaisState::Up => RelaisState::Down,
+            RelaisState::Down => RelaisState::Up,
+            _ => return None,
+        };
+        if self.current == opposite {
+            return Some(now + dead_time);
+        }
+        match self.stopped {
+            Some((direction, at)) if direction == opposite && !is_moving(self.current) => {
+                Some(at + dead_time).filter(|&until| until > now)
+            }
+            _ => None,
+        }
+    }
+
+    fn set_current(&mut self, state: RelaisState, now: Instant) {
+        if is_moving(self.current) && state != self.current {
+            self.stopped = Some((self.current, now));
+        }
+        let (was_on, on) = (self.current != RelaisState::Off, state != RelaisState::Off);
+        if !was_on && on {
+            self.stats.cycles = self.stats.cycles.saturating_add(1);
+            if self.cycle_limit == Some(self.stats.cycles) {
+                self.violation = Some(Violation::CycleLimit);
+            }
+        }
+        if was_on && !on {
+            self.stats.on_time += self.on_since(now);
+        }
+        if was_on != on {
+            self.changed = Some(now);
+        }
+        self.current = state;
+    }
+}
+
+fn is_moving(state: RelaisState) -> bool {
+    matches!(state, RelaisState::Up | RelaisState::Down)
+}
+
+/// Timers of all channels and the position of the shutters among them. A
+/// channel is a shutter once it got a [`ShutterTiming`].
+pub struct RelayManager<const N: usize> {
+    relays: FnvIndexMap<usize, ActiveRelais, N>,
+    shutters: FnvIndexMap<usize, Shutter, N>,
+    /// `None` for plain relays, see [`set_dead_time`](Self::set_dead_time)
+    dead_time: Option<Duration>,
+    cycle_limit: Option<u32>,
+}
+
+impl<const N: usize> Default for RelayManager<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> RelayManager<N> {
+    pub fn new() -> Self {
+        Self {
+            relays: FnvIndexMap::new(),
+            shutters: FnvIndexMap::new(),
+            dead_time: None,
+            cycle_limit: None,
+        }
+    }
+
+    /// Makes every channel a shutter motor that stands still for
+    /// `dead_time` between opposite directions, at least [`MIN_DEAD_TIME`]
+    pub fn set_dead_time(&mut self, dead_time: Duration) {
+        self.dead_time = Some(dead_time.max(MIN_DEAD_TIME));
+    }
+
+    pub fn next_timeout(&self, now: Instant) -> Duration {
+        self.relays
+            .values()
+            .filter_map(|r| r.next_event().map(|t| t.saturating_duration_since(now)))
+            .min()
+            .unwrap_or(Duration::from_millis(100))
+    }
+
+    /// Returns the new state if the output has to be written
+    pub fn apply_command(
+        &mut self,
+        num: usize,
+        command: RelaisState,
+        duration: embassy_time::Duration,
+        now: Instant,
+    ) -> Option<RelaisState> {
+        let dead_time = self.dead_time;
+        let state = self.relay(num)?.command(now, command, duration, dead_time);
+        if let Some(shutter) = self.shutters.get_mut(&num) {
+            shutter.cancel();
+            if let Some(state) = state {
+                shutter.drive(state, now);
+            }
+        }
+        state
+    }
+
+    /// Moves a shutter to `percent` closed. Returns the new state if the
+    /// output has to be written, `None` for channels without timing.
+    pub fn move_to(&mut self, num: usize, percent: u8, now: Instant) -> Option<RelaisState> {
+        let leg = self.shutters.get_mut(&num)?.move_to(percent, now)?;
+        self.start(num, leg, now)
+    }
+
+    /// Turns the slats of a venetian blind to `percent` closed
+    pub fn tilt_to(&mut self, num: usize, percent: u8, now: Instant) -> Option<RelaisState> {
+        let leg = self.shutters.get_mut(&num)?.tilt_to(percent, now)?;
+        self.start(num, leg, now)
+    }
+
+    /// Makes `num` a shutter, its position is unknown until the next end stop
+    pub fn set_timing(&mut self, num: usize, timing: ShutterTiming, now: Instant) {
+        match self.shutters.entry(num) {
+            Entry::Occupied(mut entry) => entry.get_mut().set_timing(timing, now),
+            Entry::Vacant(entry) => {
+                let mut shutter = Shutter::new(timing);
+                if let Some(relay) = self.relays.get(&num) {
+                    shutter.drive(relay.current, now);
+                }
+                entry.insert(shutter).ok(); // ignore overflow
+            }
+        }
+    }
+
+    pub fn timing(&self, num: usize) -> Option<ShutterTiming> {
+        self.shutters.get(&num).map(Shutter::timing)
+    }
+
+    /// Estimated position of a shutter in percent closed
+    pub fn position(&self, num: usize, now: Instant) -> Option<u8> {
+        self.shutters.get(&num)?.percent(now)
+    }
+
+    /// Estimated tilt of the slats in percent closed
+    pub fn tilt(&self, num: usize, now: Instant) -> Option<u8> {
+        self.shutters.get(&num)?.tilt_percent(now)
+    }
+
+    /// Limits apply from the next command, a channel already on for longer
+    /// than `max_on` is switched off at the next poll
+    pub fn set_limits(&mut self, num: usize, limits: RelaisLimits) {
+        if let Some(relay) = self.relay(num) {
+            relay.limits = limits;
+        }
+    }
+
+    pub fn limits(&self, num: usize) -> RelaisLimits {
+        self.relays
+            .get(&num)
+            .map_or(RelaisLimits::default(), |relay| relay.limits)
+    }
+
+    /// Cycles and on-time of a channel up to `now`
+    pub fn stats(&self, num: usize, now: Instant) -> RelaisStats {
+        self.relays
+            .get(&num)
+            .map_or(RelaisStats::default(), |relay| RelaisStats {
+                on_time: relay.stats.on_time + relay.on_since(now),
+                ..relay.stats
+            })
+    }
+
+    /// Continues counting from `stats`, e.g. the stored counters at start.
+    /// A channel that is on adds the time since it switched on.
+    pub fn set_stats(&mut self, num: usize, stats: RelaisStats) {
+        if let Some(relay) = self.relay(num) {
+            relay.stats = stats;
+        }
+    }
+
+    /// A channel reaching `cycles` switching cycles reports
+    /// [`Violation::CycleLimit`], 0 disables it. Channels already past the
+    /// limit report it at once.
+    pub fn set_cycle_limit(&mut self, cycles: u32) {
+        self.cycle_limit = (cycles != 0).then_some(cycles);
+        for relay in self.relays.values_mut() {
+            relay.cycle_limit = self.cycle_limit;
+            if self
+                .cycle_limit
+                .is_some_and(|limit| relay.stats.cycles >= limit)
+            {
+                relay.violation = Some(Violation::CycleLimit);
+            }
+        }
+    }
+
+    /// Limits the channels ran into since the last call
+    pub fn take_violations(&mut self) -> heapless::Vec<(usize, Violation), N> {
+        self.relays
+            .iter_mut()
+            .filter_map(|(&num, relay)| Some((num, relay.violation.take()?)))
+            .collect()
+    }
+
+    /// Current state of a channel and the time until a timer changes it,
+    /// zero if none is running
+    pub fn status(&self, num: usize, now: Instant) -> (RelaisState, Duration) {
+        self.relays
+            .get(&num)
+            .map_or((RelaisState::Off, ZERO), |relay| {
+                let remaining = relay
+                    .next_event()
+                    .map_or(ZERO, |when| when.saturating_duration_since(now));
+                (relay.current, remaining)
+            })
+    }
+
+    /// A shutter that stopped on its way to a position continues with the
+    /// next leg, only that leg is returned. A leg in the opposite direction
+    /// waits for the dead time.
+    pub fn poll_expired(&mut self, now: Instant) -> heapless::Vec<(usize, RelaisState), N> {
+        let mut result = heapless::Vec::new();
+        for (&num, relay) in self.relays.iter_mut() {
+            if relay.enforce_max_on(now) {
+                if let Some(shutter) = self.shutters.get_mut(&num) {
+                    shutter.cancel();
+                    shutter.drive(RelaisState::Off, now);
+                }
+                result.push((num, RelaisState::Off)).ok(); // ignore overflow
+                continue;
+            }
+            let Some(mut state) = relay.poll(now) else {
+                continue;
+            };
+            if let Some(shutter) = self.shutters.get_mut(&num) {
+                shutter.drive(state, now);
+                let leg = (!is_moving(state))
+                    .then(|| shutter.next_move(now))
+                    .flatten();
+                if let Some((direction, duration)) = leg {
+                    state = relay
+                        .command(now, direction, duration, self.dead_time)
+                        .unwrap_or(state);
+                    shutter.drive(state, now);
+                }
+            }
+            result.push((num, state)).ok(); // ignore overflow
+        }
+        result
+    }
+
+    /// Drives a shutter for one leg of a move
+    fn start(
+        &mut self,
+        num: usize,
+        (direction, duration): (RelaisState, Duration),
+        now: Instant,
+    ) -> Option<RelaisState> {
+        let dead_time = self.dead_time;
+        let state = self
+            .relay(num)?
+            .command(now, direction, duration, dead_time)?;
+        self.shutters.get_mut(&num)?.drive(state, now);
+        Some(state)
+    }
+
+    /// The entry of a channel, all channels are off at start
+    fn relay(&mut self, num: usize) -> Option<&mut ActiveRelais> {
+        let cycle_limit = self.cycle_limit;
+        match self.relays.entry(num) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => entry
+                .insert(ActiveRelais {
+                    current: RelaisState::Off,
+                    scheduled: None,
+                    deferred: None,
+                    stopped: None,
+                    limits: RelaisLimits::default(),
+                    changed: None,
+                    violation: None,
+                    stats: RelaisStats::default(),
+                    cycle_limit,
+                })
+                .ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMING: ShutterTiming = ShutterTiming {
+        up: Duration::from_secs(20),
+        down: Duration::from_secs(10),
+        turn: Duration::from_secs(0),
+    };
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    #[test]
+    fn test_timed_command() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        let state = manager.apply_command(1, RelaisState::On, Duration::from_secs(2), at(0));
+        assert_eq!(state, Some(RelaisState::On));
+        assert_eq!(manager.next_timeout(at(500)), Duration::from_millis(1500));
+        assert_eq!(
+            manager.status(1, at(500)),
+            (RelaisState::On, Duration::from_millis(1500))
+        );
+        assert_eq!(manager.status(2, at(500)), (RelaisState::Off, ZERO));
+        assert!(manager.poll_expired(at(1999)).is_empty());
+        assert_eq!(
+            &manager.poll_expired(at(2000))[..],
+            &[(1, RelaisState::Off)]
+        );
+        assert_eq!(manager.status(1, at(2000)), (RelaisState::Off, ZERO));
+    }
+
+    #[test]
+    fn test_pulse_and_toggle() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            manager.apply_command(0, RelaisState::Toggle, ZERO, at(0)),
+            Some(RelaisState::On)
+        );
+        assert_eq!(
+            manager.apply_command(0, RelaisState::Pulse, second, at(0)),
+            Some(RelaisState::On)
+        );
+        assert_eq!(&manager.poll_expired(at(1000))[..], &[(0, RelaisState::On)]);
+        assert_eq!(
+            manager.apply_command(0, RelaisState::DelayedOff, second, at(1000)),
+            None
+        );
+        assert_eq!(
+            &manager.poll_expired(at(2000))[..],
+            &[(0, RelaisState::Off)]
+        );
+    }
+
+    #[test]
+    fn test_move_to() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        assert_eq!(manager.move_to(2, 50, at(0)), None);
+
+        manager.set_timing(2, TIMING, at(0));
+        assert_eq!(manager.timing(2), Some(TIMING));
+        // reference run down first, then up to the target
+        assert_eq!(manager.move_to(2, 60, at(0)), Some(RelaisState::Down));
+        assert_eq!(manager.position(2, at(5_000)), None);
+        assert_eq!(
+            &manager.poll_expired(at(11_000))[..],
+            &[(2, RelaisState::Up)]
+        );
+        assert_eq!(manager.position(2, at(11_000)), Some(100));
+        assert_eq!(manager.next_timeout(at(11_000)), Duration::from_secs(8));
+        assert_eq!(
+            &manager.poll_expired(at(19_000))[..],
+            &[(2, RelaisState::Off)]
+        );
+        assert_eq!(manager.position(2, at(19_000)), Some(60));
+    }
+
+    #[test]
+    fn test_tilt() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        let venetian = ShutterTiming {
+            turn: Duration::from_secs(2),
+            ..TIMING
+        };
+        manager.set_timing(1, venetian, at(0));
+        // the slats are unknown, turn fully up first
+        assert_eq!(manager.tilt_to(1, 40, at(0)), Some(RelaisState::Up));
+        assert_eq!(manager.next_timeout(at(0)), Duration::from_secs(2));
+        assert_eq!(
+            &manager.poll_expired(at(2_000))[..],
+            &[(1, RelaisState::Down)]
+        );
+        assert_eq!(manager.next_timeout(at(2_000)), Duration::from_millis(800));
+        assert_eq!(
+            &manager.poll_expired(at(2_800))[..],
+            &[(1, RelaisState::Off)]
+        );
+        assert_eq!(manager.tilt(1, at(2_800)), Some(40));
+        assert_eq!(manager.tilt_to(0, 40, at(2_800)), None);
+    }
+
+    /// Every transition between the states of a motor
+    #[test]
+    fn test_dead_time() {
+        use RelaisState::*;
+        for from in [Off, Up, Down] {
+            for to in [Off, Up, Down, On] {
+                let mut manager: RelayManager<4> = RelayManager::new();
+                manager.set_dead_time(Duration::from_millis(500));
+                manager.apply_command(0, from, ZERO, at(0));
+                let state = manager.apply_command(0, to, ZERO, at(1_000));
+                let reversal = matches!((from, to), (Up, Down) | (Down, Up));
+                if reversal {
+                    assert_eq!(state, Some(Off), "{from:?} -> {to:?}");
+                    assert_eq!(manager.next_timeout(at(1_000)), Duration::from_millis(500));
+                    assert!(manager.poll_expired(at(1_499)).is_empty());
+                    assert_eq!(&manager.poll_expired(at(1_500))[..], &[(0, to)]);
+                } else {
+                    let expected = (from != to).then_some(to);
+                    assert_eq!(state, expected, "{from:?} -> {to:?}");
+                    assert!(manager.poll_expired(at(60_000)).is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dead_time_after_stop() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        manager.set_dead_time(Duration::from_millis(500));
+        let two = Duration::from_secs(2);
+        manager.apply_command(0, RelaisState::Up, ZERO, at(0));
+        manager.apply_command(0, RelaisState::Off, ZERO, at(1_000));
+        // waits for the rest of the dead time, the duration starts after it
+        assert_eq!(
+            manager.apply_command(0, RelaisState::Down, two, at(1_200)),
+            None
+        );
+        assert_eq!(
+            &manager.poll_expired(at(1_500))[..],
+            &[(0, RelaisState::Down)]
+        );
+        assert_eq!(
+            &manager.poll_expired(at(3_500))[..],
+            &[(0, RelaisState::Off)]
+        );
+
+        // a stop drops the waiting command
+        manager.apply_command(0, RelaisState::Up, ZERO, at(4_000));
+        assert_eq!(
+            manager.apply_command(0, RelaisState::Down, ZERO, at(5_000)),
+            Some(RelaisState::Off)
+        );
+        assert_eq!(
+            manager.apply_command(0, RelaisState::Off, ZERO, at(5_100)),
+            None
+        );
+        assert!(manager.poll_expired(at(6_000)).is_empty());
+
+        // too short dead times are raised
+        manager.set_dead_time(ZERO);
+        manager.apply_command(0, RelaisState::Up, ZERO, at(7_000));
+        manager.apply_command(0, RelaisState::Down, ZERO, at(8_000));
+        assert_eq!(manager.next_timeout(at(8_000)), MIN_DEAD_TIME);
+    }
+
+    #[test]
+    fn test_move_waits_for_dead_time() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        manager.set_dead_time(Duration::from_millis(500));
+        manager.set_timing(2, TIMING, at(0));
+        assert_eq!(manager.move_to(2, 60, at(0)), Some(RelaisState::Down));
+        assert_eq!(
+            &manager.poll_expired(at(11_000))[..],
+            &[(2, RelaisState::Off)]
+        );
+        assert_eq!(
+            &manager.poll_expired(at(11_500))[..],
+            &[(2, RelaisState::Up)]
+        );
+        assert_eq!(
+            &manager.poll_expired(at(19_500))[..],
+            &[(2, RelaisState::Off)]
+        );
+        assert_eq!(manager.position(2, at(19_500)), Some(60));
+    }
+
+    #[test]
+    fn test_max_on_time() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        let limits = RelaisLimits {
+            max_on: Duration::from_secs(60),
+            min_off: ZERO,
+        };
+        manager.set_limits(1, limits);
+        assert_eq!(manager.limits(1), limits);
+        manager.apply_command(1, RelaisState::On, ZERO, at(0));
+        assert_eq!(manager.next_timeout(at(0)), Duration::from_secs(60));
+        // a longer timer does not extend the limit
+        manager.apply_command(1, RelaisState::On, Duration::from_secs(90), at(30_000));
+        assert_eq!(
+            &manager.poll_expired(at(60_000))[..],
+            &[(1, RelaisState::Off)]
+        );
+        assert_eq!(&manager.take_violations()[..], &[(1, Violation::MaxOnTime)]);
+        assert!(manager.take_violations().is_empty());
+        assert!(manager.poll_expired(at(90_000)).is_empty());
+        assert_eq!(manager.status(1, at(90_000)), (RelaisState::Off, ZERO));
+    }
+
+    #[test]
+    fn test_min_off_time() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        manager.set_limits(
+            0,
+            RelaisLimits {
+                max_on: ZERO,
+                min_off: Duration::from_secs(180),
+            },
+        );
+        // nothing to wait for after start
+        assert_eq!(
+            manager.apply_command(0, RelaisState::On, ZERO, at(0)),
+            Some(RelaisState::On)
+        );
+        manager.apply_command(0, RelaisState::Off, ZERO, at(10_000));
+        assert!(manager.take_violations().is_empty());
+        assert_eq!(
+            manager.apply_command(0, RelaisState::On, ZERO, at(70_000)),
+            None
+        );
+        assert_eq!(
+            &manager.take_violations()[..],
+            &[(0, Violation::MinOffTime)]
+        );
+        assert_eq!(manager.next_timeout(at(70_000)), Duration::from_secs(120));
+        assert_eq!(
+            &manager.poll_expired(at(190_000))[..],
+            &[(0, RelaisState::On)]
+        );
+
+        // a timer switching on waits as well
+        manager.apply_command(0, RelaisState::Off, ZERO, at(200_000));
+        assert_eq!(
+            manager.apply_command(
+                0,
+                RelaisState::DelayedOn,
+                Duration::from_secs(5),
+                at(200_000)
+            ),
+            None
+        );
+        assert!(manager.poll_expired(at(205_000)).is_empty());
+        assert_eq!(
+            &manager.take_violations()[..],
+            &[(0, Violation::MinOffTime)]
+        );
+        assert_eq!(
+            &manager.poll_expired(at(380_000))[..],
+            &[(0, RelaisState::On)]
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        let stored = RelaisStats {
+            cycles: 98,
+            on_time: Duration::from_secs(3600),
+        };
+        manager.set_stats(2, stored);
+        manager.set_cycle_limit(100);
+        assert!(manager.take_violations().is_empty());
+
+        manager.apply_command(2, RelaisState::On, Duration::from_secs(10), at(0));
+        manager.poll_expired(at(10_000));
+        assert!(manager.take_violations().is_empty());
+        manager.apply_command(2, RelaisState::On, ZERO, at(20_000));
+        assert_eq!(
+            &manager.take_violations()[..],
+            &[(2, Violation::CycleLimit)]
+        );
+        // the running switch-on counts as well
+        assert_eq!(
+            manager.stats(2, at(25_000)),
+            RelaisStats {
+                cycles: 100,
+                on_time: Duration::from_secs(3615),
+            }
+        );
+        assert_eq!(manager.stats(3, at(25_000)), RelaisStats::default());
+
+        // reset after replacing the relay
+        manager.apply_command(2, RelaisState::Off, ZERO, at(25_000));
+        manager.set_stats(2, RelaisStats::default());
+        assert_eq!(manager.stats(2, at(30_000)), RelaisStats::default());
+        manager.set_cycle_limit(1);
+        assert!(manager.take_violations().is_empty());
+        manager.set_stats(2, stored);
+        manager.set_cycle_limit(50);
+        assert_eq!(
+            &manager.take_violations()[..],
+            &[(2, Violation::CycleLimit)]
+        );
+    }
+
+    #[test]
+    fn test_manual_command_cancels_move() {
+        let mut manager: RelayManager<4> = RelayManager::new();
+        manager.set_timing(0, TIMING, at(0));
+        manager.apply_command(0, RelaisState::Down, Duration::from_secs(11), at(0));
+        manager.poll_expired(at(11_000));
+        assert_eq!(manager.position(0, at(11_000)), Some(100));
+
+        manager.move_to(0, 0, at(11_000));
+        assert_eq!(
+            manager.apply_command(0, RelaisState::Off, ZERO, at(16_000)),
+            Some(RelaisState::Off)
+        );
+        assert_eq!(manager.position(0, at(16_000)), Some(75));
+        assert!(manager.poll_expired(at(40_000)).is_empty());
+    }
+}
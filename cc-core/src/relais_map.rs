@@ -0,0 +1,270 @@
+//! Wiring of the relays to the I/O expanders of a board.
+//!
+//! Every relay is one bit of an 8-bit expander on the I2C bus. The outputs
+//! are split into banks of the same size, the `bank` of a `RelaisMessage`
+//! selects one of them and the channel counts from 0 within it. Channels of
+//! all banks are numbered through for the relay task, see
+//! [`channel`](RelaisMap::channel).
+
+use crate::can_message::DecodeError;
+use crate::relais_message::{RelaisMode, RelaisState};
+use heapless::Vec;
+
+pub const MAX_EXPANDERS: usize = 4;
+pub const MAX_OUTPUTS: usize = 32;
+
+/// Bit of an expander driving one relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Output {
+    /// Index into [`RelaisMap::expanders`]
+    pub expander: u8,
+    pub bit: u8,
+}
+
+const fn output(expander: u8, bit: u8) -> Output {
+    Output { expander, bit }
+}
+
+/// Outputs of one 12 channel group on expanders `first` and `first + 1`
+const fn group_12(first: u8) -> [Output; 12] {
+    let second = first + 1;
+    [
+        output(first, 3),
+        output(first, 2),
+        output(first, 1),
+        output(first, 7),
+        output(first, 6),
+        output(first, 5),
+        output(first, 4),
+        output(second, 3),
+        output(second, 2),
+        output(second, 1),
+        output(second, 7),
+        output(second, 6),
+    ]
+}
+
+const fn outputs<const N: usize>(used: [Output; N]) -> [Output; MAX_OUTPUTS] {
+    let mut outputs = [output(0, 0); MAX_OUTPUTS];
+    let mut i = 0;
+    while i < N {
+        outputs[i] = used[i];
+        i += 1;
+    }
+    outputs
+}
+
+/// Channel map of a board, stored in the config.
+///
+/// Layout, [`LEN`](Self::LEN) bytes:
+///
+/// | byte  | content                                              |
+/// |-------|------------------------------------------------------|
+/// | 0     | number of banks                                      |
+/// | 1     | outputs per bank                                     |
+/// | 2..6  | I2C address of each expander, 0 if not fitted        |
+/// | 6..38 | outputs, expander index in the high nibble, then bit |
+///
+/// Only the first `banks * bank_outputs` outputs are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelaisMap {
+    pub banks: u8,
+    pub bank_outputs: u8,
+    pub expanders: [u8; MAX_EXPANDERS],
+    pub outputs: [Output; MAX_OUTPUTS],
+}
+
+impl RelaisMap {
+    pub const LEN: usize = 2 + MAX_EXPANDERS + MAX_OUTPUTS;
+
+    /// The 12 channel board
+    pub const BOARD_12: RelaisMap = RelaisMap {
+        banks: 1,
+        bank_outputs: 12,
+        expanders: [0x26, 0x27, 0, 0],
+        outputs: outputs(group_12(0)),
+    };
+
+    /// Built-in map of a `HardwareRevision`, used when no map is stored. All
+    /// revisions so far are the 12 channel board, other boards need their
+    /// map written with `CanMessage::RelaisMap`.
+    pub fn for_hw_rev(_hw_rev: u8) -> RelaisMap {
+        Self::BOARD_12
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        let data: &[u8; Self::LEN] = data.try_into().map_err(|_| DecodeError::InvalidLength)?;
+        let mut map = RelaisMap {
+            banks: data[0],
+            bank_outputs: data[1],
+            expanders: [0; MAX_EXPANDERS],
+            outputs: [Output::default(); MAX_OUTPUTS],
+        };
+        map.expanders.copy_from_slice(&data[2..2 + MAX_EXPANDERS]);
+        for (output, &raw) in map.outputs.iter_mut().zip(&data[2 + MAX_EXPANDERS..]) {
+            *output = Output {
+                expander: raw >> 4,
+                bit: raw & 0x0F,
+            };
+        }
+        if !map.is_valid() {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(map)
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut data = [0; Self::LEN];
+        data[0] = self.banks;
+        data[1] = self.bank_outputs;
+        data[2..2 + MAX_EXPANDERS].copy_from_slice(&self.expanders);
+        for (raw, output) in data[2 + MAX_EXPANDERS..].iter_mut().zip(&self.outputs) {
+            *raw = output.expander << 4 | output.bit;
+        }
+        data
+    }
+
+    /// At least one bank and every used output on a fitted expander. No bit
+    /// may drive two outputs, the interlock of a shutter's directions relies
+    /// on separate relays.
+    fn is_valid(&self) -> bool {
+        let used = self.banks as usize * self.bank_outputs as usize;
+        if self.banks == 0 || used > MAX_OUTPUTS {
+            return false;
+        }
+        let used = &self.outputs[..used];
+        used.iter().enumerate().all(|(i, output)| {
+            output.bit < 8
+                && self
+                    .expanders
+                    .get(output.expander as usize)
+                    .is_some_and(|&address| address != 0)
+                && !used[..i].contains(output)
+        })
+    }
+
+    /// Channels of one bank
+    pub fn bank_channels(&self, mode: RelaisMode) -> usize {
+        mode.channels(self.bank_outputs as usize)
+    }
+
+    /// Channels of all banks
+    pub fn channels(&self, mode: RelaisMode) -> usize {
+        self.bank_channels(mode) * self.banks as usize
+    }
+
+    /// Number of channel `num` in `bank` counted through all banks, `None`
+    /// if the board has no such channel
+    pub fn channel(&self, mode: RelaisMode, bank: u8, num: usize) -> Option<usize> {
+        let per_bank = self.bank_channels(mode);
+        (bank < self.banks && num < per_bank).then(|| bank as usize * per_bank + num)
+    }
+
+    /// Bank and number within the bank of a channel from
+    /// [`channel`](Self::channel)
+    pub fn split(&self, mode: RelaisMode, channel: usize) -> (u8, usize) {
+        let per_bank = self.bank_channels(mode).max(1);
+        ((channel / per_bank) as u8, channel % per_bank)
+    }
+
+    /// Expander bits to switch `channel` from `from` to `to` as `(output,
+    /// on)`, in the order of [`RelaisMode::outputs`]
+    pub fn outputs(
+        &self,
+        mode: RelaisMode,
+        channel: usize,
+        from: RelaisState,
+        to: RelaisState,
+    ) -> Vec<(Output, bool), 3> {
+        let (bank, num) = self.split(mode, channel);
+        let first = bank as usize * self.bank_outputs as usize;
+        mode.outputs(num, from, to)
+            .into_iter()
+            .filter_map(|(local, on)| Some((*self.outputs.get(first + local)?, on)))
+            .collect()
+    }
+
+    /// I2C addresses of the fitted expanders with their index
+    pub fn fitted(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        self.expanders
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, address)| address != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two groups wired like the 12 channel board, one per bank
+    fn two_banks() -> RelaisMap {
+        let (first, second) = (group_12(0), group_12(2));
+        RelaisMap {
+            banks: 2,
+            bank_outputs: 12,
+            expanders: [0x26, 0x27, 0x20, 0x21],
+            outputs: core::array::from_fn(|i| match i {
+                0..12 => first[i],
+                12..24 => second[i - 12],
+                _ => output(0, 0),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for map in [RelaisMap::BOARD_12, two_banks()] {
+            assert_eq!(RelaisMap::from_bytes(&map.to_bytes()), Ok(map));
+        }
+        assert_eq!(
+            RelaisMap::from_bytes(&[0; 10]),
+            Err(DecodeError::InvalidLength)
+        );
+
+        let mut data = RelaisMap::BOARD_12.to_bytes();
+        data[0] = 0;
+        assert_eq!(RelaisMap::from_bytes(&data), Err(DecodeError::InvalidValue));
+        // output on an expander that is not fitted
+        let mut data = RelaisMap::BOARD_12.to_bytes();
+        data[2 + MAX_EXPANDERS] = 0x20;
+        assert_eq!(RelaisMap::from_bytes(&data), Err(DecodeError::InvalidValue));
+        // two channels on the same relay
+        let mut data = RelaisMap::BOARD_12.to_bytes();
+        data[2 + MAX_EXPANDERS + 1] = data[2 + MAX_EXPANDERS];
+        assert_eq!(RelaisMap::from_bytes(&data), Err(DecodeError::InvalidValue));
+        // unused outputs may repeat
+        let mut data = RelaisMap::BOARD_12.to_bytes();
+        data[2 + MAX_EXPANDERS + 20] = data[2 + MAX_EXPANDERS];
+        assert!(RelaisMap::from_bytes(&data).is_ok());
+    }
+
+    #[test]
+    fn test_banks() {
+        let map = two_banks();
+        assert_eq!(map.channels(RelaisMode::Relais), 24);
+        assert_eq!(map.channel(RelaisMode::Relais, 1, 0), Some(12));
+        assert_eq!(map.channel(RelaisMode::Relais, 1, 12), None);
+        assert_eq!(map.channel(RelaisMode::Relais, 2, 0), None);
+        assert_eq!(map.split(RelaisMode::Relais, 13), (1, 1));
+        assert_eq!(
+            &map.outputs(RelaisMode::Relais, 12, RelaisState::Off, RelaisState::On)[..],
+            &[(output(2, 3), true)]
+        );
+
+        let mode = RelaisMode::SoftwareRollershutter;
+        assert_eq!(map.channel(mode, 1, 5), Some(11));
+        assert_eq!(map.channel(mode, 1, 6), None);
+        assert_eq!(map.split(mode, 11), (1, 5));
+        assert_eq!(
+            &map.outputs(mode, 6, RelaisState::Off, RelaisState::Up)[..],
+            &[(output(2, 2), false), (output(2, 3), true)]
+        );
+
+        let map = RelaisMap::for_hw_rev(0);
+        assert_eq!(map, RelaisMap::BOARD_12);
+        assert_eq!(map.channels(RelaisMode::Relais), 12);
+        assert_eq!(map.fitted().collect::<Vec<_, 4>>(), [(0, 0x26), (1, 0x27)]);
+    }
+}
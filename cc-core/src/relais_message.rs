@@ -1,5 +1,9 @@
+use crate::can_message::DecodeError;
 use embassy_time::Duration;
+use heapless::Vec;
 
+/// Output state of a channel and the commands to change it. Only `Off`,
+/// `Up`, `Down` and `On` are ever the state of a channel.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum RelaisState {
@@ -7,6 +11,15 @@ pub enum RelaisState {
     Up = 1,
     Down = 2,
     On = 3,
+    /// On if the channel is off, off otherwise. With a duration the channel
+    /// switches off again like after `On`.
+    Toggle = 4,
+    /// On for the duration, then back to the state before
+    Pulse = 5,
+    /// On once the duration is over
+    DelayedOn = 6,
+    /// Off once the duration is over
+    DelayedOff = 7,
 }
 
 impl core::convert::TryFrom<u8> for RelaisState {
@@ -18,53 +31,533 @@ impl core::convert::TryFrom<u8> for RelaisState {
             0 => Off,
             1 => Up,
             2 => Down,
+            3 => On,
+            4 => Toggle,
+            5 => Pulse,
+            6 => DelayedOn,
+            7 => DelayedOff,
             _ => return Err(()),
         };
         Ok(result)
     }
 }
-#[derive(Debug, Clone, Copy)]
+
+/// Wiring of the relays, stored in `RelaisMode`. The device reads it at start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum RelaisMode {
+    /// Every relay is a channel of its own
+    Relais = 0,
+    /// Two relays per shutter, one for each direction
+    SoftwareRollershutter = 1,
+    /// Two relays per shutter, one for power and one for the direction
+    #[default]
+    HardwareRollershutter = 2,
+}
+
+impl core::convert::TryFrom<u8> for RelaisMode {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        use RelaisMode::*;
+        let result = match value {
+            0 => Relais,
+            1 => SoftwareRollershutter,
+            2 => HardwareRollershutter,
+            _ => return Err(()),
+        };
+        Ok(result)
+    }
+}
+
+impl RelaisMode {
+    /// Channels a `RelaisMessage` can address on a board with `outputs` relays
+    pub fn channels(self, outputs: usize) -> usize {
+        match self {
+            RelaisMode::Relais => outputs,
+            RelaisMode::SoftwareRollershutter | RelaisMode::HardwareRollershutter => outputs / 2,
+        }
+    }
+
+    /// Relay outputs to switch channel `num` from `from` to `to` as
+    /// `(output, on)`, in the order they have to be written. Outputs always
+    /// switch off before others switch on, so the two direction relays of a
+    /// shutter are never on together. The direction relay of
+    /// `HardwareRollershutter` only switches while the power relay is off.
+    pub fn outputs(self, num: usize, from: RelaisState, to: RelaisState) -> Vec<(usize, bool), 3> {
+        use RelaisState::*;
+        let (first, second) = (num * 2, num * 2 + 1);
+        let outputs: &[(usize, bool)] = match (self, from, to) {
+            (RelaisMode::Relais, _, to) => &[(num, to == On)],
+            // one relay per direction
+            (RelaisMode::SoftwareRollershutter, _, Up) => &[(second, false), (first, true)],
+            (RelaisMode::SoftwareRollershutter, _, Down) => &[(first, false), (second, true)],
+            // power and direction
+            (RelaisMode::HardwareRollershutter, Down, Up) => {
+                &[(first, false), (second, false), (first, true)]
+            }
+            (RelaisMode::HardwareRollershutter, _, Up) => &[(second, false), (first, true)],
+            (RelaisMode::HardwareRollershutter, Up, Down) => {
+                &[(first, false), (second, true), (first, true)]
+            }
+            (RelaisMode::HardwareRollershutter, _, Down) => &[(second, true), (first, true)],
+            (_, _, _) => &[(first, false), (second, false)],
+        };
+        Vec::from_slice(outputs).unwrap()
+    }
+}
+
+/// State of a channel after power-on, stored per channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum PowerOn {
+    #[default]
+    Off = 0,
+    On = 1,
+    /// The state before the power cut, as far as it was stored
+    Last = 2,
+}
+
+impl core::convert::TryFrom<u8> for PowerOn {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PowerOn::Off),
+            1 => Ok(PowerOn::On),
+            2 => Ok(PowerOn::Last),
+            _ => Err(()),
+        }
+    }
+}
+
+impl PowerOn {
+    /// State to switch to at start, `last` is the stored state
+    pub fn state(self, last: RelaisState) -> RelaisState {
+        match self {
+            PowerOn::On => RelaisState::On,
+            PowerOn::Last if last == RelaisState::On => RelaisState::On,
+            PowerOn::Off | PowerOn::Last => RelaisState::Off,
+        }
+    }
+}
+
+/// Protection of a channel against running too long or switching too
+/// often, zero disables a limit. Sent in whole seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelaisLimits {
+    /// Longest time the channel stays on, then it is forced off
+    pub max_on: Duration,
+    /// Shortest pause after switching off, turning on waits for it
+    pub min_off: Duration,
+}
+
+impl RelaisLimits {
+    pub const LEN: usize = 4;
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        let [m0, m1, o0, o1]: [u8; Self::LEN] =
+            data.try_into().map_err(|_| DecodeError::InvalidLength)?;
+        let secs = |bytes| Duration::from_secs(u16::from_le_bytes(bytes) as u64);
+        Ok(RelaisLimits {
+            max_on: secs([m0, m1]),
+            min_off: secs([o0, o1]),
+        })
+    }
+
+    /// Limits over about 18 hours are cut
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let secs = |d: Duration| (d.as_secs().min(u16::MAX as u64) as u16).to_le_bytes();
+        let ([m0, m1], [o0, o1]) = (secs(self.max_on), secs(self.min_off));
+        [m0, m1, o0, o1]
+    }
+}
+
+/// Wear of a channel since its relay was fitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelaisStats {
+    /// How often the channel switched on
+    pub cycles: u32,
+    /// Time the channel was on in total
+    pub on_time: Duration,
+}
+
+/// `local_code` of relay related error reports
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RelaisErrorCode {
+    Unknown = 0,
+    /// The stored `RelaisMode` is unknown, the default is used
+    InvalidMode = 1,
+    /// The channel does not exist in the configured mode
+    InvalidChannel = 2,
+    /// A stored or written channel map is invalid. A stored one is replaced
+    /// by `RelaisMap::for_hw_rev`, a written one is not stored.
+    InvalidMap = 3,
+    /// An expander did not answer or a register read back wrong
+    ExpanderFault = 4,
+    /// An expander lost its configuration and was set up again
+    ExpanderReset = 5,
+    /// Unknown `PowerOn` behavior
+    InvalidPowerOn = 6,
+    /// A channel was on for its `max_on` and was switched off
+    MaxOnTime = 7,
+    /// A channel was switched on within its `min_off`, it waits for it
+    MinOffTime = 8,
+    /// Invalid `RelaisLimits`
+    InvalidLimits = 9,
+    /// A channel switched more often than the `RelaisCycleLimit`, its relay
+    /// is due for replacement
+    CycleLimit = 10,
+}
+
+impl From<u8> for RelaisErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => RelaisErrorCode::InvalidMode,
+            2 => RelaisErrorCode::InvalidChannel,
+            3 => RelaisErrorCode::InvalidMap,
+            4 => RelaisErrorCode::ExpanderFault,
+            5 => RelaisErrorCode::ExpanderReset,
+            6 => RelaisErrorCode::InvalidPowerOn,
+            7 => RelaisErrorCode::MaxOnTime,
+            8 => RelaisErrorCode::MinOffTime,
+            9 => RelaisErrorCode::InvalidLimits,
+            10 => RelaisErrorCode::CycleLimit,
+            _ => RelaisErrorCode::Unknown,
+        }
+    }
+}
+
+/// Command for a relay or rollershutter.
+///
+/// Layout v2, [`LEN`] bytes:
+///
+/// | byte | content                                 |
+/// |------|-----------------------------------------|
+/// | 0    | channel                                 |
+/// | 1    | [`RelaisState`]                         |
+/// | 2..5 | duration in ms, 24 bit little endian    |
+/// | 5    | bank                                    |
+/// | 6    | flags, reserved, sent as 0              |
+/// | 7    | layout version, [`VERSION`]             |
+///
+/// Frames of [`LEGACY_LEN`] bytes without flags and version still decode.
+/// Their sender wrote a 32-bit duration and overwrote its top byte with the
+/// bank, so only 24 bits of the duration ever arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RelaisMessage {
     pub num: usize,
     pub state: RelaisState,
-    pub duration: Duration, // reicht, da 24 Bit = max. ~16.7 Mio ms = ~4.5h
+    /// Encoded in milliseconds, longer durations are cut to [`MAX_DURATION`]
+    pub duration: Duration,
     pub bank: u8,
+    pub flags: u8,
 }
 
-impl RelaisMessage {
-    pub async fn from_bytes(data: &[u8]) -> Result<Self, ()> {
-        if data.len() < 2 {
-            return Err(());
-        }
+pub const LEN: usize = 8;
+pub const LEGACY_LEN: usize = 6;
+pub const VERSION: u8 = 2;
+/// 24 bit in milliseconds, about 4.6 hours
+pub const MAX_DURATION: Duration = Duration::from_millis(0xFF_FFFF);
 
-        let num = data[0] as usize;
-        let state: RelaisState = RelaisState::try_from(data[1])?;
-        let duration = {
-            let mut buf = [0u8; 4];
-            buf.copy_from_slice(&data[2..6]);
-            Duration::from_millis(u32::from_le_bytes(buf) as u64)
+impl RelaisMessage {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        let flags = match data.len() {
+            LEGACY_LEN => 0,
+            LEN if data[7] == VERSION => data[6],
+            // a later layout we do not know
+            LEN => return Err(DecodeError::InvalidValue),
+            _ => return Err(DecodeError::InvalidLength),
         };
 
-        let bank = data[5];
-
+        let state = RelaisState::try_from(data[1]).map_err(|_| DecodeError::InvalidValue)?;
         Ok(RelaisMessage {
+            num: data[0] as usize,
+            state,
+            duration: from_millis24([data[2], data[3], data[4]]),
+            bank: data[5],
+            flags,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; LEN] {
+        let [d0, d1, d2] = millis24(self.duration);
+        [
+            self.num as u8,
+            self.state as u8,
+            d0,
+            d1,
+            d2,
+            self.bank,
+            self.flags,
+            VERSION,
+        ]
+    }
+}
+
+/// State of a relay sent as `RelaisState`, laid out like the first bytes of
+/// a [`RelaisMessage`].
+///
+/// | byte | content                                       |
+/// |------|-----------------------------------------------|
+/// | 0    | channel                                       |
+/// | 1    | [`RelaisState`]                               |
+/// | 2..5 | ms until a timer changes the state, 0 if none |
+/// | 5    | bank                                          |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelaisStatus {
+    pub num: u8,
+    pub state: RelaisState,
+    /// Encoded in milliseconds, longer durations are cut to [`MAX_DURATION`]
+    pub remaining: Duration,
+    pub bank: u8,
+}
+
+impl RelaisStatus {
+    pub const LEN: usize = 6;
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        let [num, state, r0, r1, r2, bank]: [u8; Self::LEN] =
+            data.try_into().map_err(|_| DecodeError::InvalidLength)?;
+        Ok(RelaisStatus {
+            num,
+            state: RelaisState::try_from(state).map_err(|_| DecodeError::InvalidValue)?,
+            remaining: from_millis24([r0, r1, r2]),
+            bank,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let [r0, r1, r2] = millis24(self.remaining);
+        [self.num, self.state as u8, r0, r1, r2, self.bank]
+    }
+}
+
+/// State of a shutter sent as `RollershutterState`.
+///
+/// | byte | content                                         |
+/// |------|-------------------------------------------------|
+/// | 0    | channel                                         |
+/// | 1    | [`RelaisState`] of the motor, `Off` when idle   |
+/// | 2    | percent closed, [`UNKNOWN_POSITION`] if unknown |
+/// | 3    | bank                                            |
+/// | 4    | tilt of the slats, [`UNKNOWN_POSITION`] if none |
+/// | 5..8 | ms until the motor changes, 0 without timer     |
+///
+/// Frames without the tilt or the time still decode. Receivers ignore
+/// further bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutterStatus {
+    pub num: u8,
+    pub state: RelaisState,
+    pub position: Option<u8>,
+    pub bank: u8,
+    pub tilt: Option<u8>,
+    /// Encoded in milliseconds, longer durations are cut to [`MAX_DURATION`]
+    pub remaining: Duration,
+}
+
+pub const UNKNOWN_POSITION: u8 = 0xFF;
+
+impl ShutterStatus {
+    pub const LEN: usize = 8;
+    const MIN_LEN: usize = 4;
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        let [num, state, position, bank] = data
+            .get(..Self::MIN_LEN)
+            .and_then(|data| data.try_into().ok())
+            .ok_or(DecodeError::InvalidLength)?;
+        let state = RelaisState::try_from(state).map_err(|_| DecodeError::InvalidValue)?;
+        Ok(ShutterStatus {
             num,
             state,
-            duration,
+            position: known(position),
             bank,
+            tilt: data.get(4).copied().and_then(known),
+            remaining: match data.get(5..Self::LEN) {
+                Some(&[r0, r1, r2]) => from_millis24([r0, r1, r2]),
+                _ => Duration::from_millis(0),
+            },
         })
     }
-    pub fn to_bytes(&self) -> [u8; 6] {
-        let mut bytes = [0u8; 6];
 
-        bytes[0] = self.num as u8;
-        bytes[1] = self.state as u8;
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let [r0, r1, r2] = millis24(self.remaining);
+        [
+            self.num,
+            self.state as u8,
+            self.position.unwrap_or(UNKNOWN_POSITION),
+            self.bank,
+            self.tilt.unwrap_or(UNKNOWN_POSITION),
+            r0,
+            r1,
+            r2,
+        ]
+    }
+}
+
+fn known(value: u8) -> Option<u8> {
+    (value != UNKNOWN_POSITION).then_some(value)
+}
+
+/// 24 bits in milliseconds, longer durations are cut to [`MAX_DURATION`]
+pub(crate) fn millis24(duration: Duration) -> [u8; 3] {
+    let [b0, b1, b2, _] = (duration.min(MAX_DURATION).as_millis() as u32).to_le_bytes();
+    [b0, b1, b2]
+}
 
-        let ms = self.duration.as_millis() as u32;
-        let dur_bytes = ms.to_le_bytes();
-        bytes[2..6].copy_from_slice(&dur_bytes);
+pub(crate) fn from_millis24([b0, b1, b2]: [u8; 3]) -> Duration {
+    Duration::from_millis(u32::from_le_bytes([b0, b1, b2, 0]) as u64)
+}
 
-        bytes[5] = self.bank;
-        bytes
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let msg = RelaisMessage {
+            num: 11,
+            state: RelaisState::Down,
+            duration: MAX_DURATION,
+            bank: 1,
+            flags: 0,
+        };
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes, [11, 2, 0xFF, 0xFF, 0xFF, 1, 0, VERSION]);
+        assert_eq!(RelaisMessage::from_bytes(&bytes), Ok(msg));
+
+        // bank and duration no longer overwrite each other
+        let long = RelaisMessage {
+            duration: Duration::from_secs(24 * 3600),
+            bank: 0,
+            ..msg
+        };
+        let decoded = RelaisMessage::from_bytes(&long.to_bytes()).unwrap();
+        assert_eq!(decoded.duration, MAX_DURATION);
+        assert_eq!(decoded.bank, 0);
+    }
+
+    #[test]
+    fn test_legacy() {
+        // 12 s on bank 1 as sent by old gateways
+        let legacy = [3, 1, 0xE0, 0x2E, 0x00, 0x01];
+        let msg = RelaisMessage::from_bytes(&legacy).unwrap();
+        assert_eq!(msg.num, 3);
+        assert_eq!(msg.state, RelaisState::Up);
+        assert_eq!(msg.duration, Duration::from_millis(12_000));
+        assert_eq!(msg.bank, 1);
+        assert_eq!(msg.flags, 0);
+    }
+
+    #[test]
+    fn test_states() {
+        for value in 0..=7 {
+            let state = RelaisState::try_from(value).unwrap();
+            assert_eq!(state as u8, value);
+        }
+        assert_eq!(RelaisState::try_from(8), Err(()));
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(RelaisMode::try_from(0), Ok(RelaisMode::Relais));
+        assert_eq!(RelaisMode::try_from(2), Ok(RelaisMode::default()));
+        assert_eq!(RelaisMode::try_from(3), Err(()));
+        assert_eq!(RelaisMode::Relais.channels(12), 12);
+        assert_eq!(RelaisMode::SoftwareRollershutter.channels(12), 6);
+    }
+
+    /// Replays every transition of a shutter output by output
+    #[test]
+    fn test_outputs_interlock() {
+        use RelaisState::*;
+        let states = [Off, Up, Down, On, Off];
+        for mode in [
+            RelaisMode::SoftwareRollershutter,
+            RelaisMode::HardwareRollershutter,
+        ] {
+            for from in states {
+                for to in states {
+                    let mut relays = [false; 4];
+                    for (output, on) in mode.outputs(1, Off, from) {
+                        relays[output] = on;
+                    }
+                    let power = relays[2];
+                    for (output, on) in mode.outputs(1, from, to) {
+                        let direction = relays[3];
+                        relays[output] = on;
+                        match mode {
+                            RelaisMode::SoftwareRollershutter => {
+                                assert!(!(relays[2] && relays[3]), "{from:?} -> {to:?}")
+                            }
+                            // no direction change under power
+                            _ => assert!(
+                                !(relays[2] && power && relays[3] != direction),
+                                "{from:?} -> {to:?}"
+                            ),
+                        }
+                    }
+                    assert_eq!(relays[..2], [false, false]);
+                    let expected = match (mode, to) {
+                        (_, Off | On) => [false, false],
+                        (_, Up) => [true, false],
+                        (RelaisMode::SoftwareRollershutter, _) => [false, true],
+                        (_, _) => [true, true],
+                    };
+                    assert_eq!(relays[2..], expected, "{mode:?} {to:?}");
+                }
+            }
+        }
+        assert_eq!(&RelaisMode::Relais.outputs(5, Off, On)[..], &[(5, true)]);
+        assert_eq!(&RelaisMode::Relais.outputs(5, On, Up)[..], &[(5, false)]);
+    }
+
+    #[test]
+    fn test_shutter_status() {
+        let status = ShutterStatus {
+            num: 2,
+            state: RelaisState::Up,
+            position: Some(40),
+            bank: 0,
+            tilt: Some(60),
+            remaining: Duration::from_millis(0x01_0203),
+        };
+        assert_eq!(status.to_bytes(), [2, 1, 40, 0, 60, 3, 2, 1]);
+        assert_eq!(ShutterStatus::from_bytes(&status.to_bytes()), Ok(status));
+        let short = ShutterStatus::from_bytes(&[2, 1, 40, 0, 60]).unwrap();
+        assert_eq!(short.remaining, Duration::from_millis(0));
+        let unknown = ShutterStatus::from_bytes(&[2, 0, UNKNOWN_POSITION, 0]).unwrap();
+        assert_eq!(unknown.position, None);
+        assert_eq!(unknown.tilt, None);
+        assert_eq!(
+            ShutterStatus::from_bytes(&[2, 1, 40]),
+            Err(DecodeError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let v2 = [0, 0, 0, 0, 0, 0, 0, VERSION];
+        for len in [0, 1, 2, 5, 7] {
+            assert_eq!(
+                RelaisMessage::from_bytes(&v2[..len]),
+                Err(DecodeError::InvalidLength)
+            );
+        }
+        let mut unknown = v2;
+        unknown[7] = VERSION + 1;
+        assert_eq!(
+            RelaisMessage::from_bytes(&unknown),
+            Err(DecodeError::InvalidValue)
+        );
+        let mut state = v2;
+        state[1] = 0xFF;
+        assert_eq!(
+            RelaisMessage::from_bytes(&state),
+            Err(DecodeError::InvalidValue)
+        );
     }
 }
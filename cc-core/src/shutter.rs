@@ -0,0 +1,415 @@
+//! Position estimation of a rollershutter from its drive time.
+//!
+//! A shutter has no sensor, its position follows from how long the motor ran
+//! in which direction. The travel times for a full run up and down are
+//! measured once and stored per shutter. Everything here takes the current
+//! time as argument, so the estimation runs with any clock.
+//!
+//! Venetian blinds turn their slats at the start of every run before the
+//! blind itself moves. With a turn time the tilt of the slats is tracked the
+//! same way as the position and set by short runs.
+
+use crate::relais_message::RelaisState;
+use embassy_time::{Duration, Instant};
+
+/// Position of a closed shutter in 1/100 %, 0 is fully open (up). Tilts use
+/// the same scale, 0 is how the slats stand after a run up.
+pub const CLOSED: u16 = 10_000;
+/// Positions closer than this to the target count as reached, 1 %
+const TOLERANCE: u16 = CLOSED / 100;
+
+/// Time for a full run in each direction, zero if not measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutterTiming {
+    pub up: Duration,
+    pub down: Duration,
+    /// Time the slats of a venetian blind need to turn from open to closed,
+    /// zero for shutters without slats
+    pub turn: Duration,
+}
+
+impl ShutterTiming {
+    pub fn is_calibrated(&self) -> bool {
+        self.up.as_ticks() != 0 && self.down.as_ticks() != 0
+    }
+
+    pub fn has_slats(&self) -> bool {
+        self.turn.as_ticks() != 0
+    }
+
+    fn full(&self, direction: RelaisState) -> Duration {
+        if direction == RelaisState::Down {
+            self.down
+        } else {
+            self.up
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Motion {
+    direction: RelaisState,
+    since: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Shutter {
+    timing: ShutterTiming,
+    /// Position when the current motion started, `None` until the shutter
+    /// once ran into an end stop
+    position: Option<u16>,
+    /// Tilt when the current motion started, `None` until the slats once
+    /// turned fully
+    tilt: Option<u16>,
+    motion: Option<Motion>,
+    /// Percent closed the shutter is moving to
+    target: Option<u8>,
+    /// Tilt in percent set after the position is reached
+    tilt_target: Option<u8>,
+}
+
+impl Shutter {
+    pub fn new(timing: ShutterTiming) -> Self {
+        Self {
+            timing,
+            position: None,
+            tilt: None,
+            motion: None,
+            target: None,
+            tilt_target: None,
+        }
+    }
+
+    pub fn timing(&self) -> ShutterTiming {
+        self.timing
+    }
+
+    /// Position and tilt are unknown again after the timing changed
+    pub fn set_timing(&mut self, timing: ShutterTiming, now: Instant) {
+        self.timing = timing;
+        self.position = None;
+        self.tilt = None;
+        self.cancel();
+        self.motion = self.motion.map(|motion| Motion {
+            since: now,
+            ..motion
+        });
+    }
+
+    /// Estimated position in 1/100 %, see [`CLOSED`]
+    pub fn position(&self, now: Instant) -> Option<u16> {
+        if !self.timing.is_calibrated() {
+            return None;
+        }
+        let Some(motion) = self.motion else {
+            return self.position;
+        };
+        let elapsed = now.saturating_duration_since(motion.since);
+        // the blind only moves once the slats are turned
+        let moving = elapsed
+            .checked_sub(self.turn_left(motion.direction))
+            .unwrap_or_default();
+        let full = self.timing.full(motion.direction);
+        follow(self.position, motion.direction, travelled(moving, full))
+    }
+
+    /// Estimated tilt of the slats in 1/100 %, see [`CLOSED`]
+    pub fn tilt(&self, now: Instant) -> Option<u16> {
+        if !self.timing.has_slats() {
+            return None;
+        }
+        let Some(motion) = self.motion else {
+            return self.tilt;
+        };
+        let elapsed = now.saturating_duration_since(motion.since);
+        let turned = travelled(elapsed, self.timing.turn);
+        follow(self.tilt, motion.direction, turned)
+    }
+
+    /// Estimated position in percent closed
+    pub fn percent(&self, now: Instant) -> Option<u8> {
+        self.position(now).map(percent)
+    }
+
+    /// Estimated tilt in percent closed
+    pub fn tilt_percent(&self, now: Instant) -> Option<u8> {
+        self.tilt(now).map(percent)
+    }
+
+    /// The motor output changed to `state`, only `Up` and `Down` move
+    pub fn drive(&mut self, state: RelaisState, now: Instant) {
+        let direction = matches!(state, RelaisState::Up | RelaisState::Down).then_some(state);
+        if self.motion.map(|motion| motion.direction) == direction {
+            return;
+        }
+        // both depend on the tilt at the start of the motion
+        let (position, tilt) = (self.position(now), self.tilt(now));
+        self.position = position;
+        self.tilt = tilt;
+        self.motion = direction.map(|direction| Motion {
+            direction,
+            since: now,
+        });
+    }
+
+    /// Starts a move to `percent` closed, see [`next_move`](Self::next_move).
+    /// The slats turn fully on the way and are set back to their tilt after.
+    pub fn move_to(&mut self, percent: u8, now: Instant) -> Option<(RelaisState, Duration)> {
+        self.target = Some(percent.min(100));
+        if self.tilt_target.is_none() {
+            self.tilt_target = self.tilt_percent(now);
+        }
+        self.next_move(now)
+    }
+
+    /// Turns the slats to `percent` closed, `None` for shutters without slats
+    pub fn tilt_to(&mut self, percent: u8, now: Instant) -> Option<(RelaisState, Duration)> {
+        if !self.timing.has_slats() {
+            return None;
+        }
+        self.tilt_target = Some(percent.min(100));
+        self.next_move(now)
+    }
+
+    /// A manual command ends the move to the target
+    pub fn cancel(&mut self) {
+        self.target = None;
+        self.tilt_target = None;
+    }
+
+    /// Direction and drive time for the next leg to the target position and
+    /// then the target tilt, `None` once both are reached. From an unknown
+    /// position the shutter first runs into the end stop nearer to the
+    /// target, the slats likewise.
+    pub fn next_move(&mut self, now: Instant) -> Option<(RelaisState, Duration)> {
+        if !self.timing.is_calibrated() {
+            self.cancel();
+            return None;
+        }
+        if let Some(target) = self.target {
+            let leg = self.position_leg(target, now);
+            if leg.is_some() {
+                return leg;
+            }
+            self.target = None;
+        }
+        let leg = self.tilt_leg(self.tilt_target?, now);
+        if leg.is_none() {
+            self.tilt_target = None;
+        }
+        leg
+    }
+
+    fn position_leg(&self, target: u8, now: Instant) -> Option<(RelaisState, Duration)> {
+        let goal = target as u16 * 100;
+        let Some(position) = self.position(now) else {
+            let direction = toward(CLOSED / 2, goal);
+            let full = self.timing.full(direction);
+            return Some((direction, self.timing.turn + full + full / 10));
+        };
+        if is_reached(position, goal) {
+            return None;
+        }
+        let direction = toward(position, goal);
+        let full = self.timing.full(direction);
+        let mut duration = self.turn_left(direction) + scale(position.abs_diff(goal), full);
+        // the end stop catches any error of the estimation
+        if goal == 0 || goal == CLOSED {
+            duration += full / 10;
+        }
+        Some((direction, duration))
+    }
+
+    /// Short runs that only turn the slats, the blind stays in place
+    fn tilt_leg(&self, target: u8, now: Instant) -> Option<(RelaisState, Duration)> {
+        let goal = target as u16 * 100;
+        let Some(tilt) = self.tilt(now) else {
+            return Some((toward(CLOSED / 2, goal), self.timing.turn));
+        };
+        if is_reached(tilt, goal) {
+            return None;
+        }
+        let turn = scale(tilt.abs_diff(goal), self.timing.turn);
+        Some((toward(tilt, goal), turn))
+    }
+
+    /// Time the slats still turn at the start of a run into `direction`
+    fn turn_left(&self, direction: RelaisState) -> Duration {
+        let left = match (self.tilt, direction) {
+            (Some(tilt), RelaisState::Down) => CLOSED - tilt,
+            (Some(tilt), _) => tilt,
+            (None, _) => CLOSED,
+        };
+        scale(left, self.timing.turn)
+    }
+}
+
+/// Moves `start` by `travelled` into `direction`. An unknown start is known
+/// after a full run, it always ends in the end stop.
+fn follow(start: Option<u16>, direction: RelaisState, travelled: u16) -> Option<u16> {
+    match (start, direction) {
+        (Some(start), RelaisState::Down) => Some((start + travelled).min(CLOSED)),
+        (Some(start), _) => Some(start.saturating_sub(travelled)),
+        (None, RelaisState::Down) if travelled == CLOSED => Some(CLOSED),
+        (None, _) if travelled == CLOSED => Some(0),
+        (None, _) => None,
+    }
+}
+
+/// Distance covered in `elapsed` when a full run takes `full`
+fn travelled(elapsed: Duration, full: Duration) -> u16 {
+    let full = full.as_millis().max(1);
+    (elapsed.as_millis() * CLOSED as u64 / full).min(CLOSED as u64) as u16
+}
+
+/// Time to cover `distance` when a full run takes `full`
+fn scale(distance: u16, full: Duration) -> Duration {
+    Duration::from_millis((distance as u64 * full.as_millis()).div_ceil(CLOSED as u64))
+}
+
+fn toward(from: u16, goal: u16) -> RelaisState {
+    if goal > from {
+        RelaisState::Down
+    } else {
+        RelaisState::Up
+    }
+}
+
+/// The end stops are exact, anything else within [`TOLERANCE`]
+fn is_reached(value: u16, goal: u16) -> bool {
+    if goal == 0 || goal == CLOSED {
+        value == goal
+    } else {
+        value.abs_diff(goal) <= TOLERANCE
+    }
+}
+
+fn percent(value: u16) -> u8 {
+    ((value + 50) / 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMING: ShutterTiming = ShutterTiming {
+        up: Duration::from_secs(20),
+        down: Duration::from_secs(10),
+        turn: Duration::from_secs(0),
+    };
+    const VENETIAN: ShutterTiming = ShutterTiming {
+        turn: Duration::from_secs(2),
+        ..TIMING
+    };
+
+    fn at(secs: u64) -> Instant {
+        Instant::from_millis(secs * 1000)
+    }
+
+    /// Drives as `next_move` says until the target is reached
+    fn run(shutter: &mut Shutter, mut now: Instant) -> Instant {
+        while let Some((direction, duration)) = shutter.next_move(now) {
+            shutter.drive(direction, now);
+            now += duration;
+            shutter.drive(RelaisState::Off, now);
+        }
+        now
+    }
+
+    #[test]
+    fn test_unknown_until_end_stop() {
+        let mut shutter = Shutter::new(TIMING);
+        shutter.drive(RelaisState::Down, at(0));
+        assert_eq!(shutter.percent(at(5)), None);
+        assert_eq!(shutter.percent(at(10)), Some(100));
+        shutter.drive(RelaisState::Up, at(12));
+        assert_eq!(shutter.percent(at(17)), Some(75));
+        shutter.drive(RelaisState::Off, at(17));
+        assert_eq!(shutter.percent(at(60)), Some(75));
+
+        // a stop before the end stop leaves the position unknown
+        let mut shutter = Shutter::new(TIMING);
+        shutter.drive(RelaisState::Up, at(0));
+        shutter.drive(RelaisState::Off, at(19));
+        assert_eq!(shutter.percent(at(20)), None);
+    }
+
+    #[test]
+    fn test_same_direction_keeps_motion() {
+        let mut shutter = Shutter::new(TIMING);
+        shutter.drive(RelaisState::Down, at(0));
+        shutter.drive(RelaisState::Down, at(6));
+        assert_eq!(shutter.percent(at(10)), Some(100));
+    }
+
+    #[test]
+    fn test_move_to() {
+        let mut shutter = Shutter::new(TIMING);
+        // reference run into the lower end stop first
+        assert_eq!(
+            shutter.move_to(70, at(0)),
+            Some((RelaisState::Down, Duration::from_secs(11)))
+        );
+        let now = run(&mut shutter, at(0));
+        assert_eq!(shutter.percent(now), Some(70));
+
+        assert_eq!(
+            shutter.move_to(20, now),
+            Some((RelaisState::Up, Duration::from_secs(10)))
+        );
+        let now = run(&mut shutter, now);
+        assert_eq!(shutter.percent(now), Some(20));
+
+        // a full close runs into the end stop
+        assert_eq!(
+            shutter.move_to(100, now),
+            Some((RelaisState::Down, Duration::from_secs(9)))
+        );
+        let now = run(&mut shutter, now);
+        assert_eq!(shutter.position(now), Some(CLOSED));
+        assert_eq!(shutter.move_to(100, now), None);
+    }
+
+    #[test]
+    fn test_tilt() {
+        let mut shutter = Shutter::new(VENETIAN);
+        // the slats turn before the blind moves
+        shutter.drive(RelaisState::Down, at(0));
+        assert_eq!(shutter.tilt_percent(at(2)), Some(100));
+        assert_eq!(shutter.percent(at(11)), None);
+        assert_eq!(shutter.percent(at(12)), Some(100));
+        shutter.drive(RelaisState::Off, at(13));
+
+        assert_eq!(
+            shutter.tilt_to(25, at(13)),
+            Some((RelaisState::Up, Duration::from_millis(1500)))
+        );
+        let now = run(&mut shutter, at(13));
+        assert_eq!(shutter.tilt_percent(now), Some(25));
+        assert_eq!(shutter.percent(now), Some(100));
+
+        // up to the position, then the slats go back
+        assert_eq!(
+            shutter.move_to(50, now),
+            Some((RelaisState::Up, Duration::from_millis(10_500)))
+        );
+        let now = run(&mut shutter, now);
+        assert_eq!(shutter.percent(now), Some(50));
+        assert_eq!(shutter.tilt_percent(now), Some(25));
+
+        assert_eq!(Shutter::new(TIMING).tilt_to(50, now), None);
+    }
+
+    #[test]
+    fn test_cancel_and_uncalibrated() {
+        let mut shutter = Shutter::new(TIMING);
+        shutter.move_to(0, at(0));
+        shutter.cancel();
+        assert_eq!(shutter.next_move(at(0)), None);
+
+        let mut shutter = Shutter::new(ShutterTiming::default());
+        shutter.drive(RelaisState::Down, at(0));
+        assert_eq!(shutter.percent(at(100)), None);
+        assert_eq!(shutter.move_to(50, at(100)), None);
+    }
+}
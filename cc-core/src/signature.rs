@@ -0,0 +1,125 @@
+//! Signature trailer of firmware images.
+//!
+//! A signed image is the firmware followed by [`TRAILER_LEN`] bytes: the
+//! Ed25519 signature over the SHA-256 digest of the firmware, then [`MAGIC`].
+//! The bootloader ignores bytes past the end of the application, so the
+//! trailer can stay in the slot. The device hashes the image while reading it
+//! back for `FlashVerify` and only activates the slot if the signature
+//! matches the key it was built with.
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+pub const MAGIC: [u8; 4] = *b"CCS1";
+pub const SIGNATURE_LEN: usize = 64;
+pub const TRAILER_LEN: usize = SIGNATURE_LEN + MAGIC.len();
+/// Length of an Ed25519 public or secret key
+pub const KEY_LEN: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The image is too short or does not end with [`MAGIC`]
+    Unsigned,
+    InvalidKey,
+    /// The signature does not belong to this firmware and key
+    Mismatch,
+}
+
+/// Checks an image piece by piece, fed with the firmware without trailer
+#[derive(Default)]
+pub struct Verifier {
+    hasher: Sha256,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// SHA-256 of the firmware, the signed message
+    pub fn digest(self) -> [u8; 32] {
+        self.hasher.finalize().into()
+    }
+
+    pub fn verify(
+        self,
+        trailer: &[u8; TRAILER_LEN],
+        key: &[u8; KEY_LEN],
+    ) -> Result<(), SignatureError> {
+        let (signature, magic) = trailer.split_at(SIGNATURE_LEN);
+        if magic != MAGIC {
+            return Err(SignatureError::Unsigned);
+        }
+        let key = VerifyingKey::from_bytes(key).map_err(|_| SignatureError::InvalidKey)?;
+        let signature = Signature::from_bytes(signature.try_into().unwrap());
+        key.verify_strict(&self.digest(), &signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+}
+
+pub fn digest(firmware: &[u8]) -> [u8; 32] {
+    let mut verifier = Verifier::new();
+    verifier.update(firmware);
+    verifier.digest()
+}
+
+/// Splits a signed image into firmware and trailer
+pub fn split(image: &[u8]) -> Result<(&[u8], &[u8; TRAILER_LEN]), SignatureError> {
+    if image.len() <= TRAILER_LEN {
+        return Err(SignatureError::Unsigned);
+    }
+    let (firmware, trailer) = image.split_at(image.len() - TRAILER_LEN);
+    Ok((firmware, trailer.try_into().unwrap()))
+}
+
+/// Checks a complete signed image
+pub fn verify(image: &[u8], key: &[u8; KEY_LEN]) -> Result<(), SignatureError> {
+    let (firmware, trailer) = split(image)?;
+    let mut verifier = Verifier::new();
+    verifier.update(firmware);
+    verifier.verify(trailer, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn sign(firmware: &[u8], secret: &[u8; KEY_LEN]) -> ([u8; 256], usize) {
+        let signature = SigningKey::from_bytes(secret).sign(&digest(firmware));
+        let mut image = [0u8; 256];
+        let len = firmware.len() + TRAILER_LEN;
+        image[..firmware.len()].copy_from_slice(firmware);
+        image[firmware.len()..len - MAGIC.len()].copy_from_slice(&signature.to_bytes());
+        image[len - MAGIC.len()..len].copy_from_slice(&MAGIC);
+        (image, len)
+    }
+
+    #[test]
+    fn test_verify() {
+        let secret = [7u8; KEY_LEN];
+        let key = SigningKey::from_bytes(&secret).verifying_key().to_bytes();
+        let firmware = [0xE9u8; 100];
+        let (mut image, len) = sign(&firmware, &secret);
+
+        assert_eq!(verify(&image[..len], &key), Ok(()));
+        assert_eq!(
+            verify(&image[..len], &[1u8; KEY_LEN]),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(verify(&firmware, &key), Err(SignatureError::Unsigned));
+        assert_eq!(verify(&image[..10], &key), Err(SignatureError::Unsigned));
+
+        // piecewise like the device reading back the slot
+        let (body, trailer) = split(&image[..len]).unwrap();
+        let mut verifier = Verifier::new();
+        body.chunks(7).for_each(|chunk| verifier.update(chunk));
+        assert_eq!(verifier.verify(trailer, &key), Ok(()));
+
+        image[3] ^= 1;
+        assert_eq!(verify(&image[..len], &key), Err(SignatureError::Mismatch));
+    }
+}
@@ -0,0 +1,608 @@
+//! ISO-TP style segmentation for payloads that do not fit into a single frame.
+//!
+//! The first payload byte of every frame is a protocol control byte:
+//!
+//! | frame         | byte 0        | byte 1..                         |
+//! |---------------|---------------|----------------------------------|
+//! | single        | `0x8` + len   | up to 7 data bytes               |
+//! | first         | `0x9` + len   | low length byte, 6 data bytes    |
+//! | consecutive   | `0xA` + seq   | up to 7 data bytes               |
+//! | flow control  | `0xB` + state | block size, separation time (ms) |
+//!
+//! Unlike ISO-TP the control bytes lie in `0x80..=0xBF`, where no UTF-8 text
+//! starts. Strings sent unsegmented by older nodes stay apart, see
+//! [`is_segmented`].
+//!
+//! Both [`Sender`] and [`Receiver`] are plain state machines, the caller passes
+//! in the current time and moves the frames.
+use crate::can_message::Payload;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// Largest payload the 12 bit length of a first frame can describe
+pub const MAX_TRANSFER_LEN: usize = 4095;
+/// Time to wait for the next flow control or consecutive frame
+pub const TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Control bytes are `PCI_BASE + (frame type << 4) + low nibble`
+const PCI_BASE: u8 = 0x80;
+const SINGLE_LEN: usize = 7;
+const FIRST_LEN: usize = 6;
+const CONSECUTIVE_LEN: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportError {
+    InvalidFrame,
+    TooLong,
+    UnexpectedFrame,
+    WrongSequence,
+    Overflow,
+    Timeout,
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowStatus {
+    ContinueToSend = 0,
+    Wait = 1,
+    Overflow = 2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Single(Vec<u8, SINGLE_LEN>),
+    First {
+        len: u16,
+        data: Vec<u8, FIRST_LEN>,
+    },
+    Consecutive {
+        seq: u8,
+        data: Vec<u8, CONSECUTIVE_LEN>,
+    },
+    FlowControl {
+        status: FlowStatus,
+        block_size: u8,
+        st_min: u8,
+    },
+}
+
+impl Frame {
+    pub fn to_bytes(&self) -> Payload {
+        let mut bytes = Payload::new();
+        // every variant fits into 8 bytes by construction
+        match self {
+            Frame::Single(data) => {
+                bytes.push(PCI_BASE | data.len() as u8).unwrap();
+                bytes.extend_from_slice(data).unwrap();
+            }
+            Frame::First { len, data } => {
+                bytes
+                    .push(PCI_BASE | 0x10 | (len >> 8) as u8 & 0x0F)
+                    .unwrap();
+                bytes.push(*len as u8).unwrap();
+                bytes.extend_from_slice(data).unwrap();
+            }
+            Frame::Consecutive { seq, data } => {
+                bytes.push(PCI_BASE | 0x20 | seq & 0x0F).unwrap();
+                bytes.extend_from_slice(data).unwrap();
+            }
+            Frame::FlowControl {
+                status,
+                block_size,
+                st_min,
+            } => {
+                bytes
+                    .extend_from_slice(&[PCI_BASE | 0x30 | *status as u8, *block_size, *st_min])
+                    .unwrap();
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, TransportError> {
+        let (&pci, rest) = data.split_first().ok_or(TransportError::InvalidFrame)?;
+        if !is_segmented(data) {
+            return Err(TransportError::InvalidFrame);
+        }
+        let low = pci & 0x0F;
+        let frame = match (pci - PCI_BASE) >> 4 {
+            0 => {
+                let len = low as usize;
+                if len > SINGLE_LEN || rest.len() < len {
+                    return Err(TransportError::InvalidFrame);
+                }
+                Frame::Single(Vec::from_slice(&rest[..len]).unwrap())
+            }
+            1 => {
+                let (&len_low, rest) = rest.split_first().ok_or(TransportError::InvalidFrame)?;
+                let len = (low as u16) << 8 | len_low as u16;
+                if (len as usize) <= SINGLE_LEN || rest.len() != FIRST_LEN {
+                    return Err(TransportError::InvalidFrame);
+                }
+                Frame::First {
+                    len,
+                    data: Vec::from_slice(rest).unwrap(),
+                }
+            }
+            2 => Frame::Consecutive {
+                seq: low,
+                data: Vec::from_slice(rest).map_err(|_| TransportError::InvalidFrame)?,
+            },
+            3 => {
+                let status = match low {
+                    0 => FlowStatus::ContinueToSend,
+                    1 => FlowStatus::Wait,
+                    2 => FlowStatus::Overflow,
+                    _ => return Err(TransportError::InvalidFrame),
+                };
+                let [block_size, st_min] = rest
+                    .get(..2)
+                    .and_then(|s| s.try_into().ok())
+                    .ok_or(TransportError::InvalidFrame)?;
+                Frame::FlowControl {
+                    status,
+                    block_size,
+                    st_min,
+                }
+            }
+            _ => return Err(TransportError::InvalidFrame),
+        };
+        Ok(frame)
+    }
+
+    pub fn is_flow_control(data: &[u8]) -> bool {
+        matches!(data.first(), Some(pci) if pci >> 4 == 0xB)
+    }
+}
+
+/// Whether `data` is a transport frame and not an unsegmented string of an
+/// older node
+pub fn is_segmented(data: &[u8]) -> bool {
+    matches!(data.first(), Some(0x80..=0xBF))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SenderState {
+    Start,
+    WaitFlowControl { deadline: Instant },
+    Sending { next_at: Instant },
+    Done,
+}
+
+/// Splits one payload of up to `N` bytes into frames
+pub struct Sender<const N: usize> {
+    data: Vec<u8, N>,
+    offset: usize,
+    seq: u8,
+    block_size: u8,
+    block_remaining: u8,
+    st_min: Duration,
+    state: SenderState,
+}
+
+impl<const N: usize> Sender<N> {
+    pub fn new(data: &[u8]) -> Result<Self, TransportError> {
+        if data.len() > MAX_TRANSFER_LEN {
+            return Err(TransportError::TooLong);
+        }
+        Ok(Self {
+            data: Vec::from_slice(data).map_err(|_| TransportError::TooLong)?,
+            offset: 0,
+            seq: 1,
+            block_size: 0,
+            block_remaining: 0,
+            st_min: Duration::from_millis(0),
+            state: SenderState::Start,
+        })
+    }
+
+    /// Returns the next frame to transmit, `None` while waiting for the
+    /// receiver or after the transfer is finished.
+    pub fn poll(&mut self, now: Instant) -> Result<Option<Payload>, TransportError> {
+        match self.state {
+            SenderState::Start => {
+                let frame = if self.data.len() <= SINGLE_LEN {
+                    self.state = SenderState::Done;
+                    Frame::Single(Vec::from_slice(&self.data).unwrap())
+                } else {
+                    self.offset = FIRST_LEN;
+                    self.state = SenderState::WaitFlowControl {
+                        deadline: now + TIMEOUT,
+                    };
+                    Frame::First {
+                        len: self.data.len() as u16,
+                        data: Vec::from_slice(&self.data[..FIRST_LEN]).unwrap(),
+                    }
+                };
+                Ok(Some(frame.to_bytes()))
+            }
+            SenderState::WaitFlowControl { deadline } => {
+                if now >= deadline {
+                    self.state = SenderState::Done;
+                    return Err(TransportError::Timeout);
+                }
+                Ok(None)
+            }
+            SenderState::Sending { next_at } => {
+                if now < next_at {
+                    return Ok(None);
+                }
+                let end = (self.offset + CONSECUTIVE_LEN).min(self.data.len());
+                let frame = Frame::Consecutive {
+                    seq: self.seq,
+                    data: Vec::from_slice(&self.data[self.offset..end]).unwrap(),
+                };
+                self.offset = end;
+                self.seq = (self.seq + 1) & 0x0F;
+
+                self.state = if self.offset == self.data.len() {
+                    SenderState::Done
+                } else if self.block_size != 0 && {
+                    self.block_remaining -= 1;
+                    self.block_remaining == 0
+                } {
+                    SenderState::WaitFlowControl {
+                        deadline: now + TIMEOUT,
+                    }
+                } else {
+                    SenderState::Sending {
+                        next_at: now + self.st_min,
+                    }
+                };
+                Ok(Some(frame.to_bytes()))
+            }
+            SenderState::Done => Ok(None),
+        }
+    }
+
+    pub fn on_flow_control(&mut self, data: &[u8], now: Instant) -> Result<(), TransportError> {
+        let Frame::FlowControl {
+            status,
+            block_size,
+            st_min,
+        } = Frame::from_bytes(data)?
+        else {
+            return Err(TransportError::UnexpectedFrame);
+        };
+        if !matches!(self.state, SenderState::WaitFlowControl { .. }) {
+            return Err(TransportError::UnexpectedFrame);
+        }
+
+        match status {
+            FlowStatus::ContinueToSend => {
+                self.block_size = block_size;
+                self.block_remaining = block_size;
+                self.st_min = Duration::from_millis(st_min.min(0x7F) as u64);
+                self.state = SenderState::Sending { next_at: now };
+            }
+            FlowStatus::Wait => {
+                self.state = SenderState::WaitFlowControl {
+                    deadline: now + TIMEOUT,
+                };
+            }
+            FlowStatus::Overflow => {
+                self.state = SenderState::Done;
+                return Err(TransportError::Overflow);
+            }
+        }
+        Ok(())
+    }
+
+    /// Point in time at which [`Sender::poll`] has to be called again
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self.state {
+            SenderState::Start => Some(Instant::MIN),
+            SenderState::WaitFlowControl { deadline } => Some(deadline),
+            SenderState::Sending { next_at } => Some(next_at),
+            SenderState::Done => None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == SenderState::Done
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RxStatus {
+    Pending,
+    /// The given flow control frame has to be sent back to the sender
+    SendFlowControl(Payload),
+    /// The transfer does not fit, the given overflow frame has to be sent back
+    Rejected(Payload),
+    Complete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReceiverState {
+    Idle,
+    Receiving { deadline: Instant },
+    Complete,
+}
+
+/// Reassembles one payload of up to `N` bytes
+pub struct Receiver<const N: usize> {
+    data: Vec<u8, N>,
+    len: usize,
+    seq: u8,
+    block_size: u8,
+    block_remaining: u8,
+    st_min: u8,
+    state: ReceiverState,
+}
+
+impl<const N: usize> Receiver<N> {
+    pub const fn new(block_size: u8, st_min: u8) -> Self {
+        Self {
+            data: Vec::new(),
+            len: 0,
+            seq: 0,
+            block_size,
+            block_remaining: 0,
+            st_min,
+            state: ReceiverState::Idle,
+        }
+    }
+
+    pub fn on_frame(&mut self, data: &[u8], now: Instant) -> Result<RxStatus, TransportError> {
+        match Frame::from_bytes(data)? {
+            Frame::Single(data) => {
+                self.data.clear();
+                self.data
+                    .extend_from_slice(&data)
+                    .map_err(|_| TransportError::Overflow)?;
+                self.state = ReceiverState::Complete;
+                Ok(RxStatus::Complete)
+            }
+            Frame::First { len, data } => {
+                if len as usize > N {
+                    self.state = ReceiverState::Idle;
+                    return Ok(RxStatus::Rejected(
+                        self.flow_control(FlowStatus::Overflow).to_bytes(),
+                    ));
+                }
+                self.data.clear();
+                self.data.extend_from_slice(&data).unwrap();
+                self.len = len as usize;
+                self.seq = 1;
+                self.block_remaining = self.block_size;
+                self.state = ReceiverState::Receiving {
+                    deadline: now + TIMEOUT,
+                };
+                Ok(RxStatus::SendFlowControl(
+                    self.flow_control(FlowStatus::ContinueToSend).to_bytes(),
+                ))
+            }
+            Frame::Consecutive { seq, data } => {
+                let ReceiverState::Receiving { deadline } = self.state else {
+                    return Err(TransportError::UnexpectedFrame);
+                };
+                if now >= deadline {
+                    self.state = ReceiverState::Idle;
+                    return Err(TransportError::Timeout);
+                }
+                if seq != self.seq {
+                    self.state = ReceiverState::Idle;
+                    return Err(TransportError::WrongSequence);
+                }
+                self.seq = (self.seq + 1) & 0x0F;
+
+                let take = data.len().min(self.len - self.data.len());
+                self.data.extend_from_slice(&data[..take]).unwrap();
+                if self.data.len() == self.len {
+                    self.state = ReceiverState::Complete;
+                    return Ok(RxStatus::Complete);
+                }
+
+                self.state = ReceiverState::Receiving {
+                    deadline: now + TIMEOUT,
+                };
+                if self.block_size != 0 {
+                    self.block_remaining -= 1;
+                    if self.block_remaining == 0 {
+                        self.block_remaining = self.block_size;
+                        return Ok(RxStatus::SendFlowControl(
+                            self.flow_control(FlowStatus::ContinueToSend).to_bytes(),
+                        ));
+                    }
+                }
+                Ok(RxStatus::Pending)
+            }
+            Frame::FlowControl { .. } => Err(TransportError::UnexpectedFrame),
+        }
+    }
+
+    /// Drops a transfer whose sender went silent
+    pub fn poll(&mut self, now: Instant) -> Result<(), TransportError> {
+        match self.state {
+            ReceiverState::Receiving { deadline } if now >= deadline => {
+                self.state = ReceiverState::Idle;
+                Err(TransportError::Timeout)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The reassembled payload once the transfer is complete
+    pub fn data(&self) -> Option<&[u8]> {
+        match self.state {
+            ReceiverState::Complete => Some(&self.data),
+            _ => None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.data.clear();
+        self.state = ReceiverState::Idle;
+    }
+
+    fn flow_control(&self, status: FlowStatus) -> Frame {
+        Frame::FlowControl {
+            status,
+            block_size: self.block_size,
+            st_min: self.st_min,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves frames between a sender and a receiver until both are idle
+    fn loopback<const N: usize, const M: usize>(
+        sender: &mut Sender<N>,
+        receiver: &mut Receiver<M>,
+        now: &mut Instant,
+    ) -> Result<usize, TransportError> {
+        let mut frames = 0;
+        while !sender.is_done() {
+            match sender.poll(*now)? {
+                Some(frame) => {
+                    frames += 1;
+                    match receiver.on_frame(&frame, *now)? {
+                        RxStatus::SendFlowControl(fc) => sender.on_flow_control(&fc, *now)?,
+                        RxStatus::Rejected(fc) => sender.on_flow_control(&fc, *now)?,
+                        RxStatus::Pending | RxStatus::Complete => {}
+                    }
+                }
+                None => *now = sender.next_deadline().unwrap().max(*now),
+            }
+        }
+        Ok(frames)
+    }
+
+    fn payload(len: usize) -> Vec<u8, 512> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frames = [
+            Frame::Single(Vec::from_slice(b"abc").unwrap()),
+            Frame::Single(Vec::new()),
+            Frame::First {
+                len: 4095,
+                data: Vec::from_slice(b"123456").unwrap(),
+            },
+            Frame::Consecutive {
+                seq: 15,
+                data: Vec::from_slice(b"1234567").unwrap(),
+            },
+            Frame::FlowControl {
+                status: FlowStatus::Wait,
+                block_size: 8,
+                st_min: 5,
+            },
+        ];
+        for frame in frames {
+            assert_eq!(Frame::from_bytes(&frame.to_bytes()), Ok(frame));
+        }
+        assert_eq!(Frame::from_bytes(&[]), Err(TransportError::InvalidFrame));
+        assert_eq!(
+            Frame::from_bytes(&[0x85, 1]),
+            Err(TransportError::InvalidFrame)
+        );
+        assert_eq!(
+            Frame::from_bytes(&[0xB3, 0, 0]),
+            Err(TransportError::InvalidFrame)
+        );
+        // ISO-TP control bytes and text are no transport frames
+        assert_eq!(
+            Frame::from_bytes(&[0x05, 1, 2, 3, 4, 5]),
+            Err(TransportError::InvalidFrame)
+        );
+        assert!(!is_segmented(b"0 floor"));
+        assert!(!Frame::is_flow_control(b"0 floor"));
+    }
+
+    #[test]
+    fn test_loopback_lengths() {
+        for len in [0, 1, 7, 8, 13, 14, 100, 300, 512] {
+            let data = payload(len);
+            let mut sender = Sender::<512>::new(&data).unwrap();
+            let mut receiver = Receiver::<512>::new(4, 2);
+            let mut now = Instant::from_millis(0);
+
+            let frames = loopback(&mut sender, &mut receiver, &mut now).unwrap();
+            assert_eq!(receiver.data(), Some(&data[..]));
+
+            let expected = if len <= 7 {
+                1
+            } else {
+                1 + (len - 6).div_ceil(7)
+            };
+            assert_eq!(frames, expected);
+        }
+    }
+
+    #[test]
+    fn test_separation_time() {
+        let data = payload(6 + 7 * 4);
+        let mut sender = Sender::<64>::new(&data).unwrap();
+        let mut receiver = Receiver::<64>::new(0, 10);
+        let mut now = Instant::from_millis(0);
+
+        loopback(&mut sender, &mut receiver, &mut now).unwrap();
+        // first consecutive frame is sent right away, the other three wait
+        assert_eq!(now, Instant::from_millis(30));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let data = payload(100);
+        let mut sender = Sender::<128>::new(&data).unwrap();
+        let mut receiver = Receiver::<64>::new(0, 0);
+        let mut now = Instant::from_millis(0);
+
+        assert_eq!(
+            loopback(&mut sender, &mut receiver, &mut now),
+            Err(TransportError::Overflow)
+        );
+        assert!(sender.is_done());
+        assert_eq!(receiver.data(), None);
+
+        assert_eq!(
+            Sender::<8192>::new(&[0; 4096]).err(),
+            Some(TransportError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_sender_timeout() {
+        let mut sender = Sender::<64>::new(&payload(20)).unwrap();
+        let now = Instant::from_millis(0);
+        assert!(sender.poll(now).unwrap().is_some());
+        assert_eq!(sender.poll(now + Duration::from_millis(999)), Ok(None));
+        assert_eq!(sender.poll(now + TIMEOUT), Err(TransportError::Timeout));
+        assert!(sender.is_done());
+    }
+
+    #[test]
+    fn test_receiver_errors() {
+        let data = payload(20);
+        let mut sender = Sender::<64>::new(&data).unwrap();
+        let mut receiver = Receiver::<64>::new(0, 0);
+        let now = Instant::from_millis(0);
+
+        let first = sender.poll(now).unwrap().unwrap();
+        let RxStatus::SendFlowControl(fc) = receiver.on_frame(&first, now).unwrap() else {
+            panic!("expected flow control");
+        };
+        sender.on_flow_control(&fc, now).unwrap();
+        let _skipped = sender.poll(now).unwrap().unwrap();
+        let second = sender.poll(now).unwrap().unwrap();
+        assert_eq!(
+            receiver.on_frame(&second, now),
+            Err(TransportError::WrongSequence)
+        );
+        assert_eq!(
+            receiver.on_frame(&second, now),
+            Err(TransportError::UnexpectedFrame)
+        );
+
+        receiver.on_frame(&first, now).unwrap();
+        assert_eq!(receiver.poll(now + TIMEOUT), Err(TransportError::Timeout));
+        assert_eq!(receiver.data(), None);
+    }
+}
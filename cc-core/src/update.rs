@@ -0,0 +1,128 @@
+//! Firmware update protocol shared by the device and the host uploader.
+//!
+//! `FlashStart` carries size and CRC-32 of the image, `FlashWrite` one chunk of
+//! up to [`CHUNK_LEN`] bytes. The device answers `FlashStart`, every
+//! [`ACK_INTERVAL`] written bytes and the last chunk with `FlashProgress`, the
+//! host only sends the next block after that. An RTR `FlashVerify` reads the
+//! image back, checks the CRC and the [`signature`](crate::signature) and
+//! activates the slot, `Restart` boots it.
+//! Failures are reported with `DeviceError` and `Component::Ota` or
+//! `Component::Update`.
+//!
+//! Images start with an [`ImageHeader`](crate::image_header::ImageHeader), a
+//! device refuses images for other device types or hardware revisions.
+//!
+//! The image goes to the slot chosen with `FlashSelect`, by default to the one
+//! not running.
+//!
+//! A restarted image is pending until it sent `Available`, or until the host
+//! sends `BootConfirm`. After [`MAX_BOOT_ATTEMPTS`] boots without confirmation
+//! the device returns to the previous slot. The outcome is reported with
+//! `BootStatus` after the next `Available`.
+//!
+//! An interrupted update can be continued: a `FlashStart` with the same size
+//! and CRC is acknowledged with the bytes already written instead of 0, the
+//! host continues from there. After a restart this is the progress saved every
+//! [`RESUME_INTERVAL`] bytes.
+//!
+//! A `FlashCompressed` before `FlashStart` announces a stream compressed as
+//! described in [`compression`](crate::compression).
+//!
+//! Identical devices can be updated together. Each of them gets `FlashStart`
+//! and the header as above, then `FlashJoin`, which erases the rest of the slot.
+//! The host sends the image once to the broadcast address as `FlashChunk`
+//! frames, devices without a joined update ignore them. An RTR `FlashGaps` is
+//! answered with up to [`MAX_GAP_REPORTS`] windows of missing chunks, see
+//! [`ChunkMap`](crate::chunk_map::ChunkMap), and `FlashProgress` with the bytes
+//! received without a gap. The host repeats the missing chunks until every
+//! device has the whole image, then verifies each one.
+
+/// Version of the update protocol, part of the image header
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Bytes per `FlashWrite` frame
+pub const CHUNK_LEN: usize = 8;
+/// Bytes written between two `FlashProgress` acknowledgements
+pub const ACK_INTERVAL: u32 = 64;
+/// Bytes per `FlashChunk` frame
+pub const MULTICAST_CHUNK_LEN: usize = 4;
+/// `FlashGaps` frames sent for one request
+pub const MAX_GAP_REPORTS: usize = 16;
+/// Bytes between two saves of the update progress, one flash sector
+pub const RESUME_INTERVAL: u32 = 4096;
+/// Number of OTA slots
+pub const SLOT_COUNT: u8 = 2;
+/// Largest `len` of a `FlashRead`
+pub const MAX_READ_LEN: u8 = 64;
+/// Boots of a new image without confirmation before rolling back
+pub const MAX_BOOT_ATTEMPTS: u8 = 3;
+
+/// `local_code` of update related error reports
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateErrorCode {
+    Unknown = 0,
+    InvalidData = 1,
+    Begin = 2,
+    Init = 3,
+    Write = 4,
+    NotStarted = 5,
+    Flush = 6,
+    /// The slot is running or does not exist
+    InvalidSlot = 7,
+    Erase = 8,
+    Read = 9,
+    /// The image is unsigned or not signed with the device's key
+    Signature = 10,
+    /// The image header does not match the device, see
+    /// [`HeaderError`](crate::image_header::HeaderError) in the details
+    Incompatible = 11,
+    /// The compressed stream is corrupt or was used for a multicast update
+    Compression = 12,
+}
+
+impl From<u8> for UpdateErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => UpdateErrorCode::InvalidData,
+            2 => UpdateErrorCode::Begin,
+            3 => UpdateErrorCode::Init,
+            4 => UpdateErrorCode::Write,
+            5 => UpdateErrorCode::NotStarted,
+            6 => UpdateErrorCode::Flush,
+            7 => UpdateErrorCode::InvalidSlot,
+            8 => UpdateErrorCode::Erase,
+            9 => UpdateErrorCode::Read,
+            10 => UpdateErrorCode::Signature,
+            11 => UpdateErrorCode::Incompatible,
+            12 => UpdateErrorCode::Compression,
+            _ => UpdateErrorCode::Unknown,
+        }
+    }
+}
+
+/// State of the running image in `BootStatus`
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BootState {
+    /// No update since the last report
+    Valid = 0,
+    /// Booted after an update, not confirmed yet
+    Pending = 1,
+    Confirmed = 2,
+    /// The new image was never confirmed, running the previous one again
+    RolledBack = 3,
+}
+
+impl TryFrom<u8> for BootState {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(BootState::Valid),
+            1 => Ok(BootState::Pending),
+            2 => Ok(BootState::Confirmed),
+            3 => Ok(BootState::RolledBack),
+            _ => Err(()),
+        }
+    }
+}
@@ -1,5 +1,6 @@
 fn main() {
     vergen().unwrap();
+    update_key().unwrap();
     linker_be_nice();
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
@@ -14,6 +15,51 @@ fn vergen() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Embeds the public key for signed updates, see `update::UPDATE_KEY`.
+/// `CC_UPDATE_KEY` names a file with the key in hex as written by
+/// `cc-tool keygen` and is required for release builds. Debug builds may use
+/// the development key in `keys/dev.pub` instead when `CC_DEV_KEY=1` is set,
+/// its secret key is not part of the repository.
+fn update_key() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-env-changed=CC_UPDATE_KEY");
+    println!("cargo:rerun-if-env-changed=CC_DEV_KEY");
+    let path = match std::env::var("CC_UPDATE_KEY") {
+        Ok(path) => std::path::PathBuf::from(path),
+        Err(_) if std::env::var("PROFILE")? == "release" => {
+            return Err("CC_UPDATE_KEY must be set for release builds".into());
+        }
+        Err(_) if std::env::var("CC_DEV_KEY").is_ok_and(|v| v == "1") => {
+            println!(
+                "cargo:warning=CC_DEV_KEY set, accepting images signed with the development key"
+            );
+            std::path::Path::new(&std::env::var("CARGO_MANIFEST_DIR")?).join("keys/dev.pub")
+        }
+        Err(_) => {
+            return Err(
+                "CC_UPDATE_KEY not set, set CC_DEV_KEY=1 to use the development key in a debug build"
+                    .into(),
+            );
+        }
+    };
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let hex = std::fs::read_to_string(&path)?;
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("{}: expected 32 bytes in hex", path.display()).into());
+    }
+    let bytes = (0..32)
+        .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let out = std::path::Path::new(&std::env::var("OUT_DIR")?).join("update_key.rs");
+    std::fs::write(
+        out,
+        format!("pub const UPDATE_KEY: [u8; 32] = {bytes:?};\n"),
+    )?;
+    Ok(())
+}
+
 fn linker_be_nice() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
@@ -26,7 +26,10 @@ async fn main(spawner: Spawner) -> ! {
     config::init().await;
     device::init().await;
     update::init().await;
-
+    update::boot().await;
+    // Senden läuft schon, damit Meldungen der Wiederherstellung nicht in
+    // der Warteschlange hängen bleiben. Befehle für die Relais warten, bis
+    // `Relais::init` fertig ist.
     can::init(
         peripherals.TWAI0,
         peripherals.GPIO14,
@@ -34,13 +37,15 @@ async fn main(spawner: Spawner) -> ! {
         &spawner,
     )
     .await;
-
     Relais::init(
         peripherals.I2C0,
         peripherals.GPIO21,
         peripherals.GPIO19,
         &spawner,
-    );
+    )
+    .await;
+    can::announce().await;
+    update::supervise_boot(&spawner);
 
     Extension::init(
         ExtensionType::GpioInput4,
@@ -1,13 +1,23 @@
 use crate::config;
 use crate::device::device;
-use crate::relais::{relais_handler, rollershutter_handler};
+use crate::error;
+use crate::relais::{
+    relais_cycle_limit_handler, relais_handler, relais_limits_handler, relais_map_handler,
+    relais_mode_handler, relais_power_on_handler, relais_state_handler, relais_stats_handler,
+    rollershutter_handler, shutter_dead_time_handler, shutter_position_handler,
+    shutter_slat_timing_handler, shutter_tilt_handler, shutter_timing_handler,
+};
+use crate::transport;
 use crate::update::update;
 use cancomponents_core::can_id::CanId;
+use cancomponents_core::can_message::CanMessage;
 use cancomponents_core::can_message_type::CanMessageType;
+use cancomponents_core::transport::Frame as TpFrame;
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
 use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
 use embedded_can::Frame;
 use esp_hal::gpio::{InputPin, OutputPin};
 use esp_hal::twai::filter::DualExtendedFilter;
@@ -23,6 +33,8 @@ static TWAI_TX: StaticCell<TwaiTx<'_, Async>> = StaticCell::new();
 pub static DEVICE_ID: Mutex<CriticalSectionRawMutex, u8> = Mutex::new(255);
 pub static DEVICE_TYPE: Mutex<CriticalSectionRawMutex, u8> = Mutex::new(255);
 pub static SILENCE: Mutex<CriticalSectionRawMutex, bool> = Mutex::new(false);
+/// Das `Available` nach dem Start wurde von einem anderen Knoten bestätigt
+pub static ANNOUNCED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
 pub fn make_filter(device_type: u8, device_id: u8) -> DualExtendedFilter {
     let is_ng = true;
@@ -76,6 +88,12 @@ pub async fn init(
 
     spawner.spawn(can_send_task(tx)).unwrap();
 
+    transport::init(spawner);
+}
+
+/// Meldet den Knoten mit `Available` am Bus an, erst nachdem die Relais
+/// wiederhergestellt sind
+pub async fn announce() {
     let id = CanId::new(
         *DEVICE_TYPE.lock().await,
         *DEVICE_ID.lock().await,
@@ -101,21 +119,55 @@ pub async fn dispatch(frame: &EspTwaiFrame) {
         return;
     }
 
+    // flow control belongs to a running transfer, not to the message handler
+    if is_segmented(id.msg_type)
+        && !frame.is_remote_frame()
+        && TpFrame::is_flow_control(frame.data())
+    {
+        transport::flow_control(id.msg_type, frame.data());
+        return;
+    }
+
     match id.msg_type {
         CanMessageType::Relais => relais_handler(id, frame.data(), frame.is_remote_frame()).await,
         CanMessageType::Rollershutter => {
             rollershutter_handler(id, frame.data(), frame.is_remote_frame()).await
         }
+        CanMessageType::RelaisState | CanMessageType::RollershutterState => {
+            relais_state_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::RelaisPowerOn => {
+            relais_power_on_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::RelaisLimits => {
+            relais_limits_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::RelaisStats => {
+            relais_stats_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::RelaisCycleLimit => {
+            relais_cycle_limit_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::RelaisMap => {
+            relais_map_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
         CanMessageType::RelaisMode => {
-            let _ = device()
-                .await
-                .u8_val(
-                    id,
-                    frame.data(),
-                    frame.is_remote_frame(),
-                    config::Key::RelaisMode,
-                )
-                .await;
+            relais_mode_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::ShutterPosition => {
+            shutter_position_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::ShutterTiming => {
+            shutter_timing_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::ShutterTilt => {
+            shutter_tilt_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::ShutterSlatTiming => {
+            shutter_slat_timing_handler(id, frame.data(), frame.is_remote_frame()).await
+        }
+        CanMessageType::ShutterDeadTime => {
+            shutter_dead_time_handler(id, frame.data(), frame.is_remote_frame()).await
         }
         CanMessageType::ExtensionMode => {
             let _ = device()
@@ -158,6 +210,12 @@ pub async fn dispatch(frame: &EspTwaiFrame) {
                 .custom_string(id, frame.data(), frame.is_remote_frame())
                 .await;
         }
+        CanMessageType::ApplicationVersionString => {
+            device()
+                .await
+                .application_version_string(id, frame.data(), frame.is_remote_frame())
+                .await
+        }
         CanMessageType::DeviceIdType => {
             let _ = device()
                 .await
@@ -234,6 +292,43 @@ pub async fn dispatch(frame: &EspTwaiFrame) {
                 .erase(id, frame.data(), frame.is_remote_frame())
                 .await
         }
+        CanMessageType::FlashCompressed => {
+            update()
+                .await
+                .compressed(id, frame.data(), frame.is_remote_frame())
+                .await
+        }
+        CanMessageType::FlashJoin => {
+            update()
+                .await
+                .join(id, frame.data(), frame.is_remote_frame())
+                .await
+        }
+        CanMessageType::FlashChunk => {
+            update()
+                .await
+                .chunk(id, frame.data(), frame.is_remote_frame())
+                .await
+        }
+        CanMessageType::FlashGaps => {
+            update()
+                .await
+                .gaps(id, frame.data(), frame.is_remote_frame())
+                .await
+        }
+        CanMessageType::BootStatus => {
+            update()
+                .await
+                .boot_status(id, frame.data(), frame.is_remote_frame())
+                .await
+        }
+        CanMessageType::BootConfirm => {
+            update()
+                .await
+                .boot_confirm(id, frame.data(), frame.is_remote_frame())
+                .await
+        }
+        CanMessageType::LogDownload if frame.is_remote_frame() => error::send_log().await,
         CanMessageType::UpdateSilence => silence(frame).await,
         CanMessageType::Ping => ping(id).await,
         CanMessageType::Available => ping(id).await,
@@ -241,6 +336,25 @@ pub async fn dispatch(frame: &EspTwaiFrame) {
     }
 }
 
+fn is_announce(frame: &EspTwaiFrame) -> bool {
+    match frame.id() {
+        embedded_can::Id::Extended(id) => {
+            CanId::from(id).msg_type == CanMessageType::Available && frame.data() == [1]
+        }
+        embedded_can::Id::Standard(_) => false,
+    }
+}
+
+fn is_segmented(msg_type: CanMessageType) -> bool {
+    matches!(
+        msg_type,
+        CanMessageType::CustomString
+            | CanMessageType::ApplicationVersionString
+            | CanMessageType::LogDownload
+            | CanMessageType::RelaisMap
+    )
+}
+
 async fn silence(frame: &EspTwaiFrame) {
     let mut silence = SILENCE.lock().await;
     let data = frame.data();
@@ -278,6 +392,11 @@ pub async fn send_can_message(msg_id: CanMessageType, data: &[u8], rtr: bool) {
     CAN_CHANNEL.send(frame).await
 }
 
+/// Sends a typed message, RTR for `CanMessage::Request`
+pub async fn send_message(msg: &CanMessage) {
+    send_can_message(msg.msg_type(), &msg.payload(), msg.is_remote()).await
+}
+
 // === CAN Task ===
 
 #[embassy_executor::task]
@@ -302,5 +421,9 @@ pub async fn can_send_task(tx: &'static mut TwaiTx<'static, Async>) {
         }
         println!("can_send_task:{frame:?}");
         tx.transmit_async(&frame).await.unwrap();
+        // gesendet heißt: mindestens ein Knoten hat den Frame quittiert
+        if is_announce(&frame) {
+            ANNOUNCED.signal(());
+        }
     }
 }
@@ -21,6 +21,30 @@ pub enum Key {
     CustomString = 5,
     Baudrate = 6,
     HardwareRevision = 7,
+    /// Fortschritt eines Firmware-Updates, siehe `update::Update`
+    UpdateState = 8,
+    /// Start eines neuen Images bis zur Meldung, siehe `update::boot`
+    BootRecord = 9,
+    /// Fahrzeiten der Rollläden, siehe `relais::load_timings`
+    ShutterTiming = 10,
+    /// Wendezeiten der Lamellen, siehe `relais::load_timings`
+    SlatTiming = 11,
+    /// Pause zwischen den Richtungen der Motoren in ms
+    DeadTime = 12,
+    /// Kanalzuordnung der Relais, siehe `relais::load_map`
+    RelaisMap = 13,
+    /// Verhalten je Kanal nach dem Einschalten, siehe `relais::load_power_on`
+    PowerOn = 14,
+    /// Zuletzt geschaltete Zustände für `PowerOn::Last`
+    LastState = 15,
+    /// Schutzgrenzen je Kanal, siehe `relais::load_limits`
+    RelaisLimits = 16,
+    /// Schaltspiele je Kanal, siehe `relais::load_stats`
+    RelaisCycles = 17,
+    /// Einschaltdauer je Kanal in s
+    RelaisOnTime = 18,
+    /// Schaltspiele bis zur Verschleißwarnung
+    CycleLimit = 19,
 }
 
 pub async fn init() {
@@ -134,4 +158,31 @@ impl Config {
         .await
         .map_err(|_| ())
     }
+
+    pub async fn get_bytes<const N: usize>(&mut self, key: Key) -> Option<[u8; N]> {
+        let raw = fetch_item::<u8, &[u8], _>(
+            &mut self.flash,
+            CONFIG_PARTITION.clone(),
+            &mut self.cache,
+            &mut self.buffer,
+            &(key as u8),
+        )
+        .await
+        .ok()
+        .flatten()?;
+        raw.try_into().ok()
+    }
+
+    pub async fn set_bytes(&mut self, key: Key, value: &[u8]) -> Result<(), ()> {
+        store_item(
+            &mut self.flash,
+            CONFIG_PARTITION.clone(),
+            &mut self.cache,
+            &mut self.buffer,
+            &(key as u8),
+            &value,
+        )
+        .await
+        .map_err(|_| ())
+    }
 }
@@ -1,15 +1,19 @@
 use crate::can::{send_can_message, DEVICE_ID, DEVICE_TYPE};
 use crate::config::{self, config};
-use crate::error::{Component, ErrorCode, ErrorReport, Severity};
+use crate::error::{send_error_report, Component, ErrorCode, Severity};
+use crate::transport;
 use cancomponents_core::can_id::CanId;
 use cancomponents_core::can_message_type::CanMessageType;
 use cancomponents_core::device_message::IdTypeMsg;
+use cancomponents_core::transport::{is_segmented, Receiver, RxStatus};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_time::Instant;
 use esp_hal::efuse::Efuse;
 use heapless::String;
 
+const CUSTOM_STRING_LEN: usize = 64;
+
 static DEVICE: Mutex<CriticalSectionRawMutex, Option<Device>> = Mutex::new(None);
 
 pub async fn init() {
@@ -21,9 +25,10 @@ pub async fn init() {
         let mut config = config().await;
         let device = Device {
             custom_string: config
-                .get_str::<8>(config::Key::CustomString)
+                .get_str::<CUSTOM_STRING_LEN>(config::Key::CustomString)
                 .await
                 .unwrap_or_default(),
+            custom_string_rx: Receiver::new(0, 0),
             id: config.get_u8(config::Key::DeviceId).await.unwrap_or(255),
             dtype: config.get_u8(config::Key::DeviceType).await.unwrap_or(255),
             uid0: 0,
@@ -43,7 +48,8 @@ pub async fn device(
     embassy_sync::mutex::MutexGuard::map(guard, |opt| opt.as_mut().expect("Device not initialized"))
 }
 pub struct Device {
-    custom_string: String<8>,
+    custom_string: String<CUSTOM_STRING_LEN>,
+    custom_string_rx: Receiver<CUSTOM_STRING_LEN>,
     id: u8,
     dtype: u8,
     uid0: u64,
@@ -132,12 +138,12 @@ impl Device {
                 config.set_u8(key, data[0]).await.ok()?;
                 esp_hal::system::software_reset();
             } else {
-                ErrorReport::send(
+                send_error_report(
                     Component::Device,
                     ErrorCode::InvalidData,
                     Severity::Warning,
                     0,
-                    &[id.msg_type as u8, data.len() as u8, 0u8],
+                    &[u8::from(id.msg_type), data.len() as u8, 0u8],
                 )
                 .await;
             }
@@ -153,25 +159,41 @@ impl Device {
     ) -> Option<()> {
         if remote_request {
             // RTR-Frame: Aktuellen String senden
-            let string = self.custom_string.as_bytes();
-
-            let len = self.custom_string.len();
-            let mut data = [0u8; 8];
-            data[..len].copy_from_slice(&string[..len]);
-            send_can_message(CanMessageType::CustomString, &data, false).await;
+            transport::send(CanMessageType::CustomString, self.custom_string.as_bytes()).await;
+        } else if !is_segmented(data) {
+            // Alte Knoten senden den Text unsegmentiert, mit Nullen aufgefüllt
+            let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+            let s = core::str::from_utf8(&data[..len]).ok()?;
+            self.store_custom_string(s).await?;
         } else {
-            let s = core::str::from_utf8(data).ok()?;
-            self.custom_string.clear();
-            self.custom_string.push_str(s).ok()?;
-            let mut config = config().await;
-            config
-                .set_str(config::Key::CustomString, &self.custom_string)
-                .await
-                .ok()?;
+            match self.custom_string_rx.on_frame(data, Instant::now()) {
+                Ok(RxStatus::Pending) => {}
+                Ok(RxStatus::SendFlowControl(fc)) | Ok(RxStatus::Rejected(fc)) => {
+                    send_can_message(CanMessageType::CustomString, &fc, false).await;
+                }
+                Ok(RxStatus::Complete) => {
+                    let data = self.custom_string_rx.data()?;
+                    let mut s = String::<CUSTOM_STRING_LEN>::new();
+                    s.push_str(core::str::from_utf8(data).ok()?).ok()?;
+                    self.store_custom_string(&s).await?;
+                }
+                Err(e) => transport::report(CanMessageType::CustomString, e).await,
+            }
         }
         Some(())
     }
 
+    async fn store_custom_string(&mut self, s: &str) -> Option<()> {
+        self.custom_string.clear();
+        self.custom_string.push_str(s).ok()?;
+        let mut config = config().await;
+        config
+            .set_str(config::Key::CustomString, &self.custom_string)
+            .await
+            .ok()?;
+        Some(())
+    }
+
     pub async fn application_version(&mut self, _id: CanId, _data: &[u8], _remote_request: bool) {
         let version = env!("VERGEN_GIT_DESCRIBE");
         let version_bytes = version.as_bytes();
@@ -182,6 +204,18 @@ impl Device {
 
         send_can_message(CanMessageType::ApplicationVersion, &buf, false).await;
     }
+
+    pub async fn application_version_string(
+        &mut self,
+        _id: CanId,
+        _data: &[u8],
+        remote_request: bool,
+    ) {
+        if remote_request {
+            let version = env!("VERGEN_GIT_DESCRIBE");
+            transport::send(CanMessageType::ApplicationVersionString, version.as_bytes()).await;
+        }
+    }
     pub async fn restart(&mut self, _id: CanId, _data: &[u8], remote_request: bool) {
         if !remote_request {
             esp_hal::system::software_reset();
@@ -5,5 +5,5 @@ pub mod device;
 pub mod error;
 pub mod extension;
 pub mod relais;
-pub mod relais_manager;
+pub mod transport;
 pub mod update;
@@ -1,172 +1,935 @@
 use esp_println::println;
 
-use crate::relais_manager::RelayManager;
+use crate::can::{send_can_message, send_message};
+use crate::config::{config, Key};
+use crate::device::device;
+use crate::error::{send_error_report, Component, ErrorCode, Severity};
+use crate::transport;
 use cancomponents_core::can_id::CanId;
-use cancomponents_core::relais_message::{RelaisMessage, RelaisState};
+use cancomponents_core::can_message::CanMessage;
+use cancomponents_core::can_message_type::CanMessageType;
+use cancomponents_core::coalesce::WriteCoalescer;
+use cancomponents_core::expander::{Expander, ExpanderError};
+use cancomponents_core::relais_manager::{
+    RelayManager, Violation, DEFAULT_CYCLE_LIMIT, DEFAULT_DEAD_TIME, MIN_DEAD_TIME,
+};
+use cancomponents_core::relais_map::{Output, RelaisMap, MAX_EXPANDERS, MAX_OUTPUTS};
+use cancomponents_core::relais_message::{
+    PowerOn, RelaisErrorCode, RelaisLimits, RelaisMessage, RelaisMode, RelaisState, RelaisStats,
+    RelaisStatus, ShutterStatus,
+};
+use cancomponents_core::shutter::ShutterTiming;
+use cancomponents_core::transport::{Receiver, RxStatus};
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
-use embassy_time::{Instant, Timer};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::once_lock::OnceLock;
+use embassy_time::{Duration, Instant, Timer};
 use esp_hal::gpio::interconnect::PeripheralOutput;
-use esp_hal::i2c::master::{Config, I2c};
+use esp_hal::i2c::master::I2c;
 use esp_hal::Async;
 
-const BANK: [u8; 2] = [0x26, 0x27];
-const MAX_RELAIS: usize = 16;
-// hard coded for now until we have an nvs working
-const RELAIS_MODE: RelaisMode = RelaisMode::HardwareRollershutter;
+const MAX_RELAIS: usize = MAX_OUTPUTS;
+const MAX_SHUTTERS: usize = MAX_RELAIS / 2;
+/// Je Rollladen Auf und Ab als u32 in ms
+const TIMING_LEN: usize = MAX_SHUTTERS * 8;
+/// Je Rollladen die Wendezeit als u32 in ms
+const SLAT_LEN: usize = MAX_SHUTTERS * 4;
+/// Je Kanal die `RelaisLimits`
+const LIMITS_LEN: usize = MAX_RELAIS * RelaisLimits::LEN;
+/// Je Kanal ein u32, für Schaltspiele und Einschaltdauer getrennt
+const STATS_LEN: usize = MAX_RELAIS * 4;
+/// Nummerierung der gespeicherten Zeiten, beide Rollladen-Verdrahtungen
+/// zählen die Kanäle gleich
+const SHUTTER_WIRING: RelaisMode = RelaisMode::HardwareRollershutter;
+/// Höchstens ein Schreiben des letzten Zustands in dieser Zeit, schont den
+/// Flash
+const LAST_STATE_INTERVAL: Duration = Duration::from_secs(30);
+/// Zähler ändern sich bei jedem Schalten, seltener speichern
+const STATS_INTERVAL: Duration = Duration::from_secs(600);
 
-pub enum RelaisMode {
-    Relais = 0,
-    SoftwareRollershutter = 1,
-    HardwareRollershutter = 2,
+/// Verdrahtung der Platine, beim Start einmal geladen
+struct Layout {
+    map: RelaisMap,
+    mode: RelaisMode,
 }
-static RELAIS_CHANNEL: Channel<CriticalSectionRawMutex, RelaisMessage, MAX_RELAIS> = Channel::new();
+
+static LAYOUT: OnceLock<Layout> = OnceLock::new();
+
+enum Command {
+    Switch(RelaisMessage),
+    Position {
+        num: usize,
+        percent: u8,
+    },
+    Tilt {
+        num: usize,
+        percent: u8,
+    },
+    Timing {
+        num: usize,
+        timing: ShutterTiming,
+    },
+    DeadTime(Duration),
+    Limits {
+        num: usize,
+        limits: RelaisLimits,
+    },
+    /// Zustand aller Kanäle melden, nach einem RTR
+    Report,
+    /// Zähler aller Kanäle melden, nach einem RTR
+    Stats,
+    SetStats {
+        num: usize,
+        stats: RelaisStats,
+    },
+    CycleLimit(u32),
+}
+
+impl Command {
+    /// Kanal über alle Bänke, `None` für Einstellungen aller Kanäle
+    fn num(&self) -> Option<usize> {
+        match self {
+            Command::Switch(msg) => Some(msg.num),
+            Command::Position { num, .. }
+            | Command::Tilt { num, .. }
+            | Command::Timing { num, .. }
+            | Command::Limits { num, .. }
+            | Command::SetStats { num, .. } => Some(*num),
+            Command::DeadTime(_) | Command::Report | Command::Stats | Command::CycleLimit(_) => {
+                None
+            }
+        }
+    }
+}
+
+static RELAIS_CHANNEL: Channel<CriticalSectionRawMutex, Command, MAX_RELAIS> = Channel::new();
+static MAP_RX: Mutex<CriticalSectionRawMutex, Receiver<{ RelaisMap::LEN }>> =
+    Mutex::new(Receiver::new(0, 0));
 
 pub async fn relais_handler(_id: CanId, data: &[u8], _remote_request: bool) {
-    if let Ok(msg) = RelaisMessage::from_bytes(data).await {
-        RELAIS_CHANNEL.send(msg).await;
+    if let Ok(msg) = RelaisMessage::from_bytes(data) {
+        switch(msg).await;
     }
     // silent error, already reportet is relais_message
 }
 
 pub async fn rollershutter_handler(_id: CanId, data: &[u8], _remote_request: bool) {
-    if let Ok(msg) = RelaisMessage::from_bytes(data).await {
-        RELAIS_CHANNEL.send(msg).await;
+    if let Ok(msg) = RelaisMessage::from_bytes(data) {
+        switch(msg).await;
     }
     // silent error, already reportet is relais_message
 }
 
+/// `num` des Befehls zählt danach über alle Bänke
+async fn switch(mut msg: RelaisMessage) {
+    let layout = LAYOUT.get().await;
+    if let Some(num) = channel(layout.mode, msg.bank, msg.num).await {
+        msg.num = num;
+        RELAIS_CHANNEL.send(Command::Switch(msg)).await;
+    }
+}
+
+pub async fn shutter_position_handler(id: CanId, data: &[u8], remote_request: bool) {
+    if let Ok(CanMessage::ShutterPosition { num, bank, percent }) =
+        CanMessage::decode(id, data, remote_request)
+    {
+        let mode = LAYOUT.get().await.mode;
+        if let Some(num) = channel(mode, bank, num as usize).await {
+            RELAIS_CHANNEL
+                .send(Command::Position { num, percent })
+                .await;
+        }
+    }
+}
+
+pub async fn shutter_tilt_handler(id: CanId, data: &[u8], remote_request: bool) {
+    if let Ok(CanMessage::ShutterTilt { num, bank, percent }) =
+        CanMessage::decode(id, data, remote_request)
+    {
+        let mode = LAYOUT.get().await.mode;
+        if let Some(num) = channel(mode, bank, num as usize).await {
+            RELAIS_CHANNEL.send(Command::Tilt { num, percent }).await;
+        }
+    }
+}
+
+/// Kanal `num` in `bank` über alle Bänke gezählt, meldet unbekannte Kanäle
+async fn channel(mode: RelaisMode, bank: u8, num: usize) -> Option<usize> {
+    let channel = LAYOUT.get().await.map.channel(mode, bank, num);
+    if channel.is_none() {
+        report(RelaisErrorCode::InvalidChannel, &[num as u8, bank]).await;
+    }
+    channel
+}
+
+/// Ein RTR liefert den Zustand aller Kanäle, je nach Verdrahtung als
+/// `RelaisState` oder `RollershutterState`
+pub async fn relais_state_handler(_id: CanId, _data: &[u8], remote_request: bool) {
+    if remote_request {
+        RELAIS_CHANNEL.send(Command::Report).await;
+    }
+}
+
+/// Speichert die Fahrzeiten eines Rollladens, ein RTR liefert alle
+pub async fn shutter_timing_handler(id: CanId, data: &[u8], remote_request: bool) {
+    match CanMessage::decode(id, data, remote_request) {
+        Ok(CanMessage::Request(_)) => {
+            let map = &LAYOUT.get().await.map;
+            for (num, timing) in board_timings(map).await {
+                let (bank, num) = map.split(SHUTTER_WIRING, num);
+                send_message(&CanMessage::ShutterTiming {
+                    num: num as u8,
+                    bank,
+                    up: timing.up,
+                    down: timing.down,
+                })
+                .await;
+            }
+        }
+        Ok(CanMessage::ShutterTiming {
+            num,
+            bank,
+            up,
+            down,
+        }) => {
+            update_timing(bank, num, |timing| {
+                timing.up = up;
+                timing.down = down;
+            })
+            .await
+        }
+        _ => {}
+    }
+}
+
+/// Speichert die Wendezeit der Lamellen, ein RTR liefert alle. Ein Rollladen
+/// mit Wendezeit ist eine Jalousie.
+pub async fn shutter_slat_timing_handler(id: CanId, data: &[u8], remote_request: bool) {
+    match CanMessage::decode(id, data, remote_request) {
+        Ok(CanMessage::Request(_)) => {
+            let map = &LAYOUT.get().await.map;
+            for (num, timing) in board_timings(map).await {
+                let (bank, num) = map.split(SHUTTER_WIRING, num);
+                send_message(&CanMessage::ShutterSlatTiming {
+                    num: num as u8,
+                    bank,
+                    turn: timing.turn,
+                })
+                .await;
+            }
+        }
+        Ok(CanMessage::ShutterSlatTiming { num, bank, turn }) => {
+            update_timing(bank, num, |timing| timing.turn = turn).await
+        }
+        _ => {}
+    }
+}
+
+/// Zeiten der Rollläden, die die Platine anschließen kann
+async fn board_timings(map: &RelaisMap) -> impl Iterator<Item = (usize, ShutterTiming)> {
+    let count = map.channels(SHUTTER_WIRING);
+    load_timings().await.into_iter().enumerate().take(count)
+}
+
+/// Ändert die gespeicherten Zeiten und übernimmt sie sofort
+async fn update_timing(bank: u8, num: u8, change: impl FnOnce(&mut ShutterTiming)) {
+    let Some(num) = channel(SHUTTER_WIRING, bank, num as usize).await else {
+        return;
+    };
+    let mut timing = load_timings().await[num];
+    change(&mut timing);
+    if store_timing(num, timing).await.is_ok() {
+        RELAIS_CHANNEL.send(Command::Timing { num, timing }).await;
+    }
+}
+
+/// Pause zwischen den Richtungen aller Motoren, gilt sofort
+pub async fn shutter_dead_time_handler(id: CanId, data: &[u8], remote_request: bool) {
+    match CanMessage::decode(id, data, remote_request) {
+        Ok(CanMessage::Request(_)) => {
+            send_message(&CanMessage::ShutterDeadTime(load_dead_time().await)).await
+        }
+        Ok(CanMessage::ShutterDeadTime(dead_time)) => {
+            let dead_time = dead_time.max(MIN_DEAD_TIME);
+            let ms = dead_time.as_millis() as u32;
+            if config().await.set_u32(Key::DeadTime, ms).await.is_ok() {
+                RELAIS_CHANNEL.send(Command::DeadTime(dead_time)).await;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Verhalten eines Relais nach dem Einschalten, gilt ab dem nächsten Start.
+/// Ein RTR liefert alle Kanäle.
+pub async fn relais_power_on_handler(id: CanId, data: &[u8], remote_request: bool) {
+    let layout = LAYOUT.get().await;
+    match CanMessage::decode(id, data, remote_request) {
+        Ok(CanMessage::Request(_)) => {
+            let power_on = load_power_on().await;
+            for channel in 0..layout.map.channels(layout.mode) {
+                let (bank, num) = layout.map.split(layout.mode, channel);
+                send_message(&CanMessage::RelaisPowerOn {
+                    num: num as u8,
+                    bank,
+                    power_on: power_on[channel],
+                })
+                .await;
+            }
+        }
+        Ok(CanMessage::RelaisPowerOn {
+            num,
+            bank,
+            power_on,
+        }) => {
+            let Some(channel) = channel(layout.mode, bank, num as usize).await else {
+                return;
+            };
+            let mut stored = load_power_on().await.map(|p| p as u8);
+            stored[channel] = power_on as u8;
+            let _ = config().await.set_bytes(Key::PowerOn, &stored).await;
+        }
+        Ok(_) => {}
+        Err(_) => report(RelaisErrorCode::InvalidPowerOn, data).await,
+    }
+}
+
+/// Schutzgrenzen eines Kanals, gelten sofort. Ein RTR liefert alle Kanäle.
+pub async fn relais_limits_handler(id: CanId, data: &[u8], remote_request: bool) {
+    let layout = LAYOUT.get().await;
+    match CanMessage::decode(id, data, remote_request) {
+        Ok(CanMessage::Request(_)) => {
+            let limits = load_limits().await;
+            for channel in 0..layout.map.channels(layout.mode) {
+                let (bank, num) = layout.map.split(layout.mode, channel);
+                send_message(&CanMessage::RelaisLimits {
+                    num: num as u8,
+                    bank,
+                    limits: limits[channel],
+                })
+                .await;
+            }
+        }
+        Ok(CanMessage::RelaisLimits { num, bank, limits }) => {
+            let Some(num) = channel(layout.mode, bank, num as usize).await else {
+                return;
+            };
+            let mut stored = [0; LIMITS_LEN];
+            for (raw, limits) in stored
+                .chunks_exact_mut(RelaisLimits::LEN)
+                .zip(load_limits().await)
+            {
+                raw.copy_from_slice(&limits.to_bytes());
+            }
+            let at = num * RelaisLimits::LEN;
+            stored[at..at + RelaisLimits::LEN].copy_from_slice(&limits.to_bytes());
+            if config()
+                .await
+                .set_bytes(Key::RelaisLimits, &stored)
+                .await
+                .is_ok()
+            {
+                RELAIS_CHANNEL.send(Command::Limits { num, limits }).await;
+            }
+        }
+        Ok(_) => {}
+        Err(_) => report(RelaisErrorCode::InvalidLimits, data).await,
+    }
+}
+
+/// Schaltspiele und Einschaltdauer, ein RTR liefert alle Kanäle. Ein Frame
+/// setzt die Zähler eines Kanals, z. B. nach dem Tausch des Relais.
+pub async fn relais_stats_handler(id: CanId, data: &[u8], remote_request: bool) {
+    match CanMessage::decode(id, data, remote_request) {
+        Ok(CanMessage::Request(_)) => RELAIS_CHANNEL.send(Command::Stats).await,
+        Ok(CanMessage::RelaisStats { num, bank, stats }) => {
+            let mode = LAYOUT.get().await.mode;
+            if let Some(num) = channel(mode, bank, num as usize).await {
+                RELAIS_CHANNEL.send(Command::SetStats { num, stats }).await;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Schaltspiele bis zur Verschleißwarnung, gilt sofort für alle Kanäle
+pub async fn relais_cycle_limit_handler(id: CanId, data: &[u8], remote_request: bool) {
+    match CanMessage::decode(id, data, remote_request) {
+        Ok(CanMessage::Request(_)) => {
+            send_message(&CanMessage::RelaisCycleLimit(load_cycle_limit().await)).await
+        }
+        Ok(CanMessage::RelaisCycleLimit(cycles)) => {
+            if config()
+                .await
+                .set_u32(Key::CycleLimit, cycles)
+                .await
+                .is_ok()
+            {
+                RELAIS_CHANNEL.send(Command::CycleLimit(cycles)).await;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Kanalzuordnung über den segmentierten Transport. Ein RTR liefert die
+/// gespeicherte, eine geschriebene gilt nach dem nächsten Start.
+pub async fn relais_map_handler(_id: CanId, data: &[u8], remote_request: bool) {
+    if remote_request {
+        let map = load_map().await;
+        transport::send(CanMessageType::RelaisMap, &map.to_bytes()).await;
+        return;
+    }
+    let mut rx = MAP_RX.lock().await;
+    match rx.on_frame(data, Instant::now()) {
+        Ok(RxStatus::Pending) => {}
+        Ok(RxStatus::SendFlowControl(fc)) | Ok(RxStatus::Rejected(fc)) => {
+            send_can_message(CanMessageType::RelaisMap, &fc, false).await;
+        }
+        Ok(RxStatus::Complete) => {
+            let Some(raw) = rx.data() else {
+                return;
+            };
+            if RelaisMap::from_bytes(raw).is_err() {
+                report(RelaisErrorCode::InvalidMap, &[]).await;
+                return;
+            }
+            let _ = config().await.set_bytes(Key::RelaisMap, raw).await;
+        }
+        Err(e) => transport::report(CanMessageType::RelaisMap, e).await,
+    }
+}
+
+/// Speichert nur bekannte Verdrahtungen, das Gerät startet danach neu
+pub async fn relais_mode_handler(id: CanId, data: &[u8], remote_request: bool) {
+    let known = data
+        .first()
+        .is_none_or(|&v| RelaisMode::try_from(v).is_ok());
+    if !remote_request && !known {
+        report(RelaisErrorCode::InvalidMode, data).await;
+        return;
+    }
+    let _ = device()
+        .await
+        .u8_val(id, data, remote_request, Key::RelaisMode)
+        .await;
+}
+
 pub struct Relais {
     i2c: I2c<'static, Async>,
-    expanders: [u8; 2],
+    layout: &'static Layout,
+    /// Je Index der `RelaisMap`, `None` wenn nicht bestückt
+    expanders: [Option<Expander>; MAX_EXPANDERS],
+    /// Zuletzt vollständig geschriebener Zustand je Kanal
+    states: [RelaisState; MAX_RELAIS],
+    /// Schreiben ist fehlgeschlagen, die Ausgänge des Kanals sind unbekannt
+    faulted: [bool; MAX_RELAIS],
+    /// Speichern von `states` für `PowerOn::Last`
+    last_state: WriteCoalescer,
 }
 
 impl Relais {
-    pub fn init(
+    pub async fn init(
         i2c0: esp_hal::peripherals::I2C0<'static>,
         sda: impl PeripheralOutput<'static>,
         scl: impl PeripheralOutput<'static>,
         spawner: &Spawner,
     ) {
-        let mut i2c = I2c::new(i2c0, Config::default())
+        let mut i2c = I2c::new(i2c0, esp_hal::i2c::master::Config::default())
             .unwrap()
             .with_sda(sda)
             .with_scl(scl)
             .into_async();
 
-        i2c.write(BANK[0], &[0x3, 0x0]).ok();
-        i2c.write(BANK[1], &[0x3, 0x0]).ok();
-        i2c.write(BANK[0], &[0x1, 0x0]).ok();
-        i2c.write(BANK[1], &[0x1, 0x0]).ok();
+        let layout = Layout {
+            map: load_map().await,
+            mode: load_mode().await,
+        };
+        let mut expanders = [None; MAX_EXPANDERS];
+        for (index, address) in layout.map.fitted() {
+            let mut expander = Expander::new(address);
+            // ein fehlender Expander wird beim nächsten Schalten erneut versucht
+            if let Err(e) = expander.init(&mut i2c).await {
+                fault(address, e).await;
+            }
+            expanders[index] = Some(expander);
+        }
+        let _ = LAYOUT.init(layout);
 
-        let relais = Relais {
-            expanders: [0, 0],
+        let mut relais = Relais {
             i2c,
+            layout: LAYOUT.get().await,
+            expanders,
+            states: [RelaisState::Off; MAX_RELAIS],
+            faulted: [false; MAX_RELAIS],
+            last_state: WriteCoalescer::new(LAST_STATE_INTERVAL),
         };
+        relais.restore().await;
 
         spawner.spawn(relais_task(relais)).unwrap();
     }
-    /// Each entry: (expander index, bit position)
-    const MAPPING: [(usize, u8); MAX_RELAIS] = [
-        (0, 3),
-        (0, 2),
-        (0, 1),
-        (0, 7),
-        (0, 6),
-        (0, 5),
-        (0, 4),
-        (1, 11 - 8),
-        (1, 10 - 8),
-        (1, 9 - 8),
-        (1, 15 - 8),
-        (1, 14 - 8),
-        (0, 0),
-        (0, 0),
-        (0, 0),
-        (0, 0),
-    ];
-
-    pub fn set(&mut self, num: usize, state: RelaisState) {
-        match RELAIS_MODE {
-            RelaisMode::Relais => self.sethw(num, state),
-            RelaisMode::SoftwareRollershutter => match state {
-                RelaisState::Up => {
-                    self.sethw(num * 2, RelaisState::On);
-                    self.sethw(num * 2 + 1, RelaisState::Off);
-                }
-                RelaisState::Down => {
-                    self.sethw(num * 2, RelaisState::On);
-                    self.sethw(num * 2 + 1, RelaisState::Off);
-                }
-                _ => {
-                    self.sethw(num * 2, RelaisState::Off);
-                    self.sethw(num * 2 + 1, RelaisState::Off);
-                }
-            },
-            RelaisMode::HardwareRollershutter => match state {
-                RelaisState::Up => {
-                    self.sethw(num * 2, RelaisState::On);
-                    self.sethw(num * 2 + 1, RelaisState::Off);
-                }
-                RelaisState::Down => {
-                    self.sethw(num * 2, RelaisState::On);
-                    self.sethw(num * 2 + 1, RelaisState::On);
-                }
-                _ => {
-                    self.sethw(num * 2, RelaisState::Off);
-                    self.sethw(num * 2 + 1, RelaisState::Off);
-                }
-            },
+
+    /// Schaltet die Relais wie nach dem Einschalten eingestellt. Motoren von
+    /// Rollläden bleiben aus.
+    async fn restore(&mut self) {
+        if self.layout.mode != RelaisMode::Relais {
+            return;
+        }
+        let power_on = load_power_on().await;
+        let last = load_last_states().await;
+        for num in 0..self.layout.map.channels(self.layout.mode) {
+            let state = power_on[num].state(last[num]);
+            if state != RelaisState::Off {
+                // nach einem Fehler bleibt `states` aus, so startet
+                // `relais_task` für den Kanal keine Zeitsteuerung
+                let _ = self.set(num, state).await;
+            }
+        }
+    }
+
+    /// Speichert die Zustände, wenn `LAST_STATE_INTERVAL` um ist
+    async fn persist(&mut self, now: Instant) {
+        if !self.last_state.poll(now) {
+            return;
+        }
+        let states = self.states.map(|state| state as u8);
+        if config()
+            .await
+            .set_bytes(Key::LastState, &states)
+            .await
+            .is_err()
+        {
+            self.last_state.changed();
+        }
+    }
+
+    /// Schreibt die Ausgänge in der Reihenfolge aus `RelaisMode::outputs`,
+    /// so sind beide Richtungen eines Motors nie gleichzeitig an. Nach einem
+    /// Fehler bleiben die weiteren Ausgänge unverändert und der Kanal gilt
+    /// als gestört, vor dem nächsten Schalten gehen dann erst alle seine
+    /// Ausgänge aus.
+    pub async fn set(&mut self, num: usize, state: RelaisState) -> Result<(), ExpanderError> {
+        if num >= MAX_RELAIS {
+            return Ok(());
+        }
+        if self.faulted[num] {
+            self.write(num, self.states[num], RelaisState::Off).await?;
+            self.faulted[num] = false;
+            self.update(num, RelaisState::Off);
+        }
+        self.write(num, self.states[num], state).await?;
+        self.update(num, state);
+        Ok(())
+    }
+
+    async fn write(
+        &mut self,
+        num: usize,
+        from: RelaisState,
+        to: RelaisState,
+    ) -> Result<(), ExpanderError> {
+        let Layout { map, mode } = self.layout;
+        for (output, on) in map.outputs(*mode, num, from, to) {
+            if let Err(e) = self.sethw(output, on).await {
+                self.faulted[num] = true;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Übernimmt einen vollständig geschriebenen Zustand
+    fn update(&mut self, num: usize, state: RelaisState) {
+        let from = core::mem::replace(&mut self.states[num], state);
+        if self.layout.mode == RelaisMode::Relais && from != state {
+            self.last_state.changed();
         }
     }
 
-    fn sethw(&mut self, num: usize, state: RelaisState) {
-        if let Some(&(expander, bit)) = Self::MAPPING.get(num) {
-            println!("expander {expander}, bit {bit}");
-            let mask = 1 << bit;
-            if state == RelaisState::On {
-                self.expanders[expander] |= mask;
-            } else {
-                self.expanders[expander] &= !mask;
+    async fn sethw(&mut self, output: Output, on: bool) -> Result<(), ExpanderError> {
+        let Output { expander, bit } = output;
+        let Some(driver) = self.expanders[expander as usize].as_mut() else {
+            return Ok(());
+        };
+        println!("expander {expander}, bit {bit}");
+        let address = driver.address();
+        match driver.set(&mut self.i2c, bit, on).await {
+            Ok(reset) => {
+                if reset {
+                    report_reset(address).await;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                fault(address, e).await;
+                Err(e)
             }
-            println!(
-                "i2c write to {:#x?}: {:#x?}",
-                BANK[expander], self.expanders[expander]
-            );
-            self.i2c
-                .write(BANK[expander], &[0x1, self.expanders[expander]])
-                .ok();
         }
     }
 }
 
+/// Verdrahtung aus dem Config, wird erst nach einem Neustart übernommen
+async fn load_mode() -> RelaisMode {
+    let value = config().await.get_u8(Key::RelaisMode).await;
+    let Some(value) = value else {
+        return RelaisMode::default();
+    };
+    match RelaisMode::try_from(value) {
+        Ok(mode) => mode,
+        Err(()) => {
+            report(RelaisErrorCode::InvalidMode, &[value]).await;
+            RelaisMode::default()
+        }
+    }
+}
+
+/// Kanalzuordnung aus dem Config, ohne gespeicherte die der
+/// Hardware-Revision
+async fn load_map() -> RelaisMap {
+    let mut config = config().await;
+    let stored = config.get_bytes::<{ RelaisMap::LEN }>(Key::RelaisMap).await;
+    let hw_rev = config.get_u8(Key::HardwareRevision).await.unwrap_or(0);
+    drop(config);
+    match stored.map(|raw| RelaisMap::from_bytes(&raw)) {
+        Some(Ok(map)) => map,
+        Some(Err(_)) => {
+            report(RelaisErrorCode::InvalidMap, &[hw_rev]).await;
+            RelaisMap::for_hw_rev(hw_rev)
+        }
+        None => RelaisMap::for_hw_rev(hw_rev),
+    }
+}
+
+/// Ohne Eintrag sind alle Relais nach dem Einschalten aus
+async fn load_power_on() -> [PowerOn; MAX_RELAIS] {
+    let stored = config().await.get_bytes::<MAX_RELAIS>(Key::PowerOn).await;
+    let stored = stored.unwrap_or([PowerOn::Off as u8; MAX_RELAIS]);
+    stored.map(|value| PowerOn::try_from(value).unwrap_or_default())
+}
+
+async fn load_last_states() -> [RelaisState; MAX_RELAIS] {
+    let stored = config().await.get_bytes::<MAX_RELAIS>(Key::LastState).await;
+    let stored = stored.unwrap_or([RelaisState::Off as u8; MAX_RELAIS]);
+    stored.map(|value| RelaisState::try_from(value).unwrap_or(RelaisState::Off))
+}
+
+/// Ohne Eintrag hat kein Kanal Grenzen
+async fn load_limits() -> [RelaisLimits; MAX_RELAIS] {
+    let stored = config()
+        .await
+        .get_bytes::<LIMITS_LEN>(Key::RelaisLimits)
+        .await;
+    let stored = stored.unwrap_or([0; LIMITS_LEN]);
+    core::array::from_fn(|num| {
+        let at = num * RelaisLimits::LEN;
+        RelaisLimits::from_bytes(&stored[at..at + RelaisLimits::LEN]).unwrap_or_default()
+    })
+}
+
+/// Ohne Eintrag zählen alle Kanäle ab 0
+async fn load_stats() -> [RelaisStats; MAX_RELAIS] {
+    let mut config = config().await;
+    let cycles = config.get_bytes::<STATS_LEN>(Key::RelaisCycles).await;
+    let on_time = config.get_bytes::<STATS_LEN>(Key::RelaisOnTime).await;
+    let (cycles, on_time) = (
+        cycles.unwrap_or([0; STATS_LEN]),
+        on_time.unwrap_or([0; STATS_LEN]),
+    );
+    let u32_at = |raw: &[u8; STATS_LEN], num: usize| {
+        u32::from_le_bytes(raw[num * 4..num * 4 + 4].try_into().unwrap())
+    };
+    core::array::from_fn(|num| RelaisStats {
+        cycles: u32_at(&cycles, num),
+        on_time: Duration::from_secs(u32_at(&on_time, num) as u64),
+    })
+}
+
+async fn store_stats(manager: &RelayManager<MAX_RELAIS>, now: Instant) -> Result<(), ()> {
+    let mut cycles = [0; STATS_LEN];
+    let mut on_time = [0; STATS_LEN];
+    let raw = cycles.chunks_exact_mut(4).zip(on_time.chunks_exact_mut(4));
+    for (num, (cycles, on_time)) in raw.enumerate() {
+        let stats = manager.stats(num, now);
+        let secs = stats.on_time.as_secs().min(u32::MAX as u64) as u32;
+        cycles.copy_from_slice(&stats.cycles.to_le_bytes());
+        on_time.copy_from_slice(&secs.to_le_bytes());
+    }
+    let mut config = config().await;
+    config.set_bytes(Key::RelaisCycles, &cycles).await?;
+    config.set_bytes(Key::RelaisOnTime, &on_time).await
+}
+
+async fn load_cycle_limit() -> u32 {
+    let cycles = config().await.get_u32(Key::CycleLimit).await;
+    cycles.unwrap_or(DEFAULT_CYCLE_LIMIT)
+}
+
+async fn load_dead_time() -> Duration {
+    let ms = config().await.get_u32(Key::DeadTime).await;
+    ms.map_or(DEFAULT_DEAD_TIME, |ms| Duration::from_millis(ms as u64))
+}
+
+/// Fahr- und Wendezeiten aller Rollläden, 0 wenn nie eingemessen
+async fn load_timings() -> [ShutterTiming; MAX_SHUTTERS] {
+    let mut config = config().await;
+    let travel = config.get_bytes::<TIMING_LEN>(Key::ShutterTiming).await;
+    let travel = travel.unwrap_or([0; TIMING_LEN]);
+    let slats = config.get_bytes::<SLAT_LEN>(Key::SlatTiming).await;
+    let slats = slats.unwrap_or([0; SLAT_LEN]);
+    core::array::from_fn(|num| ShutterTiming {
+        up: millis(&travel, num * 8),
+        down: millis(&travel, num * 8 + 4),
+        turn: millis(&slats, num * 4),
+    })
+}
+
+fn millis(raw: &[u8], at: usize) -> Duration {
+    let ms = u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]]);
+    Duration::from_millis(ms as u64)
+}
+
+async fn store_timing(num: usize, timing: ShutterTiming) -> Result<(), ()> {
+    let mut config = config().await;
+    let travel = config.get_bytes::<TIMING_LEN>(Key::ShutterTiming).await;
+    let mut travel = travel.unwrap_or([0; TIMING_LEN]);
+    let up = timing.up.as_millis() as u32;
+    let down = timing.down.as_millis() as u32;
+    travel[num * 8..num * 8 + 4].copy_from_slice(&up.to_le_bytes());
+    travel[num * 8 + 4..num * 8 + 8].copy_from_slice(&down.to_le_bytes());
+    config.set_bytes(Key::ShutterTiming, &travel).await?;
+
+    let slats = config.get_bytes::<SLAT_LEN>(Key::SlatTiming).await;
+    let mut slats = slats.unwrap_or([0; SLAT_LEN]);
+    let turn = timing.turn.as_millis() as u32;
+    slats[num * 4..num * 4 + 4].copy_from_slice(&turn.to_le_bytes());
+    config.set_bytes(Key::SlatTiming, &slats).await
+}
+
+/// Meldet Zustand und Restzeit eines Kanals, bei Rollläden mit geschätzter
+/// Position und Neigung
+async fn send_state(
+    manager: &RelayManager<MAX_RELAIS>,
+    layout: &Layout,
+    channel: usize,
+    now: Instant,
+) {
+    let (state, remaining) = manager.status(channel, now);
+    let (bank, num) = layout.map.split(layout.mode, channel);
+    let msg = if layout.mode == RelaisMode::Relais {
+        CanMessage::RelaisState(RelaisStatus {
+            num: num as u8,
+            state,
+            remaining,
+            bank,
+        })
+    } else {
+        CanMessage::RollershutterState(ShutterStatus {
+            num: num as u8,
+            state,
+            position: manager.position(channel, now),
+            bank,
+            tilt: manager.tilt(channel, now),
+            remaining,
+        })
+    };
+    send_message(&msg).await;
+}
+
+async fn report(code: RelaisErrorCode, details: &[u8]) {
+    send_error_report(
+        Component::Relais,
+        ErrorCode::InvalidData,
+        Severity::Warning,
+        code as u8,
+        details,
+    )
+    .await;
+}
+
+/// Expander fehlt, der Bus hängt oder ein Register liest sich falsch zurück
+/// Details: Adresse, Register + 1 oder 0 bei Busfehler, geschrieben, gelesen
+async fn fault(address: u8, error: ExpanderError) {
+    let details = match error {
+        ExpanderError::Bus => [address, 0, 0, 0],
+        ExpanderError::Mismatch {
+            register,
+            written,
+            read,
+        } => [address, register as u8 + 1, written, read],
+    };
+    send_error_report(
+        Component::Relais,
+        ErrorCode::Unknown,
+        Severity::RecoverableError,
+        RelaisErrorCode::ExpanderFault as u8,
+        &details,
+    )
+    .await;
+}
+
+/// Kanäle, die eine Schutzgrenze erreicht haben. Details: Kanal, Bank
+async fn report_violations(manager: &mut RelayManager<MAX_RELAIS>, layout: &Layout) {
+    for (channel, violation) in manager.take_violations() {
+        let code = match violation {
+            Violation::MaxOnTime => RelaisErrorCode::MaxOnTime,
+            Violation::MinOffTime => RelaisErrorCode::MinOffTime,
+            Violation::CycleLimit => RelaisErrorCode::CycleLimit,
+        };
+        let (bank, num) = layout.map.split(layout.mode, channel);
+        send_error_report(
+            Component::Relais,
+            ErrorCode::Unknown,
+            Severity::Warning,
+            code as u8,
+            &[num as u8, bank],
+        )
+        .await;
+    }
+}
+
+/// Expander hatte nach einem Reset seine Konfiguration verloren
+async fn report_reset(address: u8) {
+    send_error_report(
+        Component::Relais,
+        ErrorCode::Unknown,
+        Severity::Warning,
+        RelaisErrorCode::ExpanderReset as u8,
+        &[address],
+    )
+    .await;
+}
+
+/// Nach einem Fehler beim Schalten läuft für den Kanal keine Zeitsteuerung
+/// weiter, er wird als aus gemeldet
+fn stop(manager: &mut RelayManager<MAX_RELAIS>, num: usize, now: Instant) {
+    manager.apply_command(num, RelaisState::Off, Duration::from_millis(0), now);
+}
+
 #[embassy_executor::task]
 async fn relais_task(mut relais: Relais) {
+    let layout = relais.layout;
     let mut manager: RelayManager<MAX_RELAIS> = RelayManager::new();
+    let shutters = layout.mode != RelaisMode::Relais;
+    if shutters {
+        manager.set_dead_time(load_dead_time().await);
+        let channels = layout.map.channels(layout.mode);
+        for (num, timing) in load_timings().await.into_iter().enumerate().take(channels) {
+            if timing.is_calibrated() {
+                manager.set_timing(num, timing, Instant::now());
+            }
+        }
+    }
+    let channels = layout.map.channels(layout.mode);
+    for (num, limits) in load_limits().await.into_iter().enumerate().take(channels) {
+        manager.set_limits(num, limits);
+    }
+    for (num, stats) in load_stats().await.into_iter().enumerate().take(channels) {
+        manager.set_stats(num, stats);
+    }
+    manager.set_cycle_limit(load_cycle_limit().await);
+    let mut stats_store = WriteCoalescer::new(STATS_INTERVAL);
+    // von `Relais::restore` schon geschaltet
+    for (num, &state) in relais.states.iter().enumerate() {
+        if state != RelaisState::Off {
+            manager.apply_command(num, state, Duration::from_millis(0), Instant::now());
+        }
+    }
 
     loop {
         let now = Instant::now();
 
-        // 1. Abgelaufene Zeitsteuerungen
+        // 1. Abgelaufene Zeitsteuerungen, Rollläden fahren evtl. weiter
         for (num, state) in manager.poll_expired(now).into_iter() {
-            relais.set(num as usize, state);
+            if relais.set(num, state).await.is_err() {
+                stop(&mut manager, num, now);
+            }
+            stats_store.changed();
+            send_state(&manager, layout, num, now).await;
+        }
+        report_violations(&mut manager, layout).await;
+
+        relais.persist(now).await;
+        if stats_store.poll(now) && store_stats(&manager, now).await.is_err() {
+            stats_store.changed();
         }
 
         // 2. Warte auf nächsten Befehl oder nächstes Timeout
         let recv = RELAIS_CHANNEL.receive();
-        let delay = Timer::after(manager.next_timeout(now));
+        let persist = [relais.last_state.due(), stats_store.due()]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|due| due.saturating_duration_since(now));
+        let timeout = manager
+            .next_timeout(now)
+            .min(persist.unwrap_or(Duration::MAX));
+        let delay = Timer::after(timeout);
 
         match select(recv, delay).await {
-            Either::First(msg) => {
+            Either::First(command) => {
                 println!("relais future met");
-                let changed =
-                    manager.apply_command(msg.num, msg.state, msg.duration, Instant::now());
-                if changed {
-                    relais.set(msg.num, msg.state);
+                // Kanäle sind schon im Handler geprüft
+                let num = command.num();
+                let now = Instant::now();
+                let changed = match command {
+                    Command::Switch(msg) => {
+                        manager.apply_command(msg.num, msg.state, msg.duration, now)
+                    }
+                    Command::Position { num, percent } => manager.move_to(num, percent, now),
+                    Command::Tilt { num, percent } => manager.tilt_to(num, percent, now),
+                    Command::Timing { num, timing } if shutters => {
+                        manager.set_timing(num, timing, now);
+                        None
+                    }
+                    Command::DeadTime(dead_time) if shutters => {
+                        manager.set_dead_time(dead_time);
+                        None
+                    }
+                    Command::Timing { .. } | Command::DeadTime(_) => None,
+                    Command::Limits { num, limits } => {
+                        manager.set_limits(num, limits);
+                        None
+                    }
+                    Command::Stats => {
+                        for channel in 0..layout.map.channels(layout.mode) {
+                            let (bank, num) = layout.map.split(layout.mode, channel);
+                            send_message(&CanMessage::RelaisStats {
+                                num: num as u8,
+                                bank,
+                                stats: manager.stats(channel, now),
+                            })
+                            .await;
+                        }
+                        None
+                    }
+                    Command::SetStats { num, stats } => {
+                        manager.set_stats(num, stats);
+                        stats_store.changed();
+                        None
+                    }
+                    Command::CycleLimit(cycles) => {
+                        manager.set_cycle_limit(cycles);
+                        None
+                    }
+                    Command::Report => {
+                        for num in 0..layout.map.channels(layout.mode) {
+                            send_state(&manager, layout, num, now).await;
+                        }
+                        None
+                    }
+                };
+                if let (Some(num), Some(state)) = (num, changed) {
+                    if relais.set(num, state).await.is_err() {
+                        stop(&mut manager, num, now);
+                    }
+                    stats_store.changed();
                     println!("set relais");
+                    send_state(&manager, layout, num, now).await;
                 }
+                report_violations(&mut manager, layout).await;
             }
             Either::Second(_) => {}
         }
@@ -0,0 +1,88 @@
+use crate::can::send_can_message;
+use crate::error::{send_error_report, Component, ErrorCode, Severity};
+use cancomponents_core::can_message::Payload;
+use cancomponents_core::can_message_type::CanMessageType;
+use cancomponents_core::transport::{Sender, TransportError};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Instant, Timer};
+use esp_println::println;
+use heapless::Vec;
+
+/// Largest payload the device sends in one segmented transfer
+pub const MAX_TRANSFER: usize = 256;
+
+static TRANSFERS: Channel<CriticalSectionRawMutex, (CanMessageType, Vec<u8, MAX_TRANSFER>), 2> =
+    Channel::new();
+static FLOW_CONTROL: Channel<CriticalSectionRawMutex, (CanMessageType, Payload), 2> =
+    Channel::new();
+
+pub fn init(spawner: &Spawner) {
+    spawner.spawn(transport_task()).unwrap();
+}
+
+/// Queues a segmented transfer, longer data is truncated to `MAX_TRANSFER`
+pub async fn send(msg_type: CanMessageType, data: &[u8]) {
+    let len = data.len().min(MAX_TRANSFER);
+    let data = Vec::from_slice(&data[..len]).unwrap();
+    TRANSFERS.send((msg_type, data)).await;
+}
+
+/// Hands a received flow control frame to the running transfer
+pub fn flow_control(msg_type: CanMessageType, data: &[u8]) {
+    if let Ok(frame) = Payload::from_slice(data) {
+        // a full queue means nobody is waiting for it
+        let _ = FLOW_CONTROL.try_send((msg_type, frame));
+    }
+}
+
+pub async fn report(msg_type: CanMessageType, error: TransportError) {
+    send_error_report(
+        Component::Can,
+        ErrorCode::InvalidData,
+        Severity::Warning,
+        error as u8,
+        &[u8::from(msg_type)],
+    )
+    .await;
+}
+
+#[embassy_executor::task]
+async fn transport_task() {
+    println!("transport_task started");
+    loop {
+        let (msg_type, data) = TRANSFERS.receive().await;
+        // drop flow control left over from an aborted transfer
+        while FLOW_CONTROL.try_receive().is_ok() {}
+
+        let mut sender = match Sender::<MAX_TRANSFER>::new(&data) {
+            Ok(sender) => sender,
+            Err(e) => {
+                report(msg_type, e).await;
+                continue;
+            }
+        };
+
+        while !sender.is_done() {
+            match sender.poll(Instant::now()) {
+                Ok(Some(frame)) => send_can_message(msg_type, &frame, false).await,
+                Ok(None) => {
+                    let Some(deadline) = sender.next_deadline() else {
+                        break;
+                    };
+                    match select(FLOW_CONTROL.receive(), Timer::at(deadline)).await {
+                        Either::First((fc_type, frame)) if fc_type == msg_type => {
+                            if let Err(e) = sender.on_flow_control(&frame, Instant::now()) {
+                                report(msg_type, e).await;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e) => report(msg_type, e).await,
+            }
+        }
+    }
+}
@@ -1,40 +1,63 @@
+use crate::can::{send_message, ANNOUNCED, DEVICE_TYPE};
+use crate::config::{config, Key};
+use crate::error::{send_error_report, Component, ErrorCode, Severity};
 use cancomponents_core::can_id::CanId;
-use crate::error::{Component, ErrorCode, ErrorReport, Severity};
+use cancomponents_core::can_message::CanMessage;
+use cancomponents_core::chunk_map::{ChunkMap, WINDOW};
+use cancomponents_core::compression::{Decoder, BLOCK_LEN};
+use cancomponents_core::crc32;
+use cancomponents_core::image_header::{HeaderError, ImageHeader, HEADER_LEN};
+use cancomponents_core::signature::{Verifier, TRAILER_LEN};
+pub use cancomponents_core::update::UpdateErrorCode;
+use cancomponents_core::update::{
+    BootState, ACK_INTERVAL, CHUNK_LEN, MAX_BOOT_ATTEMPTS, MAX_GAP_REPORTS, MAX_READ_LEN,
+    MULTICAST_CHUNK_LEN, RESUME_INTERVAL, SLOT_COUNT,
+};
+use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+use embassy_futures::yield_now;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
-use esp_hal_ota::Ota;
+use embassy_time::{Duration, Timer};
+use embedded_storage::nor_flash::NorFlash;
+use embedded_storage::ReadStorage;
+use esp_hal_ota::{Ota, OtaImgState};
 use esp_storage::FlashStorage;
 
+// UPDATE_KEY, öffentlicher Schlüssel für signierte Images, siehe build.rs
+include!(concat!(env!("OUT_DIR"), "/update_key.rs"));
+
 static UPDATE: Mutex<CriticalSectionRawMutex, Option<Update>> = Mutex::new(None);
+/// Empfangene Chunks im Multicast, zu groß für den Stack und damit für `Update`
+static RECEIVED: Mutex<CriticalSectionRawMutex, ChunkMap<MAP_WORDS>> = Mutex::new(ChunkMap::new());
+/// Frame eines komprimierten Updates, siehe `FlashCompressed`
+static DECODER: Mutex<CriticalSectionRawMutex, Decoder> = Mutex::new(Decoder::new());
 
-#[repr(u8)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum UpdateErrorCode {
-    Unknown = 0,
-    InvalidData = 1,
-    Begin = 2,
-    Init = 3,
-    Write = 4,
-    NotStarted = 5,
-}
+/// Start der OTA-Slots, siehe partitions.csv
+const SLOT_OFFSETS: [u32; SLOT_COUNT as usize] = [0x200000, 0x300000];
+const SLOT_SIZE: u32 = 0x100000;
+const SECTOR_SIZE: u32 = 4096;
+/// Blockgröße beim Zurücklesen für die CRC
+const VERIFY_READ_SIZE: usize = 256;
+/// Ein ganzer Slot plus Header in `FlashChunk`s
+const MAP_WORDS: usize =
+    (SLOT_SIZE as usize + HEADER_LEN).div_ceil(MULTICAST_CHUNK_LEN * WINDOW as usize);
+/// Ohne quittiertes `Available` bis dahin gilt der Start als fehlgeschlagen
+const BOOT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
 
-impl From<u8> for UpdateErrorCode {
-    fn from(value: u8) -> Self {
-        match value {
-            1 => UpdateErrorCode::InvalidData,
-            2 => UpdateErrorCode::Begin,
-            3 => UpdateErrorCode::Init,
-            4 => UpdateErrorCode::Write,
-            5 => UpdateErrorCode::NotStarted,
-            _ => UpdateErrorCode::Unknown,
-        }
-    }
-}
 pub async fn init() {
     let mut update_guard = UPDATE.lock().await;
 
     if update_guard.is_none() {
-        let update = Update { ota: None };
+        let update = Update {
+            ota: Ota::new(FlashStorage::new()).ok(),
+            flash: FlashStorage::new(),
+            selected: None,
+            compression: None,
+            image: None,
+            boot: None,
+            boot_reported: false,
+        };
         *update_guard = Some(update);
     }
 }
@@ -45,100 +68,870 @@ pub async fn update(
     embassy_sync::mutex::MutexGuard::map(guard, |opt| opt.as_mut().expect("Update not initialized"))
 }
 
+/// Zählt die Starts eines neuen Images und kehrt nach [`MAX_BOOT_ATTEMPTS`]
+/// Starts ohne Bestätigung zum vorherigen Slot zurück. Vor `can::init` aufrufen.
+pub async fn boot() {
+    let mut update = update().await;
+    let saved = config().await.get_bytes(Key::BootRecord).await;
+    let Some(mut record) = saved.map(BootRecord::from_bytes) else {
+        return;
+    };
+    match record.state {
+        BootState::Valid | BootState::Confirmed => return,
+        BootState::RolledBack => {}
+        BootState::Pending if update.running_slot() != Some(record.slot) => {
+            // Bootloader hat das neue Image nicht gestartet
+            record.state = BootState::RolledBack;
+            update.save_boot(&record).await;
+        }
+        BootState::Pending if record.attempts >= MAX_BOOT_ATTEMPTS => {
+            record.state = BootState::RolledBack;
+            update.save_boot(&record).await;
+            update.rollback(&record);
+            esp_hal::system::software_reset();
+        }
+        BootState::Pending => {
+            record.attempts += 1;
+            update.save_boot(&record).await;
+        }
+    }
+    update.boot = Some(record);
+}
+
+/// Bestätigt das Image, sobald das `Available` auf dem Bus angekommen ist
+pub fn supervise_boot(spawner: &Spawner) {
+    spawner.spawn(boot_task()).unwrap();
+}
+
+#[embassy_executor::task]
+async fn boot_task() {
+    let announced = select(ANNOUNCED.wait(), Timer::after(BOOT_CONFIRM_TIMEOUT)).await;
+    let mut update = update().await;
+    match announced {
+        Either::First(()) => update.confirm().await,
+        Either::Second(()) => {
+            let pending = update
+                .boot
+                .as_ref()
+                .is_some_and(|record| record.state == BootState::Pending);
+            if pending {
+                // zählt als fehlgeschlagener Start
+                esp_hal::system::software_reset();
+            }
+        }
+    }
+}
+
 pub struct Update {
+    /// Nur für Partitionsinfo und Bootauswahl, die Daten schreiben wir selbst
     ota: Option<Ota<FlashStorage>>,
+    flash: FlashStorage,
+    selected: Option<u8>,
+    /// Größe und CRC des ausgepackten Images für das nächste `FlashStart`
+    compression: Option<(u32, u32)>,
+    image: Option<Image>,
+    /// Ergebnis des letzten Updates, bis zum nächsten Neustart
+    boot: Option<BootRecord>,
+    boot_reported: bool,
+}
+
+/// Start eines neuen Images, liegt bis zur Meldung im Config
+#[derive(Clone, Copy)]
+struct BootRecord {
+    slot: u8,
+    /// `None` für die factory-Partition
+    previous: Option<u8>,
+    state: BootState,
+    attempts: u8,
+}
+
+impl BootRecord {
+    fn to_bytes(&self) -> [u8; 4] {
+        [
+            self.slot,
+            self.previous.unwrap_or(0xFF),
+            self.state as u8,
+            self.attempts,
+        ]
+    }
+
+    fn from_bytes(buf: [u8; 4]) -> Self {
+        Self {
+            slot: buf[0],
+            previous: (buf[1] != 0xFF).then_some(buf[1]),
+            state: BootState::try_from(buf[2]).unwrap_or(BootState::Valid),
+            attempts: buf[3],
+        }
+    }
+}
+
+/// Gespeicherte Länge eines [`Image`]
+const IMAGE_RECORD_LEN: usize = 13 + HEADER_LEN + 20;
+
+/// Laufendes Update, wird für das Fortsetzen nach einem Abbruch gespeichert.
+/// Positionen zählen im Image inklusive Header, der Header selbst liegt nicht
+/// im Slot. Bei einem komprimierten Update beziehen sich `size`, `crc` und
+/// `written` auf den Stream.
+#[derive(Clone, Copy)]
+struct Image {
+    slot: u8,
+    size: u32,
+    crc: u32,
+    written: u32,
+    header: [u8; HEADER_LEN],
+    /// Nach `FlashJoin`, Chunks kommen per Broadcast in beliebiger Reihenfolge.
+    /// Wird nicht gespeichert, nach einem Neustart tritt der Host neu bei.
+    multicast: bool,
+    expanded: Option<Expanded>,
+}
+
+/// Ausgepacktes Image eines komprimierten Updates
+#[derive(Clone, Copy)]
+struct Expanded {
+    size: u32,
+    crc: u32,
+    /// Ausgepackte Bytes inklusive Header, immer ganze Frames
+    written: u32,
+    /// Stream bis zum Ende des letzten vollständigen Frames
+    stream: u32,
+    /// CRC des Streams bis `stream`
+    stream_crc: u32,
+}
+
+impl Image {
+    fn to_bytes(&self) -> [u8; IMAGE_RECORD_LEN] {
+        let mut buf = [0u8; IMAGE_RECORD_LEN];
+        buf[0] = self.slot;
+        buf[1..5].copy_from_slice(&self.size.to_le_bytes());
+        buf[5..9].copy_from_slice(&self.crc.to_le_bytes());
+        buf[9..13].copy_from_slice(&self.written.to_le_bytes());
+        buf[13..21].copy_from_slice(&self.header);
+        // Größe 0: nicht komprimiert
+        if let Some(e) = self.expanded {
+            buf[21..25].copy_from_slice(&e.size.to_le_bytes());
+            buf[25..29].copy_from_slice(&e.crc.to_le_bytes());
+            buf[29..33].copy_from_slice(&e.written.to_le_bytes());
+            buf[33..37].copy_from_slice(&e.stream.to_le_bytes());
+            buf[37..41].copy_from_slice(&e.stream_crc.to_le_bytes());
+        }
+        buf
+    }
+
+    fn from_bytes(buf: [u8; IMAGE_RECORD_LEN]) -> Self {
+        let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        Self {
+            slot: buf[0],
+            size: word(1),
+            crc: word(5),
+            written: word(9),
+            header: buf[13..21].try_into().unwrap(),
+            multicast: false,
+            expanded: (word(21) != 0).then(|| Expanded {
+                size: word(21),
+                crc: word(25),
+                written: word(29),
+                stream: word(33),
+                stream_crc: word(37),
+            }),
+        }
+    }
+
+    fn is_same(&self, other: &Image) -> bool {
+        let expanded = |image: &Image| image.expanded.map(|e| (e.size, e.crc));
+        self.slot == other.slot
+            && self.size == other.size
+            && self.crc == other.crc
+            && expanded(self) == expanded(other)
+    }
+
+    /// Stand, ab dem der Host nach einem Abbruch weiterschreibt
+    fn resume_point(mut self) -> Self {
+        self.multicast = false;
+        match self.expanded.as_mut() {
+            None => self.written = block_start(self.written, self.size),
+            // nur ganze Frames sind ausgepackt, `stream` wird mit ihnen gespeichert
+            Some(e) => self.written = e.stream,
+        }
+        self
+    }
 }
 
 impl Update {
-    pub async fn start(&mut self, id: CanId, data: &[u8], _remote_request: bool) {
-        if data.len() < 8 {
-            ErrorReport::send(
-                Component::Update,
-                ErrorCode::InvalidData,
-                Severity::Warning,
-                UpdateErrorCode::InvalidData as u8,
-                &[id.msg_type as u8, data.len() as u8, 0u8],
-            )
-            .await;
+    pub async fn start(&mut self, id: CanId, data: &[u8], remote_request: bool) {
+        let Ok(CanMessage::FlashStart { size, crc }) = CanMessage::decode(id, data, remote_request)
+        else {
+            invalid_data(id, data).await;
+            return;
+        };
+
+        let slot = self.target_slot();
+        // ohne Header und Signatur-Trailer wird das Image nie gültig
+        let overhead = (HEADER_LEN + TRAILER_LEN) as u32;
+        if size <= overhead
+            || size > SLOT_SIZE + HEADER_LEN as u32
+            || Some(slot) == self.running_slot()
+        {
+            self.image = None;
+            report(UpdateErrorCode::Begin, &[slot]).await;
             return;
         }
 
-        let size = u32::from_le_bytes(data[0..4].try_into().unwrap());
-        let crc = u32::from_le_bytes(data[4..8].try_into().unwrap());
-
-        match Ota::new(FlashStorage::new()) {
-            Ok(mut ota) => {
-                if ota.ota_begin(size, crc).is_ok() {
-                    self.ota = Some(ota);
-                } else {
-                    // ota_begin fehlgeschlagen
-                    ErrorReport::send(
-                        Component::Ota,
-                        ErrorCode::Unknown,
-                        Severity::RecoverableError,
-                        UpdateErrorCode::Begin as u8,
-                        &[0u8, 0u8, 0u8],
-                    )
-                    .await;
-                    self.ota = None;
-                }
-            }
-            Err(_) => {
-                ErrorReport::send(
-                    Component::Ota,
-                    ErrorCode::Unknown,
-                    Severity::RecoverableError,
-                    UpdateErrorCode::Init as u8,
-                    &[0u8, 0u8, 0u8],
-                )
-                .await;
-                // OTA-Initialisierung fehlgeschlagen
-                self.ota = None;
-            }
+        let image = Image {
+            slot,
+            size,
+            crc,
+            written: 0,
+            header: [0xFF; HEADER_LEN],
+            multicast: false,
+            expanded: self.compression.take().map(|(size, crc)| Expanded {
+                size,
+                crc,
+                written: 0,
+                stream: 0,
+                stream_crc: 0,
+            }),
+        };
+        // gleiches Image: ab dem gemeldeten Offset weiterschreiben
+        let image = self.resume(image).await;
+        if let Some(e) = image.expanded {
+            DECODER.lock().await.reset(e.stream);
+        }
+        self.image = Some(image);
+        if image.written == 0 {
+            self.save().await;
+        }
+        self.send_progress().await;
+    }
+
+    /// Kündigt für das nächste `FlashStart` einen komprimierten Stream an
+    pub async fn compressed(&mut self, id: CanId, data: &[u8], remote_request: bool) {
+        let Ok(CanMessage::FlashCompressed { size, crc }) =
+            CanMessage::decode(id, data, remote_request)
+        else {
+            invalid_data(id, data).await;
+            return;
+        };
+        let overhead = (HEADER_LEN + TRAILER_LEN) as u32;
+        if size <= overhead || size > SLOT_SIZE + HEADER_LEN as u32 {
+            self.compression = None;
+            report(UpdateErrorCode::Begin, &[]).await;
+            return;
         }
+        self.compression = Some((size, crc));
+        send_message(&CanMessage::FlashCompressed { size, crc }).await;
     }
 
-    pub async fn write(&mut self, _id: CanId, data: &[u8], _remote_request: bool) {
-        if let Some(ota) = self.ota.as_mut() {
-            match ota.ota_write_chunk(data) {
+    pub async fn write(&mut self, id: CanId, data: &[u8], _remote_request: bool) {
+        let Some(image) = self.image.as_mut() else {
+            // OTA nicht gestartet
+            report(UpdateErrorCode::NotStarted, &[]).await;
+            return;
+        };
+        // im Multicast zählt `written` nur die lückenlos empfangenen Chunks
+        if image.multicast {
+            invalid_data(id, data).await;
+            return;
+        }
+
+        let remaining = (image.size - image.written) as usize;
+        // nur der letzte Chunk darf kürzer sein, sonst passt die Ausrichtung nicht
+        if data.is_empty()
+            || data.len() > CHUNK_LEN
+            || (data.len() < CHUNK_LEN && data.len() < remaining)
+        {
+            invalid_data(id, data).await;
+            return;
+        }
+        let len = data.len().min(remaining);
+
+        if image.written == 0 {
+            // Header prüfen, bevor irgendetwas gelöscht wird
+            if let Err(e) = check_header(data).await {
+                self.image = None;
+                self.discard().await;
+                report(UpdateErrorCode::Incompatible, &[e as u8]).await;
+                return;
+            }
+            image.header.copy_from_slice(&data[..HEADER_LEN]);
+            if let Some(e) = image.expanded.as_mut() {
+                // der Header steht unkomprimiert am Anfang des Streams
+                e.written = HEADER_LEN as u32;
+                e.stream = HEADER_LEN as u32;
+                e.stream_crc = crc32::update(0, &image.header);
+                DECODER.lock().await.reset(e.stream);
+            }
+        } else if let Some(e) = image.expanded.as_mut() {
+            let mut decoder = DECODER.lock().await;
+            let result = match decoder.push(&data[..len]) {
+                Ok(false) => Ok(()),
                 Ok(true) => {
-                    // Letzter Chunk – flush und reboot
-                    if ota.ota_flush(true, true).is_ok() {
-                        esp_hal::system::software_reset();
+                    let stream_crc = crc32::update(e.stream_crc, decoder.frame());
+                    let block_len = ((e.size - e.written) as usize).min(BLOCK_LEN);
+                    // jeder Block füllt einen ganzen Sektor
+                    let addr = SLOT_OFFSETS[image.slot as usize] + e.written - HEADER_LEN as u32;
+                    let written = match decoder.decode(block_len) {
+                        Ok(block) if !block.is_empty() => write_block(&mut self.flash, addr, block),
+                        _ => Err(UpdateErrorCode::Compression),
+                    };
+                    if written.is_ok() {
+                        e.written += block_len as u32;
+                        e.stream = image.written + len as u32;
+                        e.stream_crc = stream_crc;
                     }
+                    written
                 }
-                Ok(false) => {
-                    // Weiter schreiben
+                Err(_) => Err(UpdateErrorCode::Compression),
+            };
+            if let Err(code) = result {
+                drop(decoder);
+                self.image = None;
+                report(code, &[]).await;
+                return;
+            }
+        } else {
+            let offset = image.written - HEADER_LEN as u32;
+            let addr = SLOT_OFFSETS[image.slot as usize] + offset;
+
+            // Sektor vor dem ersten Schreiben löschen
+            if offset % SECTOR_SIZE == 0 && self.flash.erase(addr, addr + SECTOR_SIZE).is_err() {
+                self.image = None;
+                report(UpdateErrorCode::Erase, &[]).await;
+                return;
+            }
+
+            // NorFlash schreibt nur ganze Worte
+            let mut buf = [0xFFu8; CHUNK_LEN];
+            buf[..len].copy_from_slice(&data[..len]);
+            let padded = (len + 3) & !3;
+            if self.flash.write(addr, &buf[..padded]).is_err() {
+                // Fehler beim Schreiben
+                self.image = None;
+                report(UpdateErrorCode::Write, &[]).await;
+                return;
+            }
+        }
+
+        // Host wartet blockweise auf Bestätigung
+        let before = image.written;
+        image.written += len as u32;
+        let written = image.written;
+        let done = written == image.size;
+        // Sektor fertig, bis hierhin muss nach einem Abbruch nicht neu geschrieben werden
+        let sector_done = match image.expanded {
+            Some(e) => e.stream == written,
+            None => before / RESUME_INTERVAL != written / RESUME_INTERVAL,
+        };
+        if sector_done || done {
+            self.save().await;
+        }
+        if before / ACK_INTERVAL != written / ACK_INTERVAL || done {
+            self.send_progress().await;
+        }
+    }
+
+    /// Wechselt das gestartete Update in den Multicast. Der Header muss schon
+    /// per `FlashWrite` gekommen sein, er wird vor dem Löschen geprüft.
+    pub async fn join(&mut self, id: CanId, data: &[u8], remote_request: bool) {
+        if remote_request {
+            invalid_data(id, data).await;
+            return;
+        }
+        let Some(image) = self.image.as_mut() else {
+            report(UpdateErrorCode::NotStarted, &[]).await;
+            return;
+        };
+        if image.written < HEADER_LEN as u32 {
+            report(UpdateErrorCode::NotStarted, &[]).await;
+            return;
+        }
+        // Frames lassen sich nur der Reihe nach auspacken
+        if image.expanded.is_some() {
+            report(UpdateErrorCode::Compression, &[]).await;
+            return;
+        }
+
+        // passt immer, `start` begrenzt die Größe auf einen Slot
+        let chunk_len = MULTICAST_CHUNK_LEN as u32;
+        let chunks = image.size.div_ceil(chunk_len);
+        RECEIVED
+            .lock()
+            .await
+            .reset(chunks, image.written / chunk_len);
+
+        // Rest des Slots löschen, ein angefangener Sektor behält seinen Inhalt
+        let start = SLOT_OFFSETS[image.slot as usize];
+        let first = (image.written - HEADER_LEN as u32).next_multiple_of(SECTOR_SIZE);
+        let end = (image.size - HEADER_LEN as u32).next_multiple_of(SECTOR_SIZE);
+        for sector in (start + first..start + end).step_by(SECTOR_SIZE as usize) {
+            if self.flash.erase(sector, sector + SECTOR_SIZE).is_err() {
+                let slot = image.slot;
+                self.image = None;
+                report(UpdateErrorCode::Erase, &[slot]).await;
+                return;
+            }
+            yield_now().await;
+        }
+        image.multicast = true;
+        send_message(&CanMessage::FlashJoin).await;
+    }
+
+    /// Chunk aus dem Multicast, ohne Antwort. Fehlende Chunks meldet `gaps`.
+    pub async fn chunk(&mut self, id: CanId, data: &[u8], remote_request: bool) {
+        // nicht beigetretene Geräte hören mit und ignorieren alles
+        let Some(image) = self.image.as_mut().filter(|image| image.multicast) else {
+            return;
+        };
+        let Ok(CanMessage::FlashChunk { index, data }) =
+            CanMessage::decode(id, data, remote_request)
+        else {
+            return;
+        };
+        let mut received = RECEIVED.lock().await;
+        // doppelt, hinter dem Image oder Header, der schon per FlashWrite kam
+        if !received.insert(index) {
+            return;
+        }
+
+        let pos = index * MULTICAST_CHUNK_LEN as u32;
+        let len = (image.size - pos).min(MULTICAST_CHUNK_LEN as u32) as usize;
+        let addr = SLOT_OFFSETS[image.slot as usize] + pos - HEADER_LEN as u32;
+        // der letzte Chunk ist aufgefüllt, NorFlash schreibt ganze Worte
+        let mut buf = [0xFFu8; MULTICAST_CHUNK_LEN];
+        buf[..len].copy_from_slice(&data[..len]);
+        if self.flash.write(addr, &buf).is_err() {
+            self.image = None;
+            report(UpdateErrorCode::Write, &[]).await;
+            return;
+        }
+
+        // lückenlos Empfangenes gilt als geschrieben, für Verify und Fortsetzen
+        let before = image.written;
+        image.written = (received.contiguous() * MULTICAST_CHUNK_LEN as u32).min(image.size);
+        let written = image.written;
+        let done = written == image.size;
+        drop(received);
+        if before / RESUME_INTERVAL != written / RESUME_INTERVAL || done {
+            self.save().await;
+        }
+    }
+
+    /// Meldet auf ein RTR die fehlenden Chunks und danach den Fortschritt
+    pub async fn gaps(&mut self, _id: CanId, _data: &[u8], remote_request: bool) {
+        if !remote_request {
+            return;
+        }
+        let Some(image) = self.image.as_ref() else {
+            report(UpdateErrorCode::NotStarted, &[]).await;
+            return;
+        };
+        if image.multicast {
+            let received = RECEIVED.lock().await;
+            for (base, missing) in received.gaps().take(MAX_GAP_REPORTS) {
+                send_message(&CanMessage::FlashGaps { base, missing }).await;
+            }
+        }
+        self.send_progress().await;
+    }
+
+    pub async fn progress(&mut self, _id: CanId, _data: &[u8], remote_request: bool) {
+        if remote_request {
+            self.send_progress().await;
+        }
+    }
+
+    pub async fn select(&mut self, id: CanId, data: &[u8], remote_request: bool) {
+        if remote_request {
+            let slot = self.target_slot();
+            send_message(&CanMessage::FlashSelect { slot }).await;
+            return;
+        }
+        let Ok(CanMessage::FlashSelect { slot }) = CanMessage::decode(id, data, false) else {
+            invalid_data(id, data).await;
+            return;
+        };
+        if !self.is_writable(slot) {
+            report(UpdateErrorCode::InvalidSlot, &[slot]).await;
+            return;
+        }
+
+        // ein laufendes Update gehört zum alten Slot
+        self.selected = Some(slot);
+        self.image = None;
+        self.discard().await;
+        send_message(&CanMessage::FlashSelect { slot }).await;
+    }
+
+    pub async fn erase(&mut self, id: CanId, data: &[u8], remote_request: bool) {
+        let Ok(CanMessage::FlashErase { slot }) = CanMessage::decode(id, data, remote_request)
+        else {
+            invalid_data(id, data).await;
+            return;
+        };
+        if !self.is_writable(slot) {
+            report(UpdateErrorCode::InvalidSlot, &[slot]).await;
+            return;
+        }
+        if self.image.as_ref().is_some_and(|image| image.slot == slot) {
+            self.image = None;
+        }
+        // gespeicherter Fortschritt passt nicht mehr zum Inhalt
+        self.discard().await;
+
+        let start = SLOT_OFFSETS[slot as usize];
+        for sector in (start..start + SLOT_SIZE).step_by(SECTOR_SIZE as usize) {
+            if self.flash.erase(sector, sector + SECTOR_SIZE).is_err() {
+                report(UpdateErrorCode::Erase, &[slot]).await;
+                return;
+            }
+            // dauert Sekunden, andere Tasks sollen weiterlaufen
+            yield_now().await;
+        }
+        send_message(&CanMessage::FlashErase { slot }).await;
+    }
+
+    pub async fn read(&mut self, id: CanId, data: &[u8], remote_request: bool) {
+        let Ok(CanMessage::FlashRead { offset, len }) =
+            CanMessage::decode(id, data, remote_request)
+        else {
+            invalid_data(id, data).await;
+            return;
+        };
+        // immer ganze FlashData-Frames
+        let len = (len.min(MAX_READ_LEN) as usize + 3) & !3;
+        if offset.saturating_add(len as u32) > SLOT_SIZE {
+            invalid_data(id, data).await;
+            return;
+        }
+
+        let slot = self
+            .image
+            .as_ref()
+            .map_or(self.target_slot(), |image| image.slot);
+        let mut buf = [0u8; MAX_READ_LEN as usize];
+        let addr = SLOT_OFFSETS[slot as usize] + offset;
+        if self.flash.read(addr, &mut buf[..len]).is_err() {
+            report(UpdateErrorCode::Read, &[slot]).await;
+            return;
+        }
+        for (i, word) in buf[..len].chunks(4).enumerate() {
+            let msg = CanMessage::FlashData {
+                offset: offset + 4 * i as u32,
+                data: word.try_into().unwrap(),
+            };
+            send_message(&msg).await;
+        }
+    }
+
+    /// Liest das Image zurück und aktiviert den Slot, wenn CRC und Signatur stimmen
+    pub async fn verify(&mut self, _id: CanId, _data: &[u8], remote_request: bool) {
+        if !remote_request {
+            return;
+        }
+        let Some(image) = self.image.as_ref() else {
+            report(UpdateErrorCode::NotStarted, &[]).await;
+            return;
+        };
+
+        // komprimiert muss auch der Stream vollständig und unverändert angekommen sein
+        let stream_valid = image.written == image.size
+            && image
+                .expanded
+                .is_none_or(|e| e.stream == image.size && e.stream_crc == image.crc);
+        // geprüft wird das ausgepackte Image im Slot
+        let (size, written, expected) = match image.expanded {
+            Some(e) => (e.size, e.written, e.crc),
+            None => (image.size, image.written, image.crc),
+        };
+
+        // CRC und Signatur decken den Header mit ab
+        let mut crc = 0;
+        let mut verifier = Verifier::new();
+        if written >= HEADER_LEN as u32 {
+            crc = crc32::update(crc, &image.header);
+            verifier.update(&image.header);
+        }
+        let mut trailer = [0u8; TRAILER_LEN];
+        let body = size - TRAILER_LEN as u32;
+        let mut buf = [0u8; VERIFY_READ_SIZE];
+        let start = SLOT_OFFSETS[image.slot as usize];
+        let mut pos = HEADER_LEN as u32;
+        while pos < written {
+            let n = (written - pos).min(VERIFY_READ_SIZE as u32) as usize;
+            let addr = start + pos - HEADER_LEN as u32;
+            if self.flash.read(addr, &mut buf[..n]).is_err() {
+                report(UpdateErrorCode::Read, &[image.slot]).await;
+                return;
+            }
+            crc = crc32::update(crc, &buf[..n]);
+            // Firmware hashen, Trailer aufheben
+            for (i, &byte) in buf[..n].iter().enumerate() {
+                if let Some(t) = (pos + i as u32).checked_sub(body) {
+                    trailer[t as usize] = byte;
                 }
-                Err(_) => {
-                    ErrorReport::send(
-                        Component::Ota,
-                        ErrorCode::Unknown,
-                        Severity::RecoverableError,
-                        UpdateErrorCode::Write as u8,
-                        &[0u8, 0u8, 0u8],
-                    )
-                    .await;
-                    // Fehler beim Schreiben
-                    self.ota = None;
+            }
+            let hashed = (body.saturating_sub(pos) as usize).min(n);
+            verifier.update(&buf[..hashed]);
+            pos += n as u32;
+            yield_now().await;
+        }
+
+        let valid = stream_valid && written == size && crc == expected;
+        if valid && verifier.verify(&trailer, &UPDATE_KEY).is_err() {
+            // jeder am Bus könnte sonst die Firmware tauschen
+            let slot = image.slot;
+            self.image = None;
+            self.discard().await;
+            report(UpdateErrorCode::Signature, &[slot]).await;
+            return;
+        }
+        if valid {
+            let Some(ota) = self.ota.as_mut() else {
+                report(UpdateErrorCode::Init, &[]).await;
+                return;
+            };
+            // bootet beim nächsten Restart, Rollback bleibt möglich
+            ota.set_target_ota_boot_partition(image.slot as usize, OtaImgState::EspOtaImgNew);
+            let record = BootRecord {
+                slot: image.slot,
+                previous: self.running_slot(),
+                state: BootState::Pending,
+                attempts: 0,
+            };
+            self.save_boot(&record).await;
+            self.discard().await;
+        }
+        send_message(&CanMessage::FlashVerify { crc, valid }).await;
+    }
+
+    pub async fn boot_status(&mut self, _id: CanId, _data: &[u8], remote_request: bool) {
+        if remote_request {
+            self.send_boot_status().await;
+        }
+    }
+
+    pub async fn boot_confirm(&mut self, _id: CanId, _data: &[u8], remote_request: bool) {
+        if !remote_request {
+            self.confirm().await;
+        }
+    }
+
+    /// Markiert ein neues Image als gültig und meldet das Ergebnis des letzten Updates
+    async fn confirm(&mut self) {
+        if let Some(mut record) = self.boot {
+            if record.state == BootState::Pending {
+                if let Some(ota) = self.ota.as_mut() {
+                    let _ = ota.ota_mark_app_valid();
                 }
+                record.state = BootState::Confirmed;
+                self.boot = Some(record);
             }
-        } else {
-            ErrorReport::send(
-                Component::Ota,
+            // gemeldet wird nur einmal, beim nächsten Start zählt wieder Valid
+            if !self.boot_reported {
+                self.boot_reported = true;
+                let done = BootRecord {
+                    state: BootState::Valid,
+                    ..record
+                };
+                self.save_boot(&done).await;
+            }
+        }
+        self.send_boot_status().await;
+    }
+
+    async fn send_boot_status(&self) {
+        let msg = match self.boot.as_ref() {
+            Some(record) => CanMessage::BootStatus {
+                slot: self.running_slot(),
+                state: record.state,
+                attempts: record.attempts,
+            },
+            None => CanMessage::BootStatus {
+                slot: self.running_slot(),
+                state: BootState::Valid,
+                attempts: 0,
+            },
+        };
+        send_message(&msg).await;
+    }
+
+    /// Bootet beim nächsten Start wieder den vorherigen Slot
+    fn rollback(&mut self, record: &BootRecord) {
+        let Some(ota) = self.ota.as_mut() else {
+            return;
+        };
+        match record.previous {
+            Some(slot) => {
+                ota.set_target_ota_boot_partition(slot as usize, OtaImgState::EspOtaImgValid)
+            }
+            // der Bootloader überspringt ungültige Einträge und startet factory
+            None => {
+                let _ = ota.ota_mark_app_invalid_rollback();
+            }
+        }
+    }
+
+    async fn save_boot(&self, record: &BootRecord) {
+        let result = config()
+            .await
+            .set_bytes(Key::BootRecord, &record.to_bytes())
+            .await;
+        if result.is_err() {
+            send_error_report(
+                Component::Storage,
                 ErrorCode::Unknown,
-                Severity::RecoverableError,
-                UpdateErrorCode::NotStarted as u8,
-                &[0u8, 0u8, 0u8],
+                Severity::Warning,
+                Key::BootRecord as u8,
+                &[],
             )
             .await;
-            // OTA nicht gestartet
         }
     }
 
-    pub async fn progress(&mut self, _id: CanId, _data: &[u8], _remote_request: bool) {}
-    pub async fn select(&mut self, _id: CanId, _data: &[u8], _remote_request: bool) {}
-    pub async fn erase(&mut self, _id: CanId, _data: &[u8], _remote_request: bool) {}
-    pub async fn read(&mut self, _id: CanId, _data: &[u8], _remote_request: bool) {}
-    pub async fn verify(&mut self, _id: CanId, _data: &[u8], _remote_request: bool) {}
+    /// Stand eines abgebrochenen Updates desselben Images, sonst `image`.
+    /// Ohne Neustart zählt der Stand im RAM, sonst der gespeicherte.
+    async fn resume(&self, image: Image) -> Image {
+        if let Some(current) = self.image.filter(|current| current.is_same(&image)) {
+            return current.resume_point();
+        }
+        let saved = config().await.get_bytes(Key::UpdateState).await;
+        let Some(saved) = saved.map(Image::from_bytes) else {
+            return image;
+        };
+        if !saved.is_same(&image) || saved.written > saved.size {
+            return image;
+        }
+        // das Gerät könnte inzwischen anders konfiguriert sein
+        if saved.written > 0 && check_header(&saved.header).await.is_err() {
+            return image;
+        }
+        // im Multicast wird an beliebiger Stelle gespeichert
+        saved.resume_point()
+    }
+
+    async fn save(&self) {
+        let Some(image) = self.image.as_ref() else {
+            return;
+        };
+        let result = config()
+            .await
+            .set_bytes(Key::UpdateState, &image.to_bytes())
+            .await;
+        if result.is_err() {
+            // Update läuft weiter, kann nach einem Abbruch aber nicht fortgesetzt werden
+            send_error_report(
+                Component::Storage,
+                ErrorCode::Unknown,
+                Severity::Warning,
+                Key::UpdateState as u8,
+                &[],
+            )
+            .await;
+        }
+    }
+
+    /// Größe 0 passt zu keinem `FlashStart`
+    async fn discard(&self) {
+        let empty = Image {
+            slot: 0,
+            size: 0,
+            crc: 0,
+            written: 0,
+            header: [0xFF; HEADER_LEN],
+            multicast: false,
+            expanded: None,
+        };
+        let _ = config()
+            .await
+            .set_bytes(Key::UpdateState, &empty.to_bytes())
+            .await;
+    }
+
+    async fn send_progress(&self) {
+        let (written, total) = self
+            .image
+            .as_ref()
+            .map_or((0, 0), |image| (image.written, image.size));
+        send_message(&CanMessage::FlashProgress { written, total }).await;
+    }
+
+    /// Slot, aus dem gerade gebootet wurde. `None` bei der factory-Partition.
+    fn running_slot(&self) -> Option<u8> {
+        let ota = self.ota.as_ref()?;
+        ota.get_currently_booted_partition().map(|slot| slot as u8)
+    }
+
+    fn target_slot(&self) -> u8 {
+        self.selected.unwrap_or_else(|| {
+            self.running_slot()
+                .map_or(0, |running| (running + 1) % SLOT_COUNT)
+        })
+    }
+
+    fn is_writable(&self, slot: u8) -> bool {
+        slot < SLOT_COUNT && Some(slot) != self.running_slot()
+    }
+}
+
+/// Löscht den Sektor bei `addr` und schreibt einen ausgepackten Block hinein
+fn write_block(flash: &mut FlashStorage, addr: u32, block: &[u8]) -> Result<(), UpdateErrorCode> {
+    flash
+        .erase(addr, addr + SECTOR_SIZE)
+        .map_err(|_| UpdateErrorCode::Erase)?;
+    // NorFlash schreibt nur ganze Worte, der letzte Block wird aufgefüllt
+    let aligned = block.len() & !3;
+    flash
+        .write(addr, &block[..aligned])
+        .map_err(|_| UpdateErrorCode::Write)?;
+    let tail = &block[aligned..];
+    if !tail.is_empty() {
+        let mut buf = [0xFFu8; 4];
+        buf[..tail.len()].copy_from_slice(tail);
+        flash
+            .write(addr + aligned as u32, &buf)
+            .map_err(|_| UpdateErrorCode::Write)?;
+    }
+    Ok(())
+}
+
+/// Der Host setzt nur an Blockgrenzen auf
+fn block_start(written: u32, size: u32) -> u32 {
+    if written == size {
+        size
+    } else {
+        written - written % ACK_INTERVAL
+    }
+}
+
+/// Passt das Image zu Gerätetyp und Hardware-Revision?
+async fn check_header(data: &[u8]) -> Result<(), HeaderError> {
+    let header = ImageHeader::from_bytes(data)?;
+    let device_type = *DEVICE_TYPE.lock().await;
+    let hw_rev = config()
+        .await
+        .get_u8(Key::HardwareRevision)
+        .await
+        .unwrap_or(0);
+    header.check(device_type, hw_rev)
+}
+
+async fn report(code: UpdateErrorCode, details: &[u8]) {
+    send_error_report(
+        Component::Ota,
+        ErrorCode::Unknown,
+        Severity::RecoverableError,
+        code as u8,
+        details,
+    )
+    .await;
+}
+
+async fn invalid_data(id: CanId, data: &[u8]) {
+    send_error_report(
+        Component::Update,
+        ErrorCode::InvalidData,
+        Severity::Warning,
+        UpdateErrorCode::InvalidData as u8,
+        &[u8::from(id.msg_type), data.len() as u8, 0u8],
+    )
+    .await;
 }
@@ -0,0 +1,195 @@
+use cancomponents_core::can_id::CanId;
+use cancomponents_core::can_message::{CanMessage, DecodeError, Payload};
+use embedded_can::{ExtendedId, Frame as _, Id};
+use socketcan::CanFrame;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// A frame as seen on the bus, standard ids are not used by the protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub id: CanId,
+    pub data: Payload,
+    pub remote: bool,
+}
+
+impl Frame {
+    /// Frame carrying `msg` to (or from) the given device
+    pub fn new(device_type: u8, device_id: u8, msg: &CanMessage) -> Self {
+        let (id, data) = msg.encode(device_type, device_id);
+        Self {
+            id,
+            data,
+            remote: msg.is_remote(),
+        }
+    }
+
+    pub fn decode(&self) -> Result<CanMessage, DecodeError> {
+        CanMessage::decode(self.id, &self.data, self.remote)
+    }
+
+    /// Whether the frame was sent by the given device
+    pub fn is_from(&self, device_type: u8, device_id: u8) -> bool {
+        self.id.device_type == device_type & 0x3F && self.id.device_id == device_id
+    }
+}
+
+impl From<&Frame> for CanFrame {
+    fn from(frame: &Frame) -> Self {
+        let id: ExtendedId = frame.id.into();
+        if frame.remote {
+            CanFrame::new_remote(id, frame.data.len())
+        } else {
+            CanFrame::new(id, &frame.data)
+        }
+        .expect("frame payload fits into a classic CAN frame")
+    }
+}
+
+impl TryFrom<&CanFrame> for Frame {
+    type Error = ();
+
+    fn try_from(frame: &CanFrame) -> Result<Self, Self::Error> {
+        let Id::Extended(id) = frame.id() else {
+            return Err(());
+        };
+        let data = if frame.is_remote_frame() {
+            Payload::new()
+        } else {
+            Payload::from_slice(frame.data()).map_err(|_| ())?
+        };
+        Ok(Self {
+            id: CanId::from(id),
+            data,
+            remote: frame.is_remote_frame(),
+        })
+    }
+}
+
+/// Access to a CAN bus. Frames sent through a handle are not received by it.
+pub trait Bus: Send + Sync + 'static {
+    fn send(&self, frame: &Frame) -> impl Future<Output = io::Result<()>> + Send;
+    fn recv(&self) -> impl Future<Output = io::Result<Frame>> + Send;
+}
+
+/// Linux SocketCAN interface such as `can0` or `vcan0`
+pub struct SocketCan {
+    socket: socketcan::tokio::CanSocket,
+}
+
+impl SocketCan {
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let socket = socketcan::tokio::CanSocket::open(interface)?;
+        Ok(Self { socket })
+    }
+}
+
+impl Bus for SocketCan {
+    async fn send(&self, frame: &Frame) -> io::Result<()> {
+        self.socket.write_frame(frame.into()).await
+    }
+
+    async fn recv(&self) -> io::Result<Frame> {
+        loop {
+            let frame = self.socket.read_frame().await?;
+            // error frames and standard ids are not part of the protocol
+            if let Ok(frame) = Frame::try_from(&frame) {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+/// In-memory bus, every attached port sees the frames of all other ports
+#[derive(Clone)]
+pub struct VirtualBus {
+    tx: broadcast::Sender<(usize, Frame)>,
+    next_port: Arc<AtomicUsize>,
+}
+
+impl VirtualBus {
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast::channel(1024).0,
+            next_port: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn attach(&self) -> VirtualPort {
+        VirtualPort {
+            port: self.next_port.fetch_add(1, Ordering::Relaxed),
+            tx: self.tx.clone(),
+            rx: tokio::sync::Mutex::new(self.tx.subscribe()),
+        }
+    }
+}
+
+impl Default for VirtualBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct VirtualPort {
+    port: usize,
+    tx: broadcast::Sender<(usize, Frame)>,
+    rx: tokio::sync::Mutex<broadcast::Receiver<(usize, Frame)>>,
+}
+
+impl Bus for VirtualPort {
+    async fn send(&self, frame: &Frame) -> io::Result<()> {
+        // nobody listening is fine on a real bus as well
+        let _ = self.tx.send((self.port, frame.clone()));
+        Ok(())
+    }
+
+    async fn recv(&self) -> io::Result<Frame> {
+        let mut rx = self.rx.lock().await;
+        loop {
+            match rx.recv().await {
+                Ok((port, frame)) if port != self.port => return Ok(frame),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(io::ErrorKind::BrokenPipe.into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cancomponents_core::can_message_type::CanMessageType;
+
+    #[test]
+    fn test_socketcan_frame_conversion() {
+        let frames = [
+            Frame::new(3, 7, &CanMessage::Uptime(42)),
+            Frame::new(3, 7, &CanMessage::Request(CanMessageType::HwRev)),
+        ];
+        for frame in frames {
+            let raw = CanFrame::from(&frame);
+            assert_eq!(Frame::try_from(&raw), Ok(frame));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_virtual_bus_skips_own_frames() {
+        let bus = VirtualBus::new();
+        let a = bus.attach();
+        let b = bus.attach();
+
+        let ping = Frame::new(1, 2, &CanMessage::Ping);
+        a.send(&ping).await.unwrap();
+        assert_eq!(b.recv().await.unwrap(), ping);
+
+        let answer = Frame::new(1, 2, &CanMessage::Available { announce: false });
+        b.send(&answer).await.unwrap();
+        assert_eq!(a.recv().await.unwrap(), answer);
+    }
+}
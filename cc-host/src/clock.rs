@@ -0,0 +1,16 @@
+use std::sync::OnceLock;
+use std::time::Instant;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Monotonic time in the representation used by the cc-core state machines
+pub fn now() -> embassy_time::Instant {
+    let start = START.get_or_init(Instant::now);
+    embassy_time::Instant::from_micros(start.elapsed().as_micros() as u64)
+}
+
+/// Converts a cc-core deadline into a tokio one
+pub fn deadline(at: embassy_time::Instant) -> Instant {
+    let wait = at.saturating_duration_since(now());
+    Instant::now() + std::time::Duration::from_micros(wait.as_micros())
+}
@@ -0,0 +1,70 @@
+use cancomponents_core::can_message::DecodeError;
+use cancomponents_core::error_report::ErrorReport;
+use cancomponents_core::transport::TransportError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Decode(DecodeError),
+    Transport(TransportError),
+    /// The device did not answer in time
+    Timeout,
+    /// The answer did not have the expected content
+    UnexpectedResponse,
+    /// The bus reader stopped
+    Closed,
+    /// The device answered with a `DeviceError`
+    Device(ErrorReport),
+    /// The image read back by the device does not match
+    Verify {
+        expected: u32,
+        actual: u32,
+    },
+    /// The new image was not confirmed, the device runs the previous one
+    RolledBack,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "bus error: {e}"),
+            Error::Decode(e) => write!(f, "cannot decode frame: {e:?}"),
+            Error::Transport(e) => write!(f, "segmented transfer failed: {e:?}"),
+            Error::Timeout => write!(f, "no response from device"),
+            Error::UnexpectedResponse => write!(f, "unexpected response from device"),
+            Error::Closed => write!(f, "bus closed"),
+            Error::Device(report) => write!(
+                f,
+                "device reported {:?} error {:?} with code {} ({:?})",
+                report.component, report.code, report.local_code, report.severity
+            ),
+            Error::Verify { expected, actual } => write!(
+                f,
+                "image check failed, expected CRC {expected:08x}, device has {actual:08x}"
+            ),
+            Error::RolledBack => write!(f, "device rolled back to the previous image"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(e: TransportError) -> Self {
+        Error::Transport(e)
+    }
+}
@@ -0,0 +1,981 @@
+use crate::bus::{Bus, Frame};
+use crate::clock;
+use crate::error::{Error, Result};
+use cancomponents_core::can_id::CanId;
+use cancomponents_core::can_message::CanMessage;
+use cancomponents_core::can_message_type::CanMessageType;
+use cancomponents_core::error_report::LogEntry;
+use cancomponents_core::relais_map::RelaisMap;
+use cancomponents_core::relais_message::{
+    PowerOn, RelaisLimits, RelaisMessage, RelaisStats, RelaisStatus, ShutterStatus,
+};
+use cancomponents_core::shutter::ShutterTiming;
+use cancomponents_core::transport::{Receiver, RxStatus, Sender, MAX_TRANSFER_LEN};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Everything a device reports on `RequestParameter`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Parameters {
+    pub uptime_minutes: Option<u32>,
+    pub uid0: Option<u64>,
+    pub uid1: Option<u64>,
+    pub custom_string: Option<String>,
+    pub hw_rev: Option<u8>,
+    pub version: Option<String>,
+}
+
+impl Parameters {
+    fn is_complete(&self) -> bool {
+        self.uptime_minutes.is_some()
+            && self.uid0.is_some()
+            && self.uid1.is_some()
+            && self.custom_string.is_some()
+            && self.hw_rev.is_some()
+            && self.version.is_some()
+    }
+}
+
+/// Address of a device that answered a scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Node {
+    pub device_type: u8,
+    pub device_id: u8,
+}
+
+/// Request/response access to the devices on a bus
+pub struct Gateway<B: Bus> {
+    bus: Arc<B>,
+    frames: broadcast::Sender<Frame>,
+    reader: JoinHandle<()>,
+    timeout: Duration,
+}
+
+impl<B: Bus> Gateway<B> {
+    /// Starts reading from `bus`, has to be called within a tokio runtime
+    pub fn new(bus: B) -> Self {
+        let bus = Arc::new(bus);
+        let (frames, _) = broadcast::channel(1024);
+        let reader = tokio::spawn(read_task(bus.clone(), frames.clone()));
+        Self {
+            bus,
+            frames,
+            reader,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Stream of every frame received after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<Frame> {
+        self.frames.subscribe()
+    }
+
+    pub async fn send(&self, device_type: u8, device_id: u8, msg: &CanMessage) -> Result<()> {
+        self.send_frame(&Frame::new(device_type, device_id, msg))
+            .await
+    }
+
+    pub async fn send_frame(&self, frame: &Frame) -> Result<()> {
+        Ok(self.bus.send(frame).await?)
+    }
+
+    /// Waits until `deadline` for a frame accepted by `filter`
+    pub async fn wait_for<T>(
+        &self,
+        rx: &mut broadcast::Receiver<Frame>,
+        deadline: Instant,
+        mut filter: impl FnMut(&Frame) -> Option<T>,
+    ) -> Result<T> {
+        loop {
+            let frame = tokio::time::timeout_at(deadline.into(), rx.recv())
+                .await
+                .map_err(|_| Error::Timeout)?;
+            match frame {
+                Ok(frame) => {
+                    if let Some(result) = filter(&frame) {
+                        return Ok(result);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Err(Error::Closed),
+            }
+        }
+    }
+
+    /// Sends `msg` and returns the first `response` frame of the device
+    pub async fn request(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        msg: &CanMessage,
+        response: CanMessageType,
+    ) -> Result<CanMessage> {
+        let mut rx = self.subscribe();
+        self.send(device_type, device_id, msg).await?;
+        let frame = self
+            .wait_for(&mut rx, Instant::now() + self.timeout, |frame| {
+                is_response(frame, device_type, device_id, response).then(|| frame.clone())
+            })
+            .await?;
+        Ok(frame.decode()?)
+    }
+
+    /// Round trip time of a `Ping`
+    pub async fn ping(&self, device_type: u8, device_id: u8) -> Result<Duration> {
+        let start = Instant::now();
+        self.request(
+            device_type,
+            device_id,
+            &CanMessage::Ping,
+            CanMessageType::Ping,
+        )
+        .await?;
+        Ok(start.elapsed())
+    }
+
+    /// Pings the broadcast address and collects every device answering with
+    /// `Ping` or announcing itself with `Available`, until the bus stays quiet
+    /// for the timeout
+    pub async fn scan(&self) -> Result<Vec<Node>> {
+        let mut rx = self.subscribe();
+        self.send(0, 0, &CanMessage::Ping).await?;
+        let mut nodes = BTreeSet::new();
+        loop {
+            let node = self
+                .wait_for(&mut rx, Instant::now() + self.timeout, |frame| {
+                    let answer = matches!(
+                        frame.id.msg_type,
+                        CanMessageType::Ping | CanMessageType::Available
+                    );
+                    (answer && !frame.remote).then_some(Node {
+                        device_type: frame.id.device_type,
+                        device_id: frame.id.device_id,
+                    })
+                })
+                .await;
+            match node {
+                Ok(node) => {
+                    nodes.insert(node);
+                }
+                Err(Error::Timeout) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(nodes.into_iter().collect())
+    }
+
+    /// Assigns a new id and type, the device restarts and announces itself
+    /// with the new address
+    pub async fn assign(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        new_type: u8,
+        new_id: u8,
+    ) -> Result<()> {
+        let mut rx = self.subscribe();
+        let msg = CanMessage::DeviceIdType {
+            id: new_id,
+            device_type: new_type,
+        };
+        self.send(device_type, device_id, &msg).await?;
+        self.wait_for(&mut rx, Instant::now() + self.timeout, |frame| {
+            is_response(frame, new_type, new_id, CanMessageType::Available).then_some(())
+        })
+        .await
+    }
+
+    /// Switches a single relay
+    pub async fn relais(&self, device_type: u8, device_id: u8, msg: RelaisMessage) -> Result<()> {
+        self.send(device_type, device_id, &CanMessage::Relais(msg))
+            .await
+    }
+
+    /// Moves a rollershutter up or down
+    pub async fn rollershutter(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        msg: RelaisMessage,
+    ) -> Result<()> {
+        self.send(device_type, device_id, &CanMessage::Rollershutter(msg))
+            .await
+    }
+
+    /// State and remaining timer of all relays. Devices also send one
+    /// `RelaisState` after every change.
+    pub async fn read_relais_states(
+        &self,
+        device_type: u8,
+        device_id: u8,
+    ) -> Result<Vec<RelaisStatus>> {
+        let messages = self
+            .request_all(device_type, device_id, CanMessageType::RelaisState)
+            .await?;
+        Ok(messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                CanMessage::RelaisState(status) => Some(status),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Motor state, estimated position and tilt of all shutters. Devices also
+    /// send one `RollershutterState` after every change.
+    pub async fn read_shutter_states(
+        &self,
+        device_type: u8,
+        device_id: u8,
+    ) -> Result<Vec<ShutterStatus>> {
+        let messages = self
+            .request_all(device_type, device_id, CanMessageType::RollershutterState)
+            .await?;
+        Ok(messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                CanMessage::RollershutterState(status) => Some(status),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// What each relay does after power-on as `(num, bank, power_on)`
+    pub async fn read_power_on(
+        &self,
+        device_type: u8,
+        device_id: u8,
+    ) -> Result<Vec<(u8, u8, PowerOn)>> {
+        let messages = self
+            .request_all(device_type, device_id, CanMessageType::RelaisPowerOn)
+            .await?;
+        Ok(messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                CanMessage::RelaisPowerOn {
+                    num,
+                    bank,
+                    power_on,
+                } => Some((num, bank, power_on)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Stores what a relay does after power-on, applies from the next start
+    pub async fn write_power_on(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        num: u8,
+        power_on: PowerOn,
+        bank: u8,
+    ) -> Result<()> {
+        let msg = CanMessage::RelaisPowerOn {
+            num,
+            bank,
+            power_on,
+        };
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Safety limits of all relays as `(num, bank, limits)`
+    pub async fn read_limits(
+        &self,
+        device_type: u8,
+        device_id: u8,
+    ) -> Result<Vec<(u8, u8, RelaisLimits)>> {
+        let messages = self
+            .request_all(device_type, device_id, CanMessageType::RelaisLimits)
+            .await?;
+        Ok(messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                CanMessage::RelaisLimits { num, bank, limits } => Some((num, bank, limits)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Stores the safety limits of a relay, they apply at once
+    pub async fn write_limits(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        num: u8,
+        limits: RelaisLimits,
+        bank: u8,
+    ) -> Result<()> {
+        let msg = CanMessage::RelaisLimits { num, bank, limits };
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Switching cycles and on-time of all relays as `(num, bank, stats)`
+    pub async fn read_stats(
+        &self,
+        device_type: u8,
+        device_id: u8,
+    ) -> Result<Vec<(u8, u8, RelaisStats)>> {
+        let messages = self
+            .request_all(device_type, device_id, CanMessageType::RelaisStats)
+            .await?;
+        Ok(messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                CanMessage::RelaisStats { num, bank, stats } => Some((num, bank, stats)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Sets the counters of a relay, zero after it was replaced
+    pub async fn write_stats(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        num: u8,
+        stats: RelaisStats,
+        bank: u8,
+    ) -> Result<()> {
+        let msg = CanMessage::RelaisStats { num, bank, stats };
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Cycles after which a relay is reported as worn, 0 for never
+    pub async fn read_cycle_limit(&self, device_type: u8, device_id: u8) -> Result<u32> {
+        let msg_type = CanMessageType::RelaisCycleLimit;
+        let response = self
+            .request(
+                device_type,
+                device_id,
+                &CanMessage::Request(msg_type),
+                msg_type,
+            )
+            .await?;
+        match response {
+            CanMessage::RelaisCycleLimit(cycles) => Ok(cycles),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub async fn write_cycle_limit(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        cycles: u32,
+    ) -> Result<()> {
+        let msg = CanMessage::RelaisCycleLimit(cycles);
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Moves a shutter to `percent` closed, needs its travel times
+    pub async fn shutter_position(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        num: u8,
+        percent: u8,
+        bank: u8,
+    ) -> Result<()> {
+        let msg = CanMessage::ShutterPosition { num, bank, percent };
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Turns the slats of a venetian blind to `percent` closed
+    pub async fn shutter_tilt(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        num: u8,
+        percent: u8,
+        bank: u8,
+    ) -> Result<()> {
+        let msg = CanMessage::ShutterTilt { num, bank, percent };
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Stores the travel and slat turn times of a shutter, its position is
+    /// unknown until it next runs into an end stop
+    pub async fn write_shutter_timing(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        num: u8,
+        timing: ShutterTiming,
+        bank: u8,
+    ) -> Result<()> {
+        let msg = CanMessage::ShutterTiming {
+            num,
+            bank,
+            up: timing.up,
+            down: timing.down,
+        };
+        self.send(device_type, device_id, &msg).await?;
+        let msg = CanMessage::ShutterSlatTiming {
+            num,
+            bank,
+            turn: timing.turn,
+        };
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Travel and slat turn times of all shutters as `(num, bank, timing)`
+    pub async fn read_shutter_timings(
+        &self,
+        device_type: u8,
+        device_id: u8,
+    ) -> Result<Vec<(u8, u8, ShutterTiming)>> {
+        let mut timings = Vec::new();
+        for msg in self
+            .request_all(device_type, device_id, CanMessageType::ShutterTiming)
+            .await?
+        {
+            if let CanMessage::ShutterTiming {
+                num,
+                bank,
+                up,
+                down,
+            } = msg
+            {
+                let turn = Default::default();
+                timings.push((num, bank, ShutterTiming { up, down, turn }));
+            }
+        }
+        for msg in self
+            .request_all(device_type, device_id, CanMessageType::ShutterSlatTiming)
+            .await?
+        {
+            if let CanMessage::ShutterSlatTiming { num, bank, turn } = msg {
+                let timing = timings.iter_mut().find(|(n, b, _)| (*n, *b) == (num, bank));
+                if let Some((_, _, timing)) = timing {
+                    timing.turn = turn;
+                }
+            }
+        }
+        Ok(timings)
+    }
+
+    /// Pause of the shutter motors between opposite directions
+    pub async fn read_shutter_dead_time(
+        &self,
+        device_type: u8,
+        device_id: u8,
+    ) -> Result<embassy_time::Duration> {
+        let msg_type = CanMessageType::ShutterDeadTime;
+        let response = self
+            .request(
+                device_type,
+                device_id,
+                &CanMessage::Request(msg_type),
+                msg_type,
+            )
+            .await?;
+        match response {
+            CanMessage::ShutterDeadTime(dead_time) => Ok(dead_time),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Stores the pause between opposite directions, the device raises it to
+    /// its minimum
+    pub async fn write_shutter_dead_time(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        dead_time: embassy_time::Duration,
+    ) -> Result<()> {
+        let msg = CanMessage::ShutterDeadTime(dead_time);
+        self.send(device_type, device_id, &msg).await
+    }
+
+    /// Requests a value the device answers with one frame per channel
+    async fn request_all(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        msg_type: CanMessageType,
+    ) -> Result<Vec<CanMessage>> {
+        let mut rx = self.subscribe();
+        self.send(device_type, device_id, &CanMessage::Request(msg_type))
+            .await?;
+        let mut messages = Vec::new();
+        loop {
+            let frame = self
+                .wait_for(&mut rx, Instant::now() + self.timeout, |frame| {
+                    is_response(frame, device_type, device_id, msg_type).then(|| frame.clone())
+                })
+                .await;
+            match frame {
+                Ok(frame) => messages.push(frame.decode()?),
+                Err(Error::Timeout) if !messages.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Reads a single byte value such as `RelaisMode`, `ExtensionMode`,
+    /// `Baudrate` or `HwRev`
+    pub async fn read_u8(&self, device_type: u8, device_id: u8, key: CanMessageType) -> Result<u8> {
+        let response = self
+            .request(device_type, device_id, &CanMessage::Request(key), key)
+            .await?;
+        match response {
+            CanMessage::DeviceGroup(val)
+            | CanMessage::Baudrate(val)
+            | CanMessage::HwRev(val)
+            | CanMessage::ExtensionMode(val)
+            | CanMessage::RelaisMode(val) => Ok(val),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Stores a single byte value, the device restarts afterwards
+    pub async fn write_u8(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        key: CanMessageType,
+        value: u8,
+    ) -> Result<()> {
+        let frame = Frame {
+            id: CanId::new(device_type, device_id, key),
+            data: heapless::Vec::from_slice(&[value]).unwrap(),
+            remote: false,
+        };
+        self.send_frame(&frame).await
+    }
+
+    /// Reads a segmented string such as `CustomString`
+    pub async fn read_string(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        msg_type: CanMessageType,
+    ) -> Result<String> {
+        let data = self
+            .read_segmented(device_type, device_id, msg_type)
+            .await?;
+        String::from_utf8(data).map_err(|_| Error::UnexpectedResponse)
+    }
+
+    /// Writes a segmented string such as `CustomString`
+    pub async fn write_string(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        msg_type: CanMessageType,
+        value: &str,
+    ) -> Result<()> {
+        self.write_segmented(device_type, device_id, msg_type, value.as_bytes())
+            .await
+    }
+
+    /// The recent error reports of the device, the oldest first
+    pub async fn read_log(&self, device_type: u8, device_id: u8) -> Result<Vec<LogEntry>> {
+        let data = self
+            .read_segmented(device_type, device_id, CanMessageType::LogDownload)
+            .await?;
+        Ok(LogEntry::parse(&data).collect())
+    }
+
+    /// The stored channel map, which is not necessarily the one in use
+    /// before the next start
+    pub async fn read_relais_map(&self, device_type: u8, device_id: u8) -> Result<RelaisMap> {
+        let data = self
+            .read_segmented(device_type, device_id, CanMessageType::RelaisMap)
+            .await?;
+        RelaisMap::from_bytes(&data).map_err(|_| Error::UnexpectedResponse)
+    }
+
+    /// Stores a channel map, it takes effect on the next start
+    pub async fn write_relais_map(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        map: &RelaisMap,
+    ) -> Result<()> {
+        self.write_segmented(
+            device_type,
+            device_id,
+            CanMessageType::RelaisMap,
+            &map.to_bytes(),
+        )
+        .await
+    }
+
+    async fn read_segmented(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        msg_type: CanMessageType,
+    ) -> Result<Vec<u8>> {
+        let mut rx = self.subscribe();
+        self.send(device_type, device_id, &CanMessage::Request(msg_type))
+            .await?;
+        let mut receiver = Receiver::<MAX_TRANSFER_LEN>::new(0, 0);
+        loop {
+            let frame = self
+                .wait_for(&mut rx, Instant::now() + self.timeout, |frame| {
+                    is_response(frame, device_type, device_id, msg_type).then(|| frame.clone())
+                })
+                .await?;
+            if let Ok(CanMessage::LegacyCustomString(value)) = frame.decode() {
+                return Ok(value.as_bytes().to_vec());
+            }
+            if let Some(data) = self.receive_segment(&mut receiver, &frame).await? {
+                return Ok(data);
+            }
+        }
+    }
+
+    async fn write_segmented(
+        &self,
+        device_type: u8,
+        device_id: u8,
+        msg_type: CanMessageType,
+        data: &[u8],
+    ) -> Result<()> {
+        let id = CanId::new(device_type, device_id, msg_type);
+        let mut rx = self.subscribe();
+        let mut sender = Sender::<MAX_TRANSFER_LEN>::new(data)?;
+        while !sender.is_done() {
+            if let Some(data) = sender.poll(clock::now())? {
+                self.send_frame(&Frame {
+                    id,
+                    data,
+                    remote: false,
+                })
+                .await?;
+                continue;
+            }
+            let Some(next) = sender.next_deadline() else {
+                break;
+            };
+            let fc = self
+                .wait_for(&mut rx, clock::deadline(next), |frame| {
+                    is_response(frame, device_type, device_id, msg_type).then(|| frame.clone())
+                })
+                .await;
+            match fc {
+                Ok(fc) => sender.on_flow_control(&fc.data, clock::now())?,
+                // the sender reports its own timeout on the next poll
+                Err(Error::Timeout) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the answers to `RequestParameter`. Values the device does not
+    /// report, e.g. an unset `HwRev`, stay `None`.
+    pub async fn request_parameters(&self, device_type: u8, device_id: u8) -> Result<Parameters> {
+        let mut rx = self.subscribe();
+        self.send(device_type, device_id, &CanMessage::RequestParameter)
+            .await?;
+
+        let mut params = Parameters::default();
+        let mut receiver = Receiver::<MAX_TRANSFER_LEN>::new(0, 0);
+        let mut received = false;
+        while !params.is_complete() {
+            let frame = self
+                .wait_for(&mut rx, Instant::now() + self.timeout, |frame| {
+                    (frame.is_from(device_type, device_id) && !frame.remote).then(|| frame.clone())
+                })
+                .await;
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(Error::Timeout) if received => break,
+                Err(e) => return Err(e),
+            };
+            received = true;
+
+            if let Ok(CanMessage::LegacyCustomString(value)) = frame.decode() {
+                params.custom_string = Some(value.as_str().to_owned());
+                continue;
+            }
+            if frame.id.msg_type == CanMessageType::CustomString {
+                if let Some(data) = self.receive_segment(&mut receiver, &frame).await? {
+                    params.custom_string = Some(String::from_utf8_lossy(&data).into_owned());
+                }
+                continue;
+            }
+            match frame.decode() {
+                Ok(CanMessage::Uptime(minutes)) => params.uptime_minutes = Some(minutes),
+                Ok(CanMessage::DeviceUid0(uid)) => params.uid0 = Some(uid),
+                Ok(CanMessage::DeviceUid1(uid)) => params.uid1 = Some(uid),
+                Ok(CanMessage::HwRev(rev)) => params.hw_rev = Some(rev),
+                Ok(CanMessage::ApplicationVersion(version)) => {
+                    params.version = Some(version.as_str().to_owned())
+                }
+                _ => {}
+            }
+        }
+        Ok(params)
+    }
+
+    /// Feeds one frame of a segmented transfer, answering with flow control
+    async fn receive_segment<const N: usize>(
+        &self,
+        receiver: &mut Receiver<N>,
+        frame: &Frame,
+    ) -> Result<Option<Vec<u8>>> {
+        match receiver.on_frame(&frame.data, clock::now())? {
+            RxStatus::Pending => Ok(None),
+            RxStatus::SendFlowControl(data) | RxStatus::Rejected(data) => {
+                self.send_frame(&Frame {
+                    id: frame.id,
+                    data,
+                    remote: false,
+                })
+                .await?;
+                Ok(None)
+            }
+            RxStatus::Complete => Ok(receiver.data().map(<[u8]>::to_vec)),
+        }
+    }
+}
+
+impl<B: Bus> Drop for Gateway<B> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+fn is_response(frame: &Frame, device_type: u8, device_id: u8, msg_type: CanMessageType) -> bool {
+    frame.is_from(device_type, device_id) && !frame.remote && frame.id.msg_type == msg_type
+}
+
+async fn read_task<B: Bus>(bus: Arc<B>, frames: broadcast::Sender<Frame>) {
+    while let Ok(frame) = bus.recv().await {
+        // no subscribers is not an error
+        let _ = frames.send(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{SocketCan, VirtualBus};
+    use crate::image;
+    use crate::sim::SimDevice;
+    use crate::update::Uploader;
+    use cancomponents_core::error_report::Component;
+    use cancomponents_core::image_header::ImageHeader;
+    use cancomponents_core::relais_message::RelaisState;
+    use cancomponents_core::update::UpdateErrorCode;
+
+    fn sim() -> SimDevice {
+        let mut device = SimDevice::new(4, 17, 0x0011_2233_4455);
+        device.custom_string = "a rather long custom string".into();
+        device.version = "v1.4.2-7-g0123abc".into();
+        device.set_u8(CanMessageType::HwRev, 3);
+        device.set_u8(CanMessageType::RelaisMode, 2);
+        device
+    }
+
+    async fn exercise<B: Bus>(gateway: &Gateway<B>) {
+        gateway.ping(4, 17).await.unwrap();
+        assert!(matches!(gateway.ping(4, 18).await, Err(Error::Timeout)));
+        assert_eq!(
+            gateway.scan().await.unwrap(),
+            [Node {
+                device_type: 4,
+                device_id: 17
+            }]
+        );
+
+        assert_eq!(
+            gateway
+                .read_u8(4, 17, CanMessageType::RelaisMode)
+                .await
+                .unwrap(),
+            2
+        );
+
+        let params = gateway.request_parameters(4, 17).await.unwrap();
+        assert_eq!(params.uid0, Some(0x0011_2233_4455));
+        assert_eq!(params.hw_rev, Some(3));
+        assert_eq!(
+            params.custom_string.as_deref(),
+            Some("a rather long custom string")
+        );
+        assert_eq!(params.version.as_deref(), Some("v1.4.2-7"));
+
+        gateway
+            .write_string(4, 17, CanMessageType::CustomString, "stairs, ground floor")
+            .await
+            .unwrap();
+        assert_eq!(
+            gateway
+                .read_string(4, 17, CanMessageType::CustomString)
+                .await
+                .unwrap(),
+            "stairs, ground floor"
+        );
+        // older tools write the text unsegmented
+        gateway
+            .send(
+                4,
+                17,
+                &CanMessage::LegacyCustomString("cellar".try_into().unwrap()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            gateway
+                .read_string(4, 17, CanMessageType::CustomString)
+                .await
+                .unwrap(),
+            "cellar"
+        );
+        assert_eq!(
+            gateway
+                .read_string(4, 17, CanMessageType::ApplicationVersionString)
+                .await
+                .unwrap(),
+            "v1.4.2-7-g0123abc"
+        );
+
+        let firmware: Vec<u8> = (0..1000u32).map(|i| (i % 253) as u8).collect();
+        let image = image::build(&ImageHeader::new(&[4]).unwrap(), &firmware);
+        Uploader::new(gateway, 4, 17)
+            .upload(&image, |_| {})
+            .await
+            .unwrap();
+
+        // the slot does not exist, the refusal ends up in the log
+        assert!(Uploader::new(gateway, 4, 17).select(9).await.is_err());
+        let log = gateway.read_log(4, 17).await.unwrap();
+        let last = log.last().unwrap().report;
+        assert_eq!(last.component, Component::Ota);
+        assert_eq!(last.local_code, UpdateErrorCode::InvalidSlot as u8);
+
+        let map = RelaisMap {
+            expanders: [0x20, 0x21, 0, 0],
+            ..RelaisMap::BOARD_12
+        };
+        assert_eq!(
+            gateway.read_relais_map(4, 17).await.unwrap(),
+            RelaisMap::BOARD_12
+        );
+        gateway.write_relais_map(4, 17, &map).await.unwrap();
+        assert_eq!(gateway.read_relais_map(4, 17).await.unwrap(), map);
+
+        let timing = ShutterTiming {
+            up: embassy_time::Duration::from_millis(23_500),
+            down: embassy_time::Duration::from_millis(21_000),
+            turn: embassy_time::Duration::from_millis(1_400),
+        };
+        gateway
+            .write_shutter_timing(4, 17, 1, timing, 0)
+            .await
+            .unwrap();
+        let timings = gateway.read_shutter_timings(4, 17).await.unwrap();
+        assert_eq!(timings.len(), 6);
+        assert_eq!(timings[1], (1, 0, timing));
+        assert!(!timings[0].2.is_calibrated());
+        let dead_time = embassy_time::Duration::from_millis(800);
+        gateway
+            .write_shutter_dead_time(4, 17, dead_time)
+            .await
+            .unwrap();
+        assert_eq!(
+            gateway.read_shutter_dead_time(4, 17).await.unwrap(),
+            dead_time
+        );
+
+        let mut rx = gateway.subscribe();
+        let on = RelaisMessage {
+            num: 3,
+            state: RelaisState::On,
+            duration: embassy_time::Duration::from_millis(0),
+            bank: 0,
+            flags: 0,
+        };
+        gateway.relais(4, 17, on).await.unwrap();
+        let deadline = Instant::now() + DEFAULT_TIMEOUT;
+        let report = gateway
+            .wait_for(&mut rx, deadline, |frame| match frame.decode() {
+                Ok(CanMessage::RelaisState(status)) => Some(status),
+                _ => None,
+            })
+            .await
+            .unwrap();
+        assert_eq!((report.num, report.state), (3, RelaisState::On));
+        let states = gateway.read_relais_states(4, 17).await.unwrap();
+        assert_eq!(states.len(), 12);
+        assert_eq!(states[3].state, RelaisState::On);
+        assert_eq!(states[2].state, RelaisState::Off);
+        let shutters = gateway.read_shutter_states(4, 17).await.unwrap();
+        assert_eq!(shutters.len(), 6);
+        assert_eq!(shutters[0].position, None);
+        gateway
+            .write_power_on(4, 17, 2, PowerOn::Last, 0)
+            .await
+            .unwrap();
+        let power_on = gateway.read_power_on(4, 17).await.unwrap();
+        assert_eq!(power_on.len(), 12);
+        assert_eq!(power_on[2], (2, 0, PowerOn::Last));
+        assert_eq!(power_on[3], (3, 0, PowerOn::Off));
+        let limits = RelaisLimits {
+            max_on: embassy_time::Duration::from_secs(3600),
+            min_off: embassy_time::Duration::from_secs(60),
+        };
+        gateway.write_limits(4, 17, 1, limits, 0).await.unwrap();
+        let stored = gateway.read_limits(4, 17).await.unwrap();
+        assert_eq!(stored.len(), 12);
+        assert_eq!(stored[1], (1, 0, limits));
+        assert_eq!(stored[0], (0, 0, RelaisLimits::default()));
+        let stats = gateway.read_stats(4, 17).await.unwrap();
+        assert_eq!(stats.len(), 12);
+        assert_eq!(stats[3].2.cycles, 1);
+        gateway
+            .write_stats(4, 17, 3, RelaisStats::default(), 0)
+            .await
+            .unwrap();
+        let stats = gateway.read_stats(4, 17).await.unwrap();
+        assert_eq!(stats[3], (3, 0, RelaisStats::default()));
+        gateway.write_cycle_limit(4, 17, 50_000).await.unwrap();
+        assert_eq!(gateway.read_cycle_limit(4, 17).await.unwrap(), 50_000);
+
+        gateway.assign(4, 17, 5, 18).await.unwrap();
+        gateway.ping(5, 18).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_virtual_bus() {
+        let bus = VirtualBus::new();
+        tokio::spawn(sim().run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        exercise(&gateway).await;
+    }
+
+    /// Needs `ip link add dev vcan0 type vcan && ip link set up vcan0`
+    #[tokio::test]
+    async fn test_vcan() {
+        let (Ok(device_bus), Ok(gateway_bus)) =
+            (SocketCan::open("vcan0"), SocketCan::open("vcan0"))
+        else {
+            eprintln!("vcan0 not available, skipping");
+            return;
+        };
+        tokio::spawn(sim().run(device_bus));
+        let gateway = Gateway::new(gateway_bus);
+        exercise(&gateway).await;
+    }
+}
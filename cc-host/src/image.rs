@@ -0,0 +1,197 @@
+//! Preparing firmware images for upload: an
+//! [`ImageHeader`] in front, the [`signature`] trailer at the end.
+use cancomponents_core::compression::{frame_len, BLOCK_LEN, STORED};
+use cancomponents_core::crc32::crc32;
+use cancomponents_core::image_header::{ImageHeader, HEADER_LEN};
+use cancomponents_core::signature::{self, KEY_LEN, MAGIC, TRAILER_LEN};
+use ed25519_dalek::{Signer, SigningKey};
+use std::io;
+use std::path::Path;
+
+/// Puts `header` in front of the firmware built for the device
+pub fn build(header: &ImageHeader, firmware: &[u8]) -> Vec<u8> {
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(firmware);
+    image
+}
+
+/// What can be told about an image without the device's key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub header: Option<ImageHeader>,
+    /// Firmware bytes between header and trailer
+    pub firmware_len: usize,
+    pub signed: bool,
+    /// As sent with `FlashStart`
+    pub crc: u32,
+}
+
+pub fn inspect(image: &[u8]) -> ImageInfo {
+    let header = ImageHeader::from_bytes(image).ok();
+    let signed = image.len() > TRAILER_LEN && image.ends_with(&MAGIC);
+    let overhead =
+        if header.is_some() { HEADER_LEN } else { 0 } + if signed { TRAILER_LEN } else { 0 };
+    ImageInfo {
+        header,
+        firmware_len: image.len().saturating_sub(overhead),
+        signed,
+        crc: crc32(image),
+    }
+}
+
+/// Stream sent instead of `image` after `FlashCompressed`, see
+/// [`compression`](cancomponents_core::compression) for the format
+pub fn compress(image: &[u8]) -> Vec<u8> {
+    let header_len = image.len().min(HEADER_LEN);
+    let mut stream = image[..header_len].to_vec();
+    for block in image[header_len..].chunks(BLOCK_LEN) {
+        let compressed = lz4_flex::block::compress(block);
+        // firmware is mostly compressible, but not every block
+        let (flag, payload) = if compressed.len() < block.len() {
+            (0, &compressed[..])
+        } else {
+            (STORED, block)
+        };
+        let start = stream.len();
+        stream.extend_from_slice(&(payload.len() as u16 | flag).to_le_bytes());
+        stream.extend_from_slice(payload);
+        stream.resize(start + frame_len(start as u32, payload.len()), 0);
+    }
+    stream
+}
+
+/// New random secret key
+pub fn generate_key() -> io::Result<[u8; KEY_LEN]> {
+    let mut secret = [0u8; KEY_LEN];
+    getrandom::fill(&mut secret).map_err(io::Error::other)?;
+    Ok(secret)
+}
+
+/// Public key to embed in the firmware, see `CC_UPDATE_KEY` in its build script
+pub fn public_key(secret: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    SigningKey::from_bytes(secret).verifying_key().to_bytes()
+}
+
+/// Appends the signature trailer to `firmware`
+pub fn sign(firmware: &[u8], secret: &[u8; KEY_LEN]) -> Vec<u8> {
+    let digest = signature::digest(firmware);
+    let signature = SigningKey::from_bytes(secret).sign(&digest);
+    let mut image = firmware.to_vec();
+    image.extend_from_slice(&signature.to_bytes());
+    image.extend_from_slice(&MAGIC);
+    image
+}
+
+/// Reads a key written by [`write_key`]
+pub fn read_key(path: &Path) -> io::Result<[u8; KEY_LEN]> {
+    let hex = std::fs::read_to_string(path)?;
+    parse_hex(hex.trim()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: expected {KEY_LEN} bytes in hex", path.display()),
+        )
+    })
+}
+
+/// Writes a key in hex, the format the firmware build script expects
+pub fn write_key(path: &Path, key: &[u8; KEY_LEN]) -> io::Result<()> {
+    std::fs::write(path, format!("{}\n", to_hex(key)))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; KEY_LEN]> {
+    if hex.len() != 2 * KEY_LEN || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cancomponents_core::compression::Decoder;
+    use cancomponents_core::signature::SignatureError;
+    use cancomponents_core::update::CHUNK_LEN;
+
+    #[test]
+    fn test_sign() {
+        let secret = generate_key().unwrap();
+        let key = public_key(&secret);
+        let firmware: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+
+        let image = sign(&firmware, &secret);
+        assert_eq!(&image[..firmware.len()], firmware);
+        assert_eq!(signature::verify(&image, &key), Ok(()));
+
+        let other = public_key(&generate_key().unwrap());
+        assert_eq!(
+            signature::verify(&image, &other),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn test_build_and_inspect() {
+        let mut header = ImageHeader::new(&[4]).unwrap();
+        header.hw_rev_max = 2;
+        let firmware = [0xE9; 100];
+        let image = build(&header, &firmware);
+
+        let info = inspect(&image);
+        assert_eq!(info.header, Some(header));
+        assert_eq!(info.firmware_len, 100);
+        assert!(!info.signed);
+
+        let signed = sign(&image, &generate_key().unwrap());
+        let info = inspect(&signed);
+        assert_eq!(info.firmware_len, 100);
+        assert!(info.signed);
+        assert_eq!(info.crc, crc32(&signed));
+
+        assert_eq!(inspect(&firmware).header, None);
+    }
+
+    #[test]
+    fn test_compress() {
+        // compressible code followed by noise, the last block is short
+        let mut firmware: Vec<u8> = (0..6000).map(|i| (i % 16) as u8).collect();
+        let mut seed = 1u32;
+        firmware.extend((0..5000).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }));
+        let image = build(&ImageHeader::new(&[4]).unwrap(), &firmware);
+        let stream = compress(&image);
+        assert_eq!(&stream[..HEADER_LEN], &image[..HEADER_LEN]);
+        assert!(stream.len() < image.len());
+        assert_eq!(stream.len() % 64, 0);
+
+        // fed in `FlashWrite` chunks like on the device
+        let mut decoder = Decoder::new();
+        decoder.reset(HEADER_LEN as u32);
+        let mut expanded = image[..HEADER_LEN].to_vec();
+        for chunk in stream[HEADER_LEN..].chunks(CHUNK_LEN) {
+            if decoder.push(chunk).unwrap() {
+                let len = (image.len() - expanded.len()).min(BLOCK_LEN);
+                expanded.extend_from_slice(decoder.decode(len).unwrap());
+            }
+        }
+        assert_eq!(expanded, image);
+        assert_eq!(decoder.start() as usize, stream.len());
+    }
+
+    #[test]
+    fn test_hex() {
+        let key = [0xAB; KEY_LEN];
+        assert_eq!(parse_hex(&to_hex(&key)), Some(key));
+        assert_eq!(parse_hex("ab"), None);
+        assert_eq!(parse_hex(&"zz".repeat(KEY_LEN)), None);
+    }
+}
@@ -0,0 +1,8 @@
+pub mod bus;
+pub mod clock;
+pub mod error;
+pub mod gateway;
+pub mod image;
+pub mod multicast;
+pub mod sim;
+pub mod update;
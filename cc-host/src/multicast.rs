@@ -0,0 +1,365 @@
+//! Firmware upload to many identical devices with a single transfer, see
+//! [`cancomponents_core::update`] for the protocol
+use crate::bus::{Bus, Frame};
+use crate::error::{Error, Result};
+use crate::gateway::{Gateway, Node};
+use crate::update::{
+    Uploader, DEFAULT_BOOT_TIMEOUT, DEFAULT_PACING, DEFAULT_RETRIES, ERASE_TIMEOUT,
+};
+use cancomponents_core::can_message::{CanMessage, Payload};
+use cancomponents_core::crc32::crc32;
+use cancomponents_core::error_report::Component;
+use cancomponents_core::image_header::HEADER_LEN;
+use cancomponents_core::update::{BootState, ACK_INTERVAL, MULTICAST_CHUNK_LEN};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Uploads an image to several devices at once.
+///
+/// Every member gets `FlashStart` and the header on its own, then the image is
+/// sent once as `FlashChunk` to the broadcast address. Chunks a member missed
+/// are sent again until all members have the whole image or no member makes
+/// progress for [`with_retries`](Self::with_retries) rounds. Afterwards each
+/// member is verified and restarted like by [`Uploader`].
+///
+/// A member that fails drops out of the session, the others continue.
+pub struct MulticastUploader<'a, B: Bus> {
+    gateway: &'a Gateway<B>,
+    members: Vec<Node>,
+    pacing: Duration,
+    retries: usize,
+    silence: bool,
+    boot_timeout: Duration,
+}
+
+impl<'a, B: Bus> MulticastUploader<'a, B> {
+    pub fn new(gateway: &'a Gateway<B>, members: &[Node]) -> Self {
+        Self {
+            gateway,
+            members: members.to_vec(),
+            pacing: DEFAULT_PACING,
+            retries: DEFAULT_RETRIES,
+            silence: true,
+            boot_timeout: DEFAULT_BOOT_TIMEOUT,
+        }
+    }
+
+    pub fn with_pacing(mut self, pacing: Duration) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Rounds of retransmission without progress before giving up
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Whether the devices outside the session are silenced during the upload
+    pub fn with_silence(mut self, silence: bool) -> Self {
+        self.silence = silence;
+        self
+    }
+
+    pub fn with_boot_timeout(mut self, timeout: Duration) -> Self {
+        self.boot_timeout = timeout;
+        self
+    }
+
+    /// Writes `image` to all members, then verifies and restarts each of them.
+    /// Returns one result per member in the order given to [`new`](Self::new),
+    /// errors of the bus end the whole session. `progress` is called with the
+    /// bytes sent in the first pass.
+    pub async fn upload(
+        &self,
+        image: &[u8],
+        mut progress: impl FnMut(usize),
+    ) -> Result<Vec<Result<()>>> {
+        if self.silence {
+            self.set_silence(true).await?;
+        }
+        let result = self.try_upload(image, &mut progress).await;
+        if self.silence {
+            let released = self.set_silence(false).await;
+            return released.and(result);
+        }
+        result
+    }
+
+    async fn try_upload(
+        &self,
+        image: &[u8],
+        progress: &mut impl FnMut(usize),
+    ) -> Result<Vec<Result<()>>> {
+        if image.len() <= HEADER_LEN {
+            return Err(Error::UnexpectedResponse);
+        }
+        let mut results: Vec<Result<()>> = self.members.iter().map(|_| Ok(())).collect();
+
+        // error reports about the header arrive before the join
+        let mut rx = self.gateway.subscribe();
+        let first = self.start(image, &mut results).await?;
+        self.join(&mut rx, &mut results).await?;
+
+        let chunks = image.len().div_ceil(MULTICAST_CHUNK_LEN) as u32;
+        let mut sent = 0;
+        for index in first..chunks {
+            self.send_chunk(image, index).await?;
+            sent = (index as usize + 1) * MULTICAST_CHUNK_LEN;
+            if sent.is_multiple_of(ACK_INTERVAL as usize) {
+                progress(sent);
+            }
+        }
+        progress(sent.min(image.len()));
+
+        self.repair(image, &mut results).await?;
+
+        for i in active(&results) {
+            results[i] = match self.uploader(self.members[i]).verify().await {
+                Ok((_, true)) => Ok(()),
+                Ok((crc, false)) => Err(Error::Verify {
+                    expected: crc32(image),
+                    actual: crc,
+                }),
+                Err(e) => Err(e),
+            };
+        }
+        for i in active(&results) {
+            results[i] = self.restart(self.members[i]).await;
+        }
+        Ok(results)
+    }
+
+    /// Starts the update on every member, returns the first chunk one of them
+    /// is missing
+    async fn start(&self, image: &[u8], results: &mut [Result<()>]) -> Result<u32> {
+        let header = Payload::from_slice(&image[..HEADER_LEN]).unwrap();
+        let mut first = image.len();
+        for i in active(results) {
+            let node = self.members[i];
+            match self.uploader(node).start(image).await {
+                Ok(resume) if resume < HEADER_LEN => {
+                    // answered by `FlashJoin` or by the error report
+                    self.gateway
+                        .send(
+                            node.device_type,
+                            node.device_id,
+                            &CanMessage::FlashWrite(header.clone()),
+                        )
+                        .await?;
+                    first = HEADER_LEN;
+                }
+                Ok(resume) => first = first.min(resume),
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(e) => results[i] = Err(e),
+            }
+        }
+        Ok((first / MULTICAST_CHUNK_LEN) as u32)
+    }
+
+    /// Sends `FlashJoin` to all members first, so they erase at the same time
+    async fn join(
+        &self,
+        rx: &mut broadcast::Receiver<Frame>,
+        results: &mut [Result<()>],
+    ) -> Result<()> {
+        let mut pending = active(results);
+        for &i in &pending {
+            let node = self.members[i];
+            self.gateway
+                .send(node.device_type, node.device_id, &CanMessage::FlashJoin)
+                .await?;
+        }
+
+        let deadline = Instant::now() + ERASE_TIMEOUT;
+        while !pending.is_empty() {
+            let answer = self
+                .gateway
+                .wait_for(rx, deadline, |frame| {
+                    if frame.remote {
+                        return None;
+                    }
+                    let i = *pending.iter().find(|&&i| {
+                        let node = self.members[i];
+                        frame.is_from(node.device_type, node.device_id)
+                    })?;
+                    match frame.decode().ok()? {
+                        CanMessage::FlashJoin => Some((i, Ok(()))),
+                        CanMessage::DeviceError(report)
+                            if matches!(report.component, Component::Ota | Component::Update) =>
+                        {
+                            Some((i, Err(Error::Device(report))))
+                        }
+                        _ => None,
+                    }
+                })
+                .await;
+            match answer {
+                Ok((i, result)) => {
+                    pending.retain(|&p| p != i);
+                    results[i] = result;
+                }
+                Err(Error::Timeout) => {
+                    for i in pending.drain(..) {
+                        results[i] = Err(Error::Timeout);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the chunks the members report missing again
+    async fn repair(&self, image: &[u8], results: &mut [Result<()>]) -> Result<()> {
+        let total = image.len() as u32;
+        let mut best = 0;
+        let mut stalled = 0;
+        loop {
+            let mut missing = BTreeSet::new();
+            let mut received = 0;
+            let mut complete = true;
+            for i in active(results) {
+                match self.uploader(self.members[i]).gaps().await {
+                    Ok((chunks, written)) => {
+                        missing.extend(chunks);
+                        received += written as u64;
+                        complete &= written == total;
+                    }
+                    Err(Error::Io(e)) => return Err(Error::Io(e)),
+                    Err(e) => results[i] = Err(e),
+                }
+            }
+            if complete {
+                return Ok(());
+            }
+
+            if received > best {
+                best = received;
+                stalled = 0;
+            } else if stalled == self.retries {
+                // verify reports the members still missing chunks
+                return Ok(());
+            } else {
+                stalled += 1;
+            }
+            for index in missing {
+                self.send_chunk(image, index).await?;
+            }
+        }
+    }
+
+    /// Restarts a member and waits until it confirmed the new image
+    async fn restart(&self, node: Node) -> Result<()> {
+        let flash = self.uploader(node);
+        flash.restart().await?;
+        match flash.confirm().await?.state {
+            BootState::Confirmed | BootState::Valid => Ok(()),
+            _ => Err(Error::RolledBack),
+        }
+    }
+
+    async fn send_chunk(&self, image: &[u8], index: u32) -> Result<()> {
+        let pos = index as usize * MULTICAST_CHUNK_LEN;
+        let Some(chunk) = image.get(pos..) else {
+            return Ok(());
+        };
+        let mut data = [0xFF; MULTICAST_CHUNK_LEN];
+        let len = chunk.len().min(MULTICAST_CHUNK_LEN);
+        data[..len].copy_from_slice(&chunk[..len]);
+        if !self.pacing.is_zero() {
+            tokio::time::sleep(self.pacing).await;
+        }
+        self.gateway
+            .send(0, 0, &CanMessage::FlashChunk { index, data })
+            .await
+    }
+
+    fn uploader(&self, node: Node) -> Uploader<'a, B> {
+        Uploader::new(self.gateway, node.device_type, node.device_id)
+            .with_pacing(self.pacing)
+            .with_boot_timeout(self.boot_timeout)
+    }
+
+    /// Silences every device but the members, or releases all of them
+    async fn set_silence(&self, silence: bool) -> Result<()> {
+        self.gateway
+            .send(0, 0, &CanMessage::UpdateSilence(silence))
+            .await?;
+        if silence {
+            for node in &self.members {
+                self.gateway
+                    .send(
+                        node.device_type,
+                        node.device_id,
+                        &CanMessage::UpdateSilence(false),
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Members still in the session
+fn active(results: &[Result<()>]) -> Vec<usize> {
+    (0..results.len()).filter(|&i| results[i].is_ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::VirtualBus;
+    use crate::image;
+    use crate::sim::SimDevice;
+    use cancomponents_core::image_header::ImageHeader;
+    use cancomponents_core::update::UpdateErrorCode;
+
+    #[tokio::test]
+    async fn test_multicast_upload() {
+        let bus = VirtualBus::new();
+        let members = [17, 18, 19].map(|device_id| Node {
+            device_type: 4,
+            device_id,
+        });
+        for (node, loss) in members.iter().zip([None, Some(7), Some(2)]) {
+            let mut device = SimDevice::new(node.device_type, node.device_id, 1);
+            device.chunk_loss = loss;
+            tokio::spawn(device.run(bus.attach()));
+        }
+        // a sensor in the session and a relay board outside of it
+        let sensor = Node {
+            device_type: 5,
+            device_id: 17,
+        };
+        tokio::spawn(SimDevice::new(5, 17, 2).run(bus.attach()));
+        tokio::spawn(SimDevice::new(4, 20, 3).run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 20).await.unwrap();
+
+        let firmware: Vec<u8> = (0..3000).map(|i| (i * 7 % 251) as u8).collect();
+        let image = image::build(&ImageHeader::new(&[4]).unwrap(), &firmware);
+        let mut session = members.to_vec();
+        session.push(sensor);
+        let results = MulticastUploader::new(&gateway, &session)
+            .with_pacing(Duration::ZERO)
+            .upload(&image, |_| {})
+            .await
+            .unwrap();
+
+        for (node, result) in members.iter().zip(&results) {
+            assert!(result.is_ok(), "{node:?}: {result:?}");
+            let flash = Uploader::new(&gateway, node.device_type, node.device_id);
+            assert_eq!(flash.boot_status().await.unwrap().slot, Some(0));
+        }
+        match &results[3] {
+            Err(Error::Device(report)) => {
+                assert_eq!(report.local_code, UpdateErrorCode::Incompatible as u8)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        let bystander = Uploader::new(&gateway, 4, 20);
+        assert_eq!(bystander.boot_status().await.unwrap().slot, None);
+    }
+}
@@ -0,0 +1,908 @@
+//! Simulated device answering like the firmware's `can::dispatch`, used to test
+//! host tooling on a virtual bus or on `vcan0`.
+use crate::bus::{Bus, Frame};
+use crate::clock;
+use cancomponents_core::can_id::CanId;
+use cancomponents_core::can_message::{CanMessage, Payload};
+use cancomponents_core::can_message_type::CanMessageType;
+use cancomponents_core::chunk_map::{ChunkMap, WINDOW};
+use cancomponents_core::compression::{Decoder, BLOCK_LEN};
+use cancomponents_core::crc32;
+use cancomponents_core::error_report::{Component, ErrorCode, ErrorReport, LogEntry, Severity};
+use cancomponents_core::image_header::{ImageHeader, HEADER_LEN};
+use cancomponents_core::relais_manager::{DEFAULT_CYCLE_LIMIT, DEFAULT_DEAD_TIME, MIN_DEAD_TIME};
+use cancomponents_core::relais_map::RelaisMap;
+use cancomponents_core::relais_message::{
+    PowerOn, RelaisLimits, RelaisMessage, RelaisState, RelaisStats, RelaisStatus, ShutterStatus,
+};
+use cancomponents_core::shutter::ShutterTiming;
+use cancomponents_core::signature::{self, KEY_LEN};
+use cancomponents_core::transport::{Frame as Segment, Receiver, RxStatus, Sender};
+use cancomponents_core::update::{
+    BootState, UpdateErrorCode, ACK_INTERVAL, MAX_BOOT_ATTEMPTS, MAX_GAP_REPORTS, MAX_READ_LEN,
+    MULTICAST_CHUNK_LEN, RESUME_INTERVAL, SLOT_COUNT,
+};
+use std::collections::HashMap;
+use std::io;
+use std::time::Instant;
+
+const CUSTOM_STRING_LEN: usize = 64;
+const MAX_TRANSFER: usize = 256;
+/// Relays of the 12 channel board, see `RelaisMap::BOARD_12`
+const RELAIS: usize = 12;
+/// Shutters of the same board, the firmware answers with as many timings
+const SHUTTERS: usize = RELAIS / 2;
+/// Multicast images up to 1 MiB like a slot of the firmware
+const MAP_WORDS: usize = (0x10_0000 + HEADER_LEN).div_ceil(MULTICAST_CHUNK_LEN * WINDOW as usize);
+
+pub struct SimDevice {
+    pub device_type: u8,
+    pub device_id: u8,
+    pub uid: u64,
+    pub custom_string: String,
+    pub version: String,
+    /// Content of the OTA slots without the image header, erased bytes past the
+    /// end are not stored
+    pub slots: [Vec<u8>; SLOT_COUNT as usize],
+    /// `None` when running the factory image
+    pub running_slot: Option<u8>,
+    /// Reports a write error once this many bytes are flashed, for testing retries
+    pub fail_at: Option<u32>,
+    /// Boots of a new image that never reach the bus, for testing the rollback
+    pub boot_failures: u8,
+    /// Key for signed images like `UPDATE_KEY` in the firmware, `None` accepts
+    /// unsigned images
+    pub key: Option<[u8; KEY_LEN]>,
+    /// Misses every n-th `FlashChunk`, for testing the retransmission
+    pub chunk_loss: Option<u32>,
+    pub shutter_timings: [ShutterTiming; SHUTTERS],
+    pub dead_time: embassy_time::Duration,
+    /// Switched by `Relais` and `Rollershutter`, timers are not simulated
+    pub relais_states: [RelaisState; RELAIS],
+    /// Only stored, the simulation never loses power
+    pub power_on: [PowerOn; RELAIS],
+    /// Only stored, the simulated relays never run into them
+    pub limits: [RelaisLimits; RELAIS],
+    /// Only cycles are counted, the on-time stays as written
+    pub stats: [RelaisStats; RELAIS],
+    pub cycle_limit: u32,
+    /// Only stored, the relays stay numbered like `RelaisMap::BOARD_12`
+    pub relais_map: RelaisMap,
+    /// Every report sent, answered on `LogDownload` without a length limit
+    pub log: Vec<LogEntry>,
+    values: HashMap<CanMessageType, u8>,
+    custom_string_rx: Receiver<CUSTOM_STRING_LEN>,
+    relais_map_rx: Receiver<{ RelaisMap::LEN }>,
+    selected: Option<u8>,
+    /// Announced by `FlashCompressed` for the next `FlashStart`
+    compression: Option<(u32, u32)>,
+    flash: Option<Flash>,
+    /// Progress kept across restarts, like `Key::UpdateState` in the firmware
+    saved: Option<Flash>,
+    received: Box<ChunkMap<MAP_WORDS>>,
+    chunks_seen: u32,
+    decoder: Box<Decoder>,
+    /// Slot activated by a successful verify, booted on restart
+    boot_slot: Option<u8>,
+    boot_state: BootState,
+    boot_attempts: u8,
+    silent: bool,
+    boot: Instant,
+}
+
+/// Update in progress, like `Image` in the firmware
+#[derive(Clone, Copy)]
+struct Flash {
+    slot: u8,
+    size: u32,
+    crc: u32,
+    written: u32,
+    header: [u8; HEADER_LEN],
+    /// Joined a multicast update, `written` counts the chunks without a gap
+    multicast: bool,
+    /// Decompressed image of a compressed upload, the other fields count the stream
+    expanded: Option<Expanded>,
+}
+
+/// Like `Expanded` in the firmware
+#[derive(Clone, Copy)]
+struct Expanded {
+    size: u32,
+    crc: u32,
+    /// Decompressed bytes including the header, always whole frames
+    written: u32,
+    /// End of the last complete frame in the stream
+    stream: u32,
+    stream_crc: u32,
+}
+
+impl Flash {
+    fn is_same(&self, other: &Flash) -> bool {
+        let expanded = |flash: &Flash| flash.expanded.map(|e| (e.size, e.crc));
+        self.slot == other.slot
+            && self.size == other.size
+            && self.crc == other.crc
+            && expanded(self) == expanded(other)
+    }
+
+    /// Where the host continues after an interruption
+    fn resume_point(mut self) -> Self {
+        self.multicast = false;
+        match self.expanded.as_mut() {
+            None if self.written == self.size => {}
+            None => self.written -= self.written % ACK_INTERVAL,
+            // only whole frames are decompressed, saved together with `stream`
+            Some(e) => self.written = e.stream,
+        }
+        self
+    }
+}
+
+impl SimDevice {
+    pub fn new(device_type: u8, device_id: u8, uid: u64) -> Self {
+        Self {
+            device_type,
+            device_id,
+            uid,
+            custom_string: String::new(),
+            version: String::from("sim"),
+            slots: Default::default(),
+            running_slot: None,
+            fail_at: None,
+            boot_failures: 0,
+            key: None,
+            chunk_loss: None,
+            shutter_timings: Default::default(),
+            dead_time: DEFAULT_DEAD_TIME,
+            relais_states: [RelaisState::Off; RELAIS],
+            power_on: [PowerOn::Off; RELAIS],
+            limits: [RelaisLimits::default(); RELAIS],
+            stats: [RelaisStats::default(); RELAIS],
+            cycle_limit: DEFAULT_CYCLE_LIMIT,
+            relais_map: RelaisMap::BOARD_12,
+            log: Vec::new(),
+            values: HashMap::new(),
+            custom_string_rx: Receiver::new(0, 0),
+            relais_map_rx: Receiver::new(0, 0),
+            selected: None,
+            compression: None,
+            flash: None,
+            saved: None,
+            received: Box::default(),
+            chunks_seen: 0,
+            decoder: Box::default(),
+            boot_slot: None,
+            boot_state: BootState::Valid,
+            boot_attempts: 0,
+            silent: false,
+            boot: Instant::now(),
+        }
+    }
+
+    /// Sets a single byte value such as `HwRev` or `RelaisMode`
+    pub fn set_u8(&mut self, key: CanMessageType, value: u8) {
+        self.values.insert(key, value);
+    }
+
+    pub fn u8_val(&self, key: CanMessageType) -> Option<u8> {
+        self.values.get(&key).copied()
+    }
+
+    pub async fn run<B: Bus>(mut self, bus: B) -> io::Result<()> {
+        self.send(&bus, &CanMessage::Available { announce: true })
+            .await?;
+        loop {
+            let frame = bus.recv().await?;
+            if self.accepts(&frame) {
+                self.handle(&bus, &frame).await?;
+            }
+        }
+    }
+
+    /// Frames for this device or the broadcast address
+    pub fn accepts(&self, frame: &Frame) -> bool {
+        let id = frame.id;
+        (id.device_type == self.device_type
+            && (id.device_id == self.device_id || id.device_id == 0))
+            || (id.device_type == 0 && id.device_id == 0)
+    }
+
+    async fn handle<B: Bus>(&mut self, bus: &B, frame: &Frame) -> io::Result<()> {
+        use CanMessageType as T;
+        let Ok(msg) = frame.decode() else {
+            return Ok(());
+        };
+        match msg {
+            CanMessage::Ping => self.send(bus, &CanMessage::Ping).await?,
+            CanMessage::Available { .. } => {
+                self.send(bus, &CanMessage::Available { announce: false })
+                    .await?
+            }
+            CanMessage::Restart => self.restart(bus).await?,
+            CanMessage::RequestParameter => self.parameters(bus).await?,
+            CanMessage::Request(T::Uptime) => self.send(bus, &self.uptime()).await?,
+            CanMessage::Request(T::DeviceUid0) => {
+                self.send(bus, &CanMessage::DeviceUid0(self.uid)).await?
+            }
+            CanMessage::Request(T::DeviceUid1) => {
+                self.send(bus, &CanMessage::DeviceUid1(self.uid)).await?
+            }
+            CanMessage::Request(T::CustomString) => {
+                let data = self.custom_string.clone();
+                self.send_segmented(bus, T::CustomString, data.as_bytes())
+                    .await?
+            }
+            CanMessage::Request(T::ApplicationVersionString) => {
+                let data = self.version.clone();
+                self.send_segmented(bus, T::ApplicationVersionString, data.as_bytes())
+                    .await?
+            }
+            CanMessage::Request(T::LogDownload) => {
+                let data: Vec<u8> = self.log.iter().flat_map(LogEntry::to_bytes).collect();
+                self.send_segmented(bus, T::LogDownload, &data).await?
+            }
+            CanMessage::Request(T::RelaisMap) => {
+                let data = self.relais_map.to_bytes();
+                self.send_segmented(bus, T::RelaisMap, &data).await?
+            }
+            CanMessage::Request(T::FlashProgress) => self.send_progress(bus).await?,
+            CanMessage::Request(T::FlashSelect) => {
+                let slot = self.target_slot();
+                self.send(bus, &CanMessage::FlashSelect { slot }).await?
+            }
+            CanMessage::Request(T::FlashVerify) => self.flash_verify(bus).await?,
+            CanMessage::Request(T::BootStatus) => self.send_boot_status(bus).await?,
+            CanMessage::Request(T::FlashGaps) => self.send_gaps(bus).await?,
+            CanMessage::Request(T::ShutterTiming) => {
+                for (num, timing) in self.shutter_timings.into_iter().enumerate() {
+                    let msg = CanMessage::ShutterTiming {
+                        num: num as u8,
+                        bank: 0,
+                        up: timing.up,
+                        down: timing.down,
+                    };
+                    self.send(bus, &msg).await?;
+                }
+            }
+            CanMessage::Request(T::ShutterDeadTime) => {
+                let msg = CanMessage::ShutterDeadTime(self.dead_time);
+                self.send(bus, &msg).await?
+            }
+            CanMessage::Request(T::ShutterSlatTiming) => {
+                for (num, timing) in self.shutter_timings.into_iter().enumerate() {
+                    let msg = CanMessage::ShutterSlatTiming {
+                        num: num as u8,
+                        bank: 0,
+                        turn: timing.turn,
+                    };
+                    self.send(bus, &msg).await?;
+                }
+            }
+            CanMessage::Request(T::RelaisState) => {
+                for num in 0..RELAIS {
+                    self.send(bus, &self.relais_status(num)).await?;
+                }
+            }
+            CanMessage::Request(T::RollershutterState) => {
+                for num in 0..SHUTTERS {
+                    self.send(bus, &self.shutter_status(num)).await?;
+                }
+            }
+            CanMessage::Request(T::RelaisPowerOn) => {
+                for (num, power_on) in self.power_on.into_iter().enumerate() {
+                    let msg = CanMessage::RelaisPowerOn {
+                        num: num as u8,
+                        bank: 0,
+                        power_on,
+                    };
+                    self.send(bus, &msg).await?;
+                }
+            }
+            CanMessage::Request(T::RelaisStats) => {
+                for (num, stats) in self.stats.into_iter().enumerate() {
+                    let msg = CanMessage::RelaisStats {
+                        num: num as u8,
+                        bank: 0,
+                        stats,
+                    };
+                    self.send(bus, &msg).await?;
+                }
+            }
+            CanMessage::Request(T::RelaisCycleLimit) => {
+                let msg = CanMessage::RelaisCycleLimit(self.cycle_limit);
+                self.send(bus, &msg).await?
+            }
+            CanMessage::Request(T::RelaisLimits) => {
+                for (num, limits) in self.limits.into_iter().enumerate() {
+                    let msg = CanMessage::RelaisLimits {
+                        num: num as u8,
+                        bank: 0,
+                        limits,
+                    };
+                    self.send(bus, &msg).await?;
+                }
+            }
+            CanMessage::Request(key) => {
+                if let Some(value) = self.u8_val(key) {
+                    self.send_raw(bus, key, &[value]).await?;
+                }
+            }
+            CanMessage::LegacyCustomString(value) => self.custom_string = value.as_str().into(),
+            CanMessage::CustomString(_) => {
+                match self.custom_string_rx.on_frame(&frame.data, clock::now()) {
+                    Ok(RxStatus::SendFlowControl(fc)) | Ok(RxStatus::Rejected(fc)) => {
+                        self.send_raw(bus, T::CustomString, &fc).await?
+                    }
+                    Ok(RxStatus::Complete) => {
+                        let data = self.custom_string_rx.data().unwrap_or_default();
+                        self.custom_string = String::from_utf8_lossy(data).into_owned();
+                    }
+                    Ok(RxStatus::Pending) | Err(_) => {}
+                }
+            }
+            CanMessage::RelaisMap(_) => {
+                match self.relais_map_rx.on_frame(&frame.data, clock::now()) {
+                    Ok(RxStatus::SendFlowControl(fc)) | Ok(RxStatus::Rejected(fc)) => {
+                        self.send_raw(bus, T::RelaisMap, &fc).await?
+                    }
+                    Ok(RxStatus::Complete) => {
+                        let data = self.relais_map_rx.data().unwrap_or_default();
+                        if let Ok(map) = RelaisMap::from_bytes(data) {
+                            self.relais_map = map;
+                        }
+                    }
+                    Ok(RxStatus::Pending) | Err(_) => {}
+                }
+            }
+            CanMessage::DeviceIdType { id, device_type } => {
+                self.device_id = id;
+                self.device_type = device_type & 0x3F;
+                self.restart(bus).await?;
+            }
+            CanMessage::UpdateSilence(silent) => self.silent = silent,
+            CanMessage::FlashStart { size, crc } => {
+                let slot = self.target_slot();
+                if size <= HEADER_LEN as u32 || Some(slot) == self.running_slot {
+                    self.flash = None;
+                    self.report(bus, UpdateErrorCode::Begin).await?
+                } else {
+                    let expanded = self.compression.take().map(|(size, crc)| Expanded {
+                        size,
+                        crc,
+                        written: 0,
+                        stream: 0,
+                        stream_crc: 0,
+                    });
+                    let flash = self.resume(Flash {
+                        slot,
+                        size,
+                        crc,
+                        written: 0,
+                        header: [0xFF; HEADER_LEN],
+                        multicast: false,
+                        expanded,
+                    });
+                    if let Some(e) = flash.expanded {
+                        self.decoder.reset(e.stream);
+                    }
+                    self.flash = Some(flash);
+                    if flash.written == 0 {
+                        self.saved = self.flash;
+                    }
+                    self.send_progress(bus).await?
+                }
+            }
+            CanMessage::FlashCompressed { size, crc } => {
+                if size <= HEADER_LEN as u32 {
+                    self.compression = None;
+                    self.report(bus, UpdateErrorCode::Begin).await?
+                } else {
+                    self.compression = Some((size, crc));
+                    self.send(bus, &CanMessage::FlashCompressed { size, crc })
+                        .await?
+                }
+            }
+            CanMessage::FlashWrite(chunk) => self.flash_write(bus, &chunk).await?,
+            CanMessage::FlashJoin => self.flash_join(bus).await?,
+            CanMessage::FlashChunk { index, data } => self.flash_chunk(index, &data),
+            CanMessage::FlashSelect { slot } => {
+                if self.is_writable(slot) {
+                    self.selected = Some(slot);
+                    self.flash = None;
+                    self.saved = None;
+                    self.send(bus, &CanMessage::FlashSelect { slot }).await?
+                } else {
+                    self.report(bus, UpdateErrorCode::InvalidSlot).await?
+                }
+            }
+            CanMessage::FlashErase { slot } => {
+                if self.is_writable(slot) {
+                    self.slots[slot as usize].clear();
+                    if self.flash.as_ref().is_some_and(|flash| flash.slot == slot) {
+                        self.flash = None;
+                    }
+                    self.saved = None;
+                    self.send(bus, &CanMessage::FlashErase { slot }).await?
+                } else {
+                    self.report(bus, UpdateErrorCode::InvalidSlot).await?
+                }
+            }
+            CanMessage::FlashRead { offset, len } => self.flash_read(bus, offset, len).await?,
+            CanMessage::BootConfirm => {
+                if self.boot_state == BootState::Pending {
+                    self.boot_state = BootState::Confirmed;
+                }
+                self.send_boot_status(bus).await?
+            }
+            CanMessage::Relais(msg) if self.switch(msg) => {
+                self.send(bus, &self.relais_status(msg.num)).await?
+            }
+            CanMessage::Rollershutter(msg) if self.switch(msg) => {
+                self.send(bus, &self.shutter_status(msg.num)).await?
+            }
+            CanMessage::ShutterTiming { num, up, down, .. } => {
+                if let Some(timing) = self.shutter_timings.get_mut(num as usize) {
+                    timing.up = up;
+                    timing.down = down;
+                }
+            }
+            CanMessage::ShutterDeadTime(dead_time) => self.dead_time = dead_time.max(MIN_DEAD_TIME),
+            CanMessage::RelaisStats { num, stats, .. } => {
+                if let Some(stored) = self.stats.get_mut(num as usize) {
+                    *stored = stats;
+                }
+            }
+            CanMessage::RelaisCycleLimit(cycles) => self.cycle_limit = cycles,
+            CanMessage::RelaisLimits { num, limits, .. } => {
+                if let Some(stored) = self.limits.get_mut(num as usize) {
+                    *stored = limits;
+                }
+            }
+            CanMessage::RelaisPowerOn { num, power_on, .. } => {
+                if let Some(stored) = self.power_on.get_mut(num as usize) {
+                    *stored = power_on;
+                }
+            }
+            CanMessage::ShutterSlatTiming { num, turn, .. } => {
+                if let Some(timing) = self.shutter_timings.get_mut(num as usize) {
+                    timing.turn = turn;
+                }
+            }
+            CanMessage::Baudrate(value)
+            | CanMessage::HwRev(value)
+            | CanMessage::ExtensionMode(value)
+            | CanMessage::RelaisMode(value) => {
+                self.set_u8(frame.id.msg_type, value);
+                self.restart(bus).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Applies a command without its timer, true if the channel changed
+    fn switch(&mut self, msg: RelaisMessage) -> bool {
+        let Some(current) = self.relais_states.get_mut(msg.num) else {
+            return false;
+        };
+        let state = match msg.state {
+            RelaisState::Toggle if *current == RelaisState::Off => RelaisState::On,
+            RelaisState::Toggle => RelaisState::Off,
+            RelaisState::Pulse | RelaisState::DelayedOn | RelaisState::DelayedOff => return false,
+            state => state,
+        };
+        let from = core::mem::replace(current, state);
+        if from == RelaisState::Off && state != RelaisState::Off {
+            self.stats[msg.num].cycles += 1;
+        }
+        from != state
+    }
+
+    fn relais_status(&self, num: usize) -> CanMessage {
+        CanMessage::RelaisState(RelaisStatus {
+            num: num as u8,
+            state: self.relais_states[num],
+            remaining: embassy_time::Duration::from_millis(0),
+            bank: 0,
+        })
+    }
+
+    /// The position of a shutter is never known in the simulation
+    fn shutter_status(&self, num: usize) -> CanMessage {
+        CanMessage::RollershutterState(ShutterStatus {
+            num: num as u8,
+            state: self.relais_states[num],
+            position: None,
+            bank: 0,
+            tilt: None,
+            remaining: embassy_time::Duration::from_millis(0),
+        })
+    }
+
+    fn uptime(&self) -> CanMessage {
+        CanMessage::Uptime((self.boot.elapsed().as_secs() / 60) as u32)
+    }
+
+    async fn flash_write<B: Bus>(&mut self, bus: &B, chunk: &[u8]) -> io::Result<()> {
+        let Some(flash) = self.flash.as_mut() else {
+            return self.report(bus, UpdateErrorCode::NotStarted).await;
+        };
+        // in a multicast `written` only counts the chunks without a gap
+        if flash.multicast {
+            return self.report(bus, UpdateErrorCode::InvalidData).await;
+        }
+        let before = flash.written;
+        let len = chunk.len().min((flash.size - before) as usize);
+        if before == 0 {
+            // checked before anything is erased, not written to the slot
+            let hw_rev = self.values.get(&CanMessageType::HwRev).copied();
+            let checked = ImageHeader::from_bytes(chunk)
+                .and_then(|header| header.check(self.device_type, hw_rev.unwrap_or(0)));
+            if let Err(e) = checked {
+                self.flash = None;
+                self.saved = None;
+                return self
+                    .report_details(bus, UpdateErrorCode::Incompatible, &[e as u8])
+                    .await;
+            }
+            flash.header.copy_from_slice(&chunk[..HEADER_LEN]);
+            if let Some(e) = flash.expanded.as_mut() {
+                e.written = HEADER_LEN as u32;
+                e.stream = HEADER_LEN as u32;
+                e.stream_crc = crc32::update(0, &flash.header);
+                self.decoder.reset(e.stream);
+            }
+        } else if let Some(e) = flash.expanded.as_mut() {
+            let decoded = match self.decoder.push(&chunk[..len]) {
+                Ok(false) => Ok(()),
+                Ok(true) => {
+                    let stream_crc = crc32::update(e.stream_crc, self.decoder.frame());
+                    let block_len = ((e.size - e.written) as usize).min(BLOCK_LEN);
+                    match self.decoder.decode(block_len) {
+                        Ok(block) if !block.is_empty() => {
+                            let start = e.written as usize - HEADER_LEN;
+                            let slot = &mut self.slots[flash.slot as usize];
+                            slot.resize(start, 0xFF);
+                            slot.extend_from_slice(block);
+                            e.written += block_len as u32;
+                            e.stream = before + len as u32;
+                            e.stream_crc = stream_crc;
+                            Ok(())
+                        }
+                        _ => Err(()),
+                    }
+                }
+                Err(_) => Err(()),
+            };
+            if decoded.is_err() {
+                self.flash = None;
+                return self.report(bus, UpdateErrorCode::Compression).await;
+            }
+        } else {
+            let start = before as usize - HEADER_LEN;
+            let slot = &mut self.slots[flash.slot as usize];
+            if slot.len() < start + len {
+                slot.resize(start + len, 0xFF);
+            }
+            slot[start..start + len].copy_from_slice(&chunk[..len]);
+        }
+        flash.written += len as u32;
+        let written = flash.written;
+
+        if self.fail_at.is_some_and(|at| written >= at) {
+            self.fail_at = None;
+            self.flash = None;
+            return self.report(bus, UpdateErrorCode::Write).await;
+        }
+        let done = written == flash.size;
+        let sector_done = match flash.expanded {
+            Some(e) => e.stream == written,
+            None => before / RESUME_INTERVAL != written / RESUME_INTERVAL,
+        };
+        if sector_done || done {
+            self.saved = Some(*flash);
+        }
+        if before / ACK_INTERVAL != written / ACK_INTERVAL || done {
+            self.send_progress(bus).await?;
+        }
+        Ok(())
+    }
+
+    /// Switches to multicast like `Update::join`
+    async fn flash_join<B: Bus>(&mut self, bus: &B) -> io::Result<()> {
+        let Some(flash) = self
+            .flash
+            .as_mut()
+            .filter(|f| f.written >= HEADER_LEN as u32)
+        else {
+            return self.report(bus, UpdateErrorCode::NotStarted).await;
+        };
+        // frames can only be decompressed in order
+        if flash.expanded.is_some() {
+            return self.report(bus, UpdateErrorCode::Compression).await;
+        }
+        let chunk_len = MULTICAST_CHUNK_LEN as u32;
+        if !self
+            .received
+            .reset(flash.size.div_ceil(chunk_len), flash.written / chunk_len)
+        {
+            return self.report(bus, UpdateErrorCode::Begin).await;
+        }
+        // the rest of the slot is erased, a started sector is kept
+        let first =
+            (flash.written as usize - HEADER_LEN).next_multiple_of(RESUME_INTERVAL as usize);
+        self.slots[flash.slot as usize].truncate(first);
+        flash.multicast = true;
+        self.send(bus, &CanMessage::FlashJoin).await
+    }
+
+    fn flash_chunk(&mut self, index: u32, data: &[u8]) {
+        let Some(flash) = self.flash.as_mut().filter(|f| f.multicast) else {
+            return;
+        };
+        self.chunks_seen += 1;
+        if self
+            .chunk_loss
+            .is_some_and(|n| self.chunks_seen.is_multiple_of(n))
+        {
+            return;
+        }
+        if !self.received.insert(index) {
+            return;
+        }
+        let pos = index as usize * MULTICAST_CHUNK_LEN;
+        let len = (flash.size as usize - pos).min(MULTICAST_CHUNK_LEN);
+        let start = pos - HEADER_LEN;
+        let slot = &mut self.slots[flash.slot as usize];
+        if slot.len() < start + len {
+            slot.resize(start + len, 0xFF);
+        }
+        slot[start..start + len].copy_from_slice(&data[..len]);
+
+        let before = flash.written;
+        flash.written = (self.received.contiguous() * MULTICAST_CHUNK_LEN as u32).min(flash.size);
+        let written = flash.written;
+        if before / RESUME_INTERVAL != written / RESUME_INTERVAL || written == flash.size {
+            self.saved = Some(*flash);
+        }
+    }
+
+    async fn send_gaps<B: Bus>(&mut self, bus: &B) -> io::Result<()> {
+        let Some(flash) = self.flash.as_ref() else {
+            return self.report(bus, UpdateErrorCode::NotStarted).await;
+        };
+        if flash.multicast {
+            for (base, missing) in self.received.gaps().take(MAX_GAP_REPORTS) {
+                self.send(bus, &CanMessage::FlashGaps { base, missing })
+                    .await?;
+            }
+        }
+        self.send_progress(bus).await
+    }
+
+    async fn flash_read<B: Bus>(&self, bus: &B, offset: u32, len: u8) -> io::Result<()> {
+        let slot = self
+            .flash
+            .as_ref()
+            .map_or(self.target_slot(), |flash| flash.slot);
+        let content = &self.slots[slot as usize];
+        let len = (len.min(MAX_READ_LEN) as u32).div_ceil(4) * 4;
+        for word in (offset..offset + len).step_by(4) {
+            let mut data = [0xFF; 4];
+            for (i, byte) in data.iter_mut().enumerate() {
+                if let Some(&b) = content.get(word as usize + i) {
+                    *byte = b;
+                }
+            }
+            self.send(bus, &CanMessage::FlashData { offset: word, data })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn flash_verify<B: Bus>(&mut self, bus: &B) -> io::Result<()> {
+        let Some(flash) = &self.flash else {
+            return self.report(bus, UpdateErrorCode::NotStarted).await;
+        };
+        // a compressed stream must have arrived complete and unchanged as well
+        let stream_valid = flash.written == flash.size
+            && flash
+                .expanded
+                .is_none_or(|e| e.stream == flash.size && e.stream_crc == flash.crc);
+        let (size, written, expected) = match flash.expanded {
+            Some(e) => (e.size, e.written, e.crc),
+            None => (flash.size, flash.written, flash.crc),
+        };
+        let mut content = flash.header.to_vec();
+        let slot = &self.slots[flash.slot as usize];
+        content.extend_from_slice(&slot[..(written as usize).saturating_sub(HEADER_LEN)]);
+        let crc = crc32::crc32(&content);
+        let valid = stream_valid && written == size && crc == expected;
+        let signed = match &self.key {
+            Some(key) => signature::verify(&content, key).is_ok(),
+            None => true,
+        };
+        if valid && !signed {
+            self.flash = None;
+            self.saved = None;
+            return self.report(bus, UpdateErrorCode::Signature).await;
+        }
+        if valid {
+            self.boot_slot = Some(flash.slot);
+            self.saved = None;
+        }
+        self.send(bus, &CanMessage::FlashVerify { crc, valid })
+            .await
+    }
+
+    /// State of an interrupted upload of the same image, otherwise `flash`
+    fn resume(&self, flash: Flash) -> Flash {
+        let same = |other: &Flash| other.is_same(&flash);
+        self.flash
+            .filter(same)
+            .or(self.saved.filter(same))
+            .map_or(flash, Flash::resume_point)
+    }
+
+    async fn send_progress<B: Bus>(&self, bus: &B) -> io::Result<()> {
+        let (written, total) = self
+            .flash
+            .as_ref()
+            .map_or((0, 0), |flash| (flash.written, flash.size));
+        self.send(bus, &CanMessage::FlashProgress { written, total })
+            .await
+    }
+
+    fn target_slot(&self) -> u8 {
+        self.selected.unwrap_or_else(|| {
+            self.running_slot
+                .map_or(0, |running| (running + 1) % SLOT_COUNT)
+        })
+    }
+
+    fn is_writable(&self, slot: u8) -> bool {
+        slot < SLOT_COUNT && Some(slot) != self.running_slot
+    }
+
+    async fn report<B: Bus>(&mut self, bus: &B, code: UpdateErrorCode) -> io::Result<()> {
+        self.report_details(bus, code, &[0, 0, 0]).await
+    }
+
+    async fn report_details<B: Bus>(
+        &mut self,
+        bus: &B,
+        code: UpdateErrorCode,
+        details: &[u8],
+    ) -> io::Result<()> {
+        let report = ErrorReport::new(
+            Component::Ota,
+            ErrorCode::Unknown,
+            Severity::RecoverableError,
+            code as u8,
+            details,
+        );
+        self.log.push(LogEntry {
+            uptime: self.boot.elapsed().as_secs() as u32,
+            report,
+        });
+        self.send(bus, &CanMessage::DeviceError(report)).await
+    }
+
+    /// Boots the slot activated by verify, a broken image is given up after
+    /// [`MAX_BOOT_ATTEMPTS`] boots like in the firmware
+    async fn restart<B: Bus>(&mut self, bus: &B) -> io::Result<()> {
+        self.boot = Instant::now();
+        self.silent = false;
+        self.flash = None;
+        self.selected = None;
+        (self.boot_state, self.boot_attempts) = match self.boot_slot.take() {
+            Some(_) if self.boot_failures >= MAX_BOOT_ATTEMPTS => {
+                (BootState::RolledBack, MAX_BOOT_ATTEMPTS)
+            }
+            Some(slot) => {
+                self.running_slot = Some(slot);
+                (BootState::Pending, self.boot_failures + 1)
+            }
+            None => (BootState::Valid, 0),
+        };
+        self.send(bus, &CanMessage::Available { announce: true })
+            .await?;
+
+        // the announcement went out, confirm and report like `update::boot_task`
+        if self.boot_state == BootState::Pending {
+            self.boot_state = BootState::Confirmed;
+        }
+        if self.boot_state != BootState::Valid {
+            self.send_boot_status(bus).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_boot_status<B: Bus>(&self, bus: &B) -> io::Result<()> {
+        let msg = CanMessage::BootStatus {
+            slot: self.running_slot,
+            state: self.boot_state,
+            attempts: self.boot_attempts,
+        };
+        self.send(bus, &msg).await
+    }
+
+    async fn parameters<B: Bus>(&mut self, bus: &B) -> io::Result<()> {
+        self.send(bus, &self.uptime()).await?;
+        self.send(bus, &CanMessage::DeviceUid0(self.uid)).await?;
+        self.send(bus, &CanMessage::DeviceUid1(self.uid)).await?;
+        let data = self.custom_string.clone();
+        self.send_segmented(bus, CanMessageType::CustomString, data.as_bytes())
+            .await?;
+        if let Some(rev) = self.u8_val(CanMessageType::HwRev) {
+            self.send(bus, &CanMessage::HwRev(rev)).await?;
+        }
+        let mut len = self.version.len().min(8);
+        while !self.version.is_char_boundary(len) {
+            len -= 1;
+        }
+        let version = heapless::String::try_from(&self.version[..len]).unwrap();
+        self.send(bus, &CanMessage::ApplicationVersion(version))
+            .await
+    }
+
+    /// Sends `msg` unless silenced by `UpdateSilence`
+    pub async fn send<B: Bus>(&self, bus: &B, msg: &CanMessage) -> io::Result<()> {
+        if self.silent {
+            return Ok(());
+        }
+        bus.send(&Frame::new(self.device_type, self.device_id, msg))
+            .await
+    }
+
+    pub async fn send_raw<B: Bus>(
+        &self,
+        bus: &B,
+        msg_type: CanMessageType,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if self.silent {
+            return Ok(());
+        }
+        bus.send(&Frame {
+            id: CanId::new(self.device_type, self.device_id, msg_type),
+            data: Payload::from_slice(data).unwrap(),
+            remote: false,
+        })
+        .await
+    }
+
+    /// Runs a segmented transfer, other frames arriving meanwhile are dropped
+    async fn send_segmented<B: Bus>(
+        &self,
+        bus: &B,
+        msg_type: CanMessageType,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let Ok(mut sender) = Sender::<MAX_TRANSFER>::new(data) else {
+            return Ok(());
+        };
+        while !sender.is_done() {
+            match sender.poll(clock::now()) {
+                Ok(Some(frame)) => self.send_raw(bus, msg_type, &frame).await?,
+                Ok(None) => {
+                    let Some(next) = sender.next_deadline() else {
+                        break;
+                    };
+                    let deadline = clock::deadline(next).into();
+                    if let Ok(frame) = tokio::time::timeout_at(deadline, bus.recv()).await {
+                        let frame = frame?;
+                        if self.accepts(&frame)
+                            && frame.id.msg_type == msg_type
+                            && Segment::is_flow_control(&frame.data)
+                        {
+                            let _ = sender.on_flow_control(&frame.data, clock::now());
+                        }
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,760 @@
+//! Firmware upload as expected by the firmware's `update::Update`, see
+//! [`cancomponents_core::update`] for the protocol
+use crate::bus::{Bus, Frame};
+use crate::error::{Error, Result};
+use crate::gateway::Gateway;
+use crate::image;
+use cancomponents_core::can_message::{CanMessage, Payload};
+use cancomponents_core::can_message_type::CanMessageType;
+use cancomponents_core::chunk_map::WINDOW;
+use cancomponents_core::crc32::crc32;
+use cancomponents_core::error_report::Component;
+use cancomponents_core::update::{
+    BootState, UpdateErrorCode, ACK_INTERVAL, CHUNK_LEN, MAX_READ_LEN,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Gap between two chunks of a block, keeps the receive queue of the device
+/// from overflowing
+pub const DEFAULT_PACING: Duration = Duration::from_millis(1);
+pub const DEFAULT_RETRIES: usize = 3;
+/// Time the device needs to read back a whole slot, or to restart
+pub const DEFAULT_BOOT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Erasing a whole slot takes several seconds
+pub const ERASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Outcome of the last update as reported by the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootStatus {
+    /// `None` when running the factory image
+    pub slot: Option<u8>,
+    pub state: BootState,
+    /// Boots of the new image until it was confirmed or given up
+    pub attempts: u8,
+}
+
+/// Uploads an image to a single device and gives access to the other flash
+/// commands.
+///
+/// During [`upload`](Self::upload) the other devices are silenced with
+/// `UpdateSilence`. A failed attempt, reported by the device or noticed by a
+/// missing acknowledgement, starts over with `FlashStart`.
+pub struct Uploader<'a, B: Bus> {
+    gateway: &'a Gateway<B>,
+    device_type: u8,
+    device_id: u8,
+    pacing: Duration,
+    retries: usize,
+    silence: bool,
+    slot: Option<u8>,
+    boot_timeout: Duration,
+    compress: bool,
+}
+
+impl<'a, B: Bus> Uploader<'a, B> {
+    pub fn new(gateway: &'a Gateway<B>, device_type: u8, device_id: u8) -> Self {
+        Self {
+            gateway,
+            device_type,
+            device_id,
+            pacing: DEFAULT_PACING,
+            retries: DEFAULT_RETRIES,
+            silence: true,
+            slot: None,
+            boot_timeout: DEFAULT_BOOT_TIMEOUT,
+            compress: false,
+        }
+    }
+
+    pub fn with_pacing(mut self, pacing: Duration) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Whether the other devices are silenced during the upload
+    pub fn with_silence(mut self, silence: bool) -> Self {
+        self.silence = silence;
+        self
+    }
+
+    /// Slot to upload to, by default the device picks the one not running
+    pub fn with_slot(mut self, slot: u8) -> Self {
+        self.slot = Some(slot);
+        self
+    }
+
+    pub fn with_boot_timeout(mut self, timeout: Duration) -> Self {
+        self.boot_timeout = timeout;
+        self
+    }
+
+    /// Sends the image as compressed stream, see [`image::compress`]. Progress
+    /// then counts the bytes of the stream.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Writes and verifies `image`, then waits until the device restarted into
+    /// it. `progress` is called with the number of acknowledged bytes.
+    pub async fn upload(&self, image: &[u8], mut progress: impl FnMut(usize)) -> Result<()> {
+        if self.silence {
+            self.set_silence(true).await?;
+        }
+
+        let mut attempt = 0;
+        let result = loop {
+            match self.try_upload(image, &mut progress).await {
+                Err(e) if attempt < self.retries && is_retryable(&e) => attempt += 1,
+                result => break result,
+            }
+        };
+
+        if self.silence {
+            // the device restarted, but the others are still silent
+            let released = self.set_silence(false).await;
+            return result.and(released);
+        }
+        result
+    }
+
+    async fn try_upload(&self, image: &[u8], progress: &mut impl FnMut(usize)) -> Result<()> {
+        if let Some(slot) = self.slot {
+            self.select(slot).await?;
+        }
+        self.write(image, &mut *progress).await?;
+        let (crc, valid) = self.verify().await?;
+        if !valid {
+            return Err(Error::Verify {
+                expected: crc32(image),
+                actual: crc,
+            });
+        }
+        self.restart().await?;
+        match self.confirm().await?.state {
+            BootState::Confirmed | BootState::Valid => Ok(()),
+            _ => Err(Error::RolledBack),
+        }
+    }
+
+    /// Sends `image` with `FlashStart` and `FlashWrite` without activating it.
+    /// An interrupted upload of the same image continues where the device
+    /// stopped.
+    pub async fn write(&self, image: &[u8], mut progress: impl FnMut(usize)) -> Result<()> {
+        let stream;
+        let image = if self.compress {
+            self.announce_compression(image).await?;
+            stream = image::compress(image);
+            &stream
+        } else {
+            image
+        };
+        let total = image.len() as u32;
+        let resume = self.start(image).await?;
+        progress(resume);
+
+        let mut rx = self.gateway.subscribe();
+        let mut written = resume;
+        // a compressed upload may continue between two progress reports
+        let ack_interval = ACK_INTERVAL as usize;
+        let (first, rest) = image[resume..]
+            .split_at((resume.next_multiple_of(ack_interval) - resume).min(image.len() - resume));
+        let blocks = Some(first).filter(|b| !b.is_empty());
+        for block in blocks.into_iter().chain(rest.chunks(ack_interval)) {
+            for chunk in block.chunks(CHUNK_LEN) {
+                if !self.pacing.is_zero() {
+                    tokio::time::sleep(self.pacing).await;
+                }
+                self.send(&CanMessage::FlashWrite(Payload::from_slice(chunk).unwrap()))
+                    .await?;
+            }
+            written += block.len();
+            if self.wait_progress(&mut rx, total).await? != written as u32 {
+                return Err(Error::UnexpectedResponse);
+            }
+            progress(written);
+        }
+        Ok(())
+    }
+
+    /// Sends `FlashStart` for `image`, returns the bytes the device already has
+    pub async fn start(&self, image: &[u8]) -> Result<usize> {
+        let total = image.len() as u32;
+        let start = CanMessage::FlashStart {
+            size: total,
+            crc: crc32(image),
+        };
+        let mut rx = self.gateway.subscribe();
+        self.send(&start).await?;
+        let resume = self.wait_progress(&mut rx, total).await? as usize;
+        if resume > image.len() || (!resume.is_multiple_of(CHUNK_LEN) && resume != image.len()) {
+            return Err(Error::UnexpectedResponse);
+        }
+        Ok(resume)
+    }
+
+    /// Tells the device that the next `FlashStart` carries the compressed
+    /// stream of `image`
+    async fn announce_compression(&self, image: &[u8]) -> Result<()> {
+        let msg = CanMessage::FlashCompressed {
+            size: image.len() as u32,
+            crc: crc32(image),
+        };
+        self.request(&msg, self.gateway.timeout(), |answer| *answer == msg)
+            .await?;
+        Ok(())
+    }
+
+    /// Switches an update started with [`start`](Self::start) and the header
+    /// to multicast, see [`MulticastUploader`](crate::multicast::MulticastUploader)
+    pub async fn join(&self) -> Result<()> {
+        self.request(&CanMessage::FlashJoin, ERASE_TIMEOUT, |msg| {
+            *msg == CanMessage::FlashJoin
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Chunks of a multicast update the device is missing and the bytes it has
+    /// without a gap. Only the first
+    /// [`MAX_GAP_REPORTS`](cancomponents_core::update::MAX_GAP_REPORTS) windows
+    /// with gaps are reported.
+    pub async fn gaps(&self) -> Result<(Vec<u32>, u32)> {
+        let mut rx = self.gateway.subscribe();
+        self.send(&CanMessage::Request(CanMessageType::FlashGaps))
+            .await?;
+        let mut chunks = Vec::new();
+        loop {
+            let msg = self
+                .wait(&mut rx, self.gateway.timeout(), |msg| {
+                    matches!(
+                        msg,
+                        CanMessage::FlashGaps { .. } | CanMessage::FlashProgress { .. }
+                    )
+                })
+                .await?;
+            match msg {
+                CanMessage::FlashGaps { base, missing } => chunks.extend(
+                    (0..WINDOW)
+                        .filter(|n| missing & 1 << n != 0)
+                        .map(|n| base + n),
+                ),
+                CanMessage::FlashProgress { written, .. } => return Ok((chunks, written)),
+                _ => return Err(Error::UnexpectedResponse),
+            }
+        }
+    }
+
+    /// Bytes written and size of the running update
+    pub async fn progress(&self) -> Result<(u32, u32)> {
+        let msg = self
+            .request(
+                &CanMessage::Request(CanMessageType::FlashProgress),
+                self.gateway.timeout(),
+                |msg| matches!(msg, CanMessage::FlashProgress { .. }),
+            )
+            .await?;
+        match msg {
+            CanMessage::FlashProgress { written, total } => Ok((written, total)),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Slot the next update is written to
+    pub async fn selected_slot(&self) -> Result<u8> {
+        let msg = self
+            .request(
+                &CanMessage::Request(CanMessageType::FlashSelect),
+                self.gateway.timeout(),
+                |msg| matches!(msg, CanMessage::FlashSelect { .. }),
+            )
+            .await?;
+        match msg {
+            CanMessage::FlashSelect { slot } => Ok(slot),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Selects the slot for the next update, the running one is refused
+    pub async fn select(&self, slot: u8) -> Result<()> {
+        self.request(
+            &CanMessage::FlashSelect { slot },
+            self.gateway.timeout(),
+            |msg| *msg == CanMessage::FlashSelect { slot },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Erases a slot, the running one is refused
+    pub async fn erase(&self, slot: u8) -> Result<()> {
+        self.request(&CanMessage::FlashErase { slot }, ERASE_TIMEOUT, |msg| {
+            *msg == CanMessage::FlashErase { slot }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Reads back `len` bytes of the slot being written or selected
+    pub async fn read(&self, offset: u32, len: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let start = offset + data.len() as u32;
+            let n = (len - data.len()).min(MAX_READ_LEN as usize);
+            let mut rx = self.gateway.subscribe();
+            self.send(&CanMessage::FlashRead {
+                offset: start,
+                len: n as u8,
+            })
+            .await?;
+            // answered in words
+            let mut words = vec![None; n.div_ceil(4)];
+            while words.iter().any(Option::is_none) {
+                let msg = self
+                    .wait(&mut rx, self.gateway.timeout(), |msg| {
+                        matches!(msg, CanMessage::FlashData { .. })
+                    })
+                    .await?;
+                if let CanMessage::FlashData { offset, data } = msg {
+                    let index = offset.wrapping_sub(start) as usize / 4;
+                    if offset >= start && index < words.len() {
+                        words[index] = Some(data);
+                    }
+                }
+            }
+            let bytes = words.into_iter().flatten().flatten();
+            data.extend(bytes.take(n));
+        }
+        Ok(data)
+    }
+
+    /// Lets the device read back the written image. A matching CRC activates
+    /// the slot for the next restart.
+    pub async fn verify(&self) -> Result<(u32, bool)> {
+        let msg = self
+            .request(
+                &CanMessage::Request(CanMessageType::FlashVerify),
+                self.boot_timeout,
+                |msg| matches!(msg, CanMessage::FlashVerify { .. }),
+            )
+            .await?;
+        match msg {
+            CanMessage::FlashVerify { crc, valid } => Ok((crc, valid)),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Restarts the device and waits for its announcement
+    pub async fn restart(&self) -> Result<()> {
+        self.request(&CanMessage::Restart, self.boot_timeout, |msg| {
+            *msg == CanMessage::Available { announce: true }
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn boot_status(&self) -> Result<BootStatus> {
+        let msg = CanMessage::Request(CanMessageType::BootStatus);
+        self.wait_boot_status(&msg).await
+    }
+
+    /// Confirms the running image, otherwise the device returns to the previous
+    /// one after a few restarts. The device also confirms by itself once its
+    /// announcement was received.
+    pub async fn confirm(&self) -> Result<BootStatus> {
+        self.wait_boot_status(&CanMessage::BootConfirm).await
+    }
+
+    async fn wait_boot_status(&self, msg: &CanMessage) -> Result<BootStatus> {
+        let msg = self
+            .request(msg, self.gateway.timeout(), |msg| {
+                matches!(msg, CanMessage::BootStatus { .. })
+            })
+            .await?;
+        match msg {
+            CanMessage::BootStatus {
+                slot,
+                state,
+                attempts,
+            } => Ok(BootStatus {
+                slot,
+                state,
+                attempts,
+            }),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    async fn send(&self, msg: &CanMessage) -> Result<()> {
+        self.gateway
+            .send(self.device_type, self.device_id, msg)
+            .await
+    }
+
+    async fn request(
+        &self,
+        msg: &CanMessage,
+        timeout: Duration,
+        filter: impl FnMut(&CanMessage) -> bool,
+    ) -> Result<CanMessage> {
+        let mut rx = self.gateway.subscribe();
+        self.send(msg).await?;
+        self.wait(&mut rx, timeout, filter).await
+    }
+
+    /// Waits for the next acknowledgement, returns the bytes written
+    async fn wait_progress(&self, rx: &mut broadcast::Receiver<Frame>, total: u32) -> Result<u32> {
+        let msg = self
+            .wait(rx, self.gateway.timeout(), |msg| {
+                matches!(msg, CanMessage::FlashProgress { .. })
+            })
+            .await?;
+        match msg {
+            CanMessage::FlashProgress { written, total: t } if t == total => Ok(written),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Waits for a message of the device accepted by `filter`. Error reports
+    /// of the update end the wait.
+    async fn wait(
+        &self,
+        rx: &mut broadcast::Receiver<Frame>,
+        timeout: Duration,
+        mut filter: impl FnMut(&CanMessage) -> bool,
+    ) -> Result<CanMessage> {
+        let deadline = Instant::now() + timeout;
+        self.gateway
+            .wait_for(rx, deadline, |frame| {
+                if !frame.is_from(self.device_type, self.device_id) || frame.remote {
+                    return None;
+                }
+                match frame.decode().ok()? {
+                    CanMessage::DeviceError(report)
+                        if matches!(report.component, Component::Ota | Component::Update) =>
+                    {
+                        Some(Err(Error::Device(report)))
+                    }
+                    msg if filter(&msg) => Some(Ok(msg)),
+                    _ => None,
+                }
+            })
+            .await?
+    }
+
+    /// Silences every device but the target, or releases all of them
+    async fn set_silence(&self, silence: bool) -> Result<()> {
+        self.gateway
+            .send(0, 0, &CanMessage::UpdateSilence(silence))
+            .await?;
+        if silence {
+            self.send(&CanMessage::UpdateSilence(false)).await?;
+        }
+        Ok(())
+    }
+}
+
+fn is_retryable(e: &Error) -> bool {
+    match e {
+        // the same image fails again
+        Error::Device(report) => !matches!(
+            UpdateErrorCode::from(report.local_code),
+            UpdateErrorCode::Signature | UpdateErrorCode::Incompatible
+        ),
+        Error::Timeout | Error::UnexpectedResponse | Error::Verify { .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::VirtualBus;
+    use crate::image;
+    use crate::sim::SimDevice;
+    use cancomponents_core::compression;
+    use cancomponents_core::image_header::{HeaderError, ImageHeader, HEADER_LEN};
+    use cancomponents_core::update::MAX_BOOT_ATTEMPTS;
+
+    /// Image of `len` bytes for device type 4
+    fn image(len: usize) -> Vec<u8> {
+        let firmware: Vec<u8> = (HEADER_LEN..len).map(|i| (i * 7 % 251) as u8).collect();
+        image::build(&ImageHeader::new(&[4]).unwrap(), &firmware)
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_write_error() {
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.fail_at = Some(300);
+        tokio::spawn(device.run(bus.attach()));
+        tokio::spawn(SimDevice::new(4, 20, 2).run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+
+        let image = image(1000);
+        let mut acked = Vec::new();
+        Uploader::new(&gateway, 4, 17)
+            .with_pacing(Duration::ZERO)
+            .upload(&image, |written| acked.push(written))
+            .await
+            .unwrap();
+        assert_eq!(acked.last(), Some(&1000));
+        assert!(acked.contains(&256));
+
+        // the bystander talks again
+        gateway.ping(4, 20).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_error_without_retries() {
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.fail_at = Some(300);
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+
+        let result = Uploader::new(&gateway, 4, 17)
+            .with_pacing(Duration::ZERO)
+            .with_retries(0)
+            .upload(&image(1000), |_| {})
+            .await;
+        match result {
+            Err(Error::Device(report)) => {
+                assert_eq!(report.component, Component::Ota);
+                assert_eq!(report.local_code, UpdateErrorCode::Write as u8);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_restart() {
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.fail_at = Some(9000);
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+        let flash = Uploader::new(&gateway, 4, 17)
+            .with_pacing(Duration::ZERO)
+            .with_retries(0);
+
+        let image = image(10000);
+        assert!(flash.upload(&image, |_| {}).await.is_err());
+        // like a power loss, only the saved progress survives
+        flash.restart().await.unwrap();
+
+        let mut acked = Vec::new();
+        flash
+            .upload(&image, |written| acked.push(written))
+            .await
+            .unwrap();
+        assert_eq!(acked.first(), Some(&8192));
+        assert_eq!(acked.last(), Some(&10000));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_upload() {
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.fail_at = Some(1000);
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+        let flash = Uploader::new(&gateway, 4, 17)
+            .with_pacing(Duration::ZERO)
+            .with_retries(0)
+            .with_compression(true);
+
+        let image = image(20000);
+        let stream = image::compress(&image);
+        assert!(flash.upload(&image, |_| {}).await.is_err());
+        flash.restart().await.unwrap();
+
+        // continues after the last complete frame
+        let mut acked = Vec::new();
+        flash
+            .upload(&image, |written| acked.push(written))
+            .await
+            .unwrap();
+        assert!(acked[0] > 0 && acked[0] < 1000);
+        assert_eq!(acked.last(), Some(&stream.len()));
+        assert_eq!(flash.boot_status().await.unwrap().slot, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_resume_mid_stream() {
+        let image = image(20000);
+        let stream = image::compress(&image);
+        // ends of the frames, each one padded up to the next progress report
+        let mut ends = vec![HEADER_LEN];
+        while let Some(&start) = ends.last().filter(|&&end| end < stream.len()) {
+            let raw = u16::from_le_bytes([stream[start], stream[start + 1]]);
+            let payload = (raw & !compression::STORED) as usize;
+            ends.push(start + compression::frame_len(start as u32, payload));
+        }
+        assert!(ends.len() > 3);
+
+        // inside the first frame the device keeps only the header
+        for frame in [1, 3] {
+            let bus = VirtualBus::new();
+            let mut device = SimDevice::new(4, 17, 1);
+            device.fail_at = Some(ends[frame - 1] as u32 + 2 * CHUNK_LEN as u32);
+            tokio::spawn(device.run(bus.attach()));
+            let gateway = Gateway::new(bus.attach());
+            gateway.ping(4, 17).await.unwrap();
+            let flash = Uploader::new(&gateway, 4, 17)
+                .with_pacing(Duration::ZERO)
+                .with_retries(0)
+                .with_compression(true);
+
+            assert!(flash.upload(&image, |_| {}).await.is_err());
+            flash.restart().await.unwrap();
+
+            let mut acked = Vec::new();
+            flash
+                .upload(&image, |written| acked.push(written))
+                .await
+                .unwrap();
+            assert_eq!(acked[0], ends[frame - 1]);
+            assert_eq!(acked.last(), Some(&stream.len()));
+            assert_eq!(flash.boot_status().await.unwrap().slot, Some(0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_upload() {
+        let secret = image::generate_key().unwrap();
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.key = Some(image::public_key(&secret));
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+        let flash = Uploader::new(&gateway, 4, 17).with_pacing(Duration::ZERO);
+
+        let firmware = image(500);
+        match flash.upload(&firmware, |_| {}).await {
+            Err(Error::Device(report)) => {
+                assert_eq!(report.local_code, UpdateErrorCode::Signature as u8)
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let signed = image::sign(&firmware, &secret);
+        flash.upload(&signed, |_| {}).await.unwrap();
+        assert_eq!(flash.boot_status().await.unwrap().slot, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_image() {
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.set_u8(CanMessageType::HwRev, 3);
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+        let flash = Uploader::new(&gateway, 4, 17).with_pacing(Duration::ZERO);
+
+        let rejected = |result: Result<()>| match result {
+            Err(Error::Device(report)) => {
+                assert_eq!(report.local_code, UpdateErrorCode::Incompatible as u8);
+                HeaderError::try_from(report.details[0]).unwrap()
+            }
+            other => panic!("unexpected {other:?}"),
+        };
+
+        let firmware = [0xE9; 200];
+        let sensor = image::build(&ImageHeader::new(&[5, 6]).unwrap(), &firmware);
+        let result = flash.write(&sensor, |_| {}).await;
+        assert_eq!(rejected(result), HeaderError::DeviceType);
+
+        let mut header = ImageHeader::new(&[4]).unwrap();
+        header.hw_rev_max = 2;
+        let old_board = image::build(&header, &firmware);
+        let result = flash.write(&old_board, |_| {}).await;
+        assert_eq!(rejected(result), HeaderError::HwRev);
+
+        let result = flash.write(&firmware, |_| {}).await;
+        assert_eq!(rejected(result), HeaderError::Missing);
+
+        header.hw_rev_max = 3;
+        flash
+            .upload(&image::build(&header, &firmware), |_| {})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rollback() {
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.boot_failures = 1;
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+        let flash = Uploader::new(&gateway, 4, 17).with_pacing(Duration::ZERO);
+
+        // the second boot of the image gets through
+        flash.upload(&image(300), |_| {}).await.unwrap();
+        let status = flash.boot_status().await.unwrap();
+        assert_eq!(status.slot, Some(0));
+        assert_eq!(status.state, BootState::Confirmed);
+        assert_eq!(status.attempts, 2);
+
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.boot_failures = MAX_BOOT_ATTEMPTS;
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+        let flash = Uploader::new(&gateway, 4, 17).with_pacing(Duration::ZERO);
+
+        assert!(matches!(
+            flash.upload(&image(300), |_| {}).await,
+            Err(Error::RolledBack)
+        ));
+        let status = flash.boot_status().await.unwrap();
+        assert_eq!(status.slot, None);
+        assert_eq!(status.state, BootState::RolledBack);
+    }
+
+    #[tokio::test]
+    async fn test_flash_commands() {
+        let bus = VirtualBus::new();
+        let mut device = SimDevice::new(4, 17, 1);
+        device.running_slot = Some(0);
+        tokio::spawn(device.run(bus.attach()));
+        let gateway = Gateway::new(bus.attach());
+        gateway.ping(4, 17).await.unwrap();
+        let flash = Uploader::new(&gateway, 4, 17).with_pacing(Duration::ZERO);
+
+        assert_eq!(flash.selected_slot().await.unwrap(), 1);
+        assert!(matches!(flash.select(0).await, Err(Error::Device(_))));
+        assert!(matches!(flash.erase(2).await, Err(Error::Device(_))));
+        flash.select(1).await.unwrap();
+
+        let image = image(300);
+        flash.write(&image, |_| {}).await.unwrap();
+        assert_eq!(flash.progress().await.unwrap(), (300, 300));
+        // the header is not stored in the slot
+        assert_eq!(flash.read(0, 292).await.unwrap(), &image[HEADER_LEN..]);
+        assert_eq!(flash.read(101, 7).await.unwrap(), &image[109..116]);
+        assert_eq!(flash.verify().await.unwrap(), (crc32(&image), true));
+
+        flash.erase(1).await.unwrap();
+        assert_eq!(flash.read(0, 4).await.unwrap(), [0xFF; 4]);
+        assert!(matches!(flash.verify().await, Err(Error::Device(_))));
+
+        flash.upload(&image, |_| {}).await.unwrap();
+        assert_eq!(flash.selected_slot().await.unwrap(), 0);
+    }
+}
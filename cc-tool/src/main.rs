@@ -0,0 +1,998 @@
+use anyhow::{bail, Context, Result};
+use cancomponents_core::can_message_type::CanMessageType;
+use cancomponents_core::image_header::{ImageHeader, MAX_DEVICE_TYPES};
+use cancomponents_core::relais_message::{
+    PowerOn, RelaisLimits, RelaisMessage, RelaisMode, RelaisState, RelaisStats, MAX_DURATION,
+};
+use cancomponents_core::shutter::ShutterTiming;
+use cancomponents_core::signature::{self, MAGIC};
+use cancomponents_host::bus::SocketCan;
+use cancomponents_host::gateway::{Gateway, Node};
+use cancomponents_host::image;
+use cancomponents_host::multicast::MulticastUploader;
+use cancomponents_host::update::{BootStatus, Uploader, DEFAULT_RETRIES};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Commissioning and maintenance of cancomponents nodes
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// SocketCAN interface
+    #[arg(short, long, default_value = "can0")]
+    interface: String,
+
+    /// Response timeout in milliseconds
+    #[arg(short, long, default_value_t = 500)]
+    timeout: u64,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List all nodes with uid, version and hardware revision
+    Scan,
+    /// Measure the round trip time to a node
+    Ping {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+    },
+    /// Show everything a node reports on `RequestParameter`
+    Info {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+    },
+    /// Show the recent error reports of a node, the oldest first
+    Log {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+    },
+    /// Assign a new type and id, the node restarts
+    Assign {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        /// New address as type:id
+        #[arg(value_parser = parse_node)]
+        new: Node,
+    },
+    /// Read a setting
+    Get {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        key: Key,
+    },
+    /// Write a setting, single byte values restart the node
+    Set {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        key: Key,
+        value: String,
+    },
+    /// Switch a relay
+    Relais {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        num: u8,
+        state: Switch,
+        /// Switch back or delay by this many milliseconds, at most 24 bits
+        #[arg(short, long, default_value_t = 0, value_parser = parse_duration)]
+        duration: u32,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Move a rollershutter
+    Shutter {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        num: u8,
+        direction: Direction,
+        /// Stop after this many milliseconds, at most 24 bits
+        #[arg(short, long, default_value_t = 0, value_parser = parse_duration)]
+        duration: u32,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Move a rollershutter to a position, needs its travel times
+    ShutterPosition {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        num: u8,
+        /// Percent closed, 0 is fully open
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        percent: u8,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Turn the slats of a venetian blind
+    ShutterTilt {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        num: u8,
+        /// Percent closed, 0 is how the slats stand after a run up
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        percent: u8,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Show the travel times of all rollershutters or store those of one
+    ShutterTiming {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        /// Shutter to store the travel times for
+        #[arg(requires_all = ["up", "down"])]
+        num: Option<u8>,
+        /// Full run up in milliseconds, at most 24 bits
+        #[arg(long, value_parser = parse_duration, requires = "num")]
+        up: Option<u32>,
+        /// Full run down in milliseconds, at most 24 bits
+        #[arg(long, value_parser = parse_duration, requires = "num")]
+        down: Option<u32>,
+        /// Slat turn time of a venetian blind in milliseconds, 0 without slats
+        #[arg(long, default_value_t = 0, value_parser = parse_duration)]
+        turn: u32,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Show or set the pause of the shutter motors between opposite directions
+    ShutterDeadTime {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        /// New dead time in milliseconds
+        #[arg(value_parser = clap::value_parser!(u16))]
+        ms: Option<u16>,
+    },
+    /// Show what all relays do after power-on or set it for one
+    RelaisPowerOn {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        #[arg(requires = "power_on")]
+        num: Option<u8>,
+        #[arg(value_enum, requires = "num")]
+        power_on: Option<Startup>,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Show the safety limits of all relays or set those of one, 0 disables
+    /// a limit
+    RelaisLimits {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        #[arg(requires_all = ["max_on", "min_off"])]
+        num: Option<u8>,
+        /// Longest time the relay stays on in seconds
+        #[arg(long, requires = "num")]
+        max_on: Option<u16>,
+        /// Shortest pause after switching off in seconds
+        #[arg(long, requires = "num")]
+        min_off: Option<u16>,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Show switching cycles and on-time of all relays or reset those of one
+    RelaisStats {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        /// Relay to set back to zero, e.g. after replacing it
+        #[arg(long)]
+        reset: Option<u8>,
+        #[arg(short, long, default_value_t = 0)]
+        bank: u8,
+    },
+    /// Show or set the cycles after which a relay is reported as worn
+    RelaisCycleLimit {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        /// New limit, 0 for no warning
+        cycles: Option<u32>,
+    },
+    /// Show the state and running timers of all relays
+    RelaisState {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+    },
+    /// Show motor state, position and tilt of all rollershutters
+    ShutterState {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+    },
+    /// Put a compatibility header in front of a firmware binary
+    Build {
+        /// Device type the image runs on, can be repeated
+        #[arg(short, long = "device-type", required = true)]
+        device_types: Vec<u8>,
+        /// Lowest supported hardware revision
+        #[arg(long, default_value_t = 0)]
+        hw_rev_min: u8,
+        /// Highest supported hardware revision
+        #[arg(long, default_value_t = u8::MAX)]
+        hw_rev_max: u8,
+        firmware: PathBuf,
+        output: PathBuf,
+    },
+    /// Show header and signature of an image
+    Inspect { image: PathBuf },
+    /// Create a key pair for signed images, the public key goes into the
+    /// firmware build with CC_UPDATE_KEY
+    Keygen {
+        /// Secret key file to create
+        key: PathBuf,
+        /// Also write the public key to this file
+        #[arg(long)]
+        public: Option<PathBuf>,
+    },
+    /// Append the signature trailer to a firmware image
+    Sign {
+        /// Secret key file written by keygen
+        #[arg(short, long)]
+        key: PathBuf,
+        firmware: PathBuf,
+        output: PathBuf,
+    },
+    /// Upload a firmware image to several identical nodes at once
+    Multicast {
+        image: PathBuf,
+        /// Nodes to update as type:id
+        #[arg(value_parser = parse_node, required_unless_present = "device_type")]
+        nodes: Vec<Node>,
+        /// Also update every node of this type found by a scan
+        #[arg(short, long)]
+        device_type: Option<u8>,
+        /// Gap between two frames in milliseconds
+        #[arg(long, default_value_t = 1)]
+        pacing: u64,
+        /// Rounds of retransmission without progress before giving up
+        #[arg(long, default_value_t = DEFAULT_RETRIES)]
+        retries: usize,
+        /// Keep the other nodes talking during the upload
+        #[arg(long)]
+        no_silence: bool,
+    },
+    /// Firmware update and access to the OTA slots
+    Flash {
+        #[arg(value_parser = parse_node)]
+        node: Node,
+        #[command(subcommand)]
+        command: FlashCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum FlashCommand {
+    /// Upload a firmware image, the node restarts into it
+    Upload {
+        image: PathBuf,
+        /// Gap between two frames in milliseconds
+        #[arg(long, default_value_t = 1)]
+        pacing: u64,
+        #[arg(long, default_value_t = DEFAULT_RETRIES)]
+        retries: usize,
+        /// Slot to write, by default the one not running
+        #[arg(long)]
+        slot: Option<u8>,
+        /// Keep the other nodes talking during the upload
+        #[arg(long)]
+        no_silence: bool,
+        /// Send the image LZ4 compressed, the node unpacks it
+        #[arg(long)]
+        compress: bool,
+    },
+    /// Show the progress of the running update
+    Progress,
+    /// Show or select the slot for the next update
+    Select { slot: Option<u8> },
+    /// Erase a slot
+    Erase { slot: u8 },
+    /// Hexdump a part of the slot being written
+    Read {
+        #[arg(value_parser = parse_number)]
+        offset: u32,
+        #[arg(value_parser = parse_number)]
+        len: u32,
+    },
+    /// Check the written image, a valid one boots on the next restart
+    Verify,
+    /// Show the outcome of the last update
+    Status,
+    /// Confirm the running image, unconfirmed images are rolled back
+    Confirm,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Key {
+    RelaisMode,
+    ExtensionMode,
+    HwRev,
+    Baudrate,
+    CustomString,
+}
+
+impl Key {
+    fn msg_type(self) -> CanMessageType {
+        match self {
+            Key::RelaisMode => CanMessageType::RelaisMode,
+            Key::ExtensionMode => CanMessageType::ExtensionMode,
+            Key::HwRev => CanMessageType::HwRev,
+            Key::Baudrate => CanMessageType::Baudrate,
+            Key::CustomString => CanMessageType::CustomString,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Switch {
+    On,
+    Off,
+    Toggle,
+    /// On for the duration, then back
+    Pulse,
+    /// On after the duration
+    DelayedOn,
+    /// Off after the duration
+    DelayedOff,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Startup {
+    Off,
+    On,
+    /// State before the power went off
+    Last,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Direction {
+    Up,
+    Down,
+    Stop,
+}
+
+/// Parses a node address written as `type:id`
+fn parse_node(s: &str) -> Result<Node, String> {
+    let (device_type, device_id) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected type:id, got {s}"))?;
+    let device_type: u8 = device_type.parse().map_err(|e| format!("type: {e}"))?;
+    let device_id: u8 = device_id.parse().map_err(|e| format!("id: {e}"))?;
+    if device_type > 0x3F {
+        return Err(format!("type {device_type} is out of range (0..=63)"));
+    }
+    Ok(Node {
+        device_type,
+        device_id,
+    })
+}
+
+/// Parses decimal or `0x` prefixed hexadecimal numbers
+fn parse_number(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Durations of a `RelaisMessage` have 24 bits
+fn parse_duration(s: &str) -> Result<u32, String> {
+    let ms = parse_number(s)?;
+    if ms as u64 > MAX_DURATION.as_millis() {
+        return Err(format!("at most {} ms", MAX_DURATION.as_millis()));
+    }
+    Ok(ms)
+}
+
+fn relais_message(num: u8, state: RelaisState, duration: u32, bank: u8) -> RelaisMessage {
+    RelaisMessage {
+        num: num as usize,
+        state,
+        duration: embassy_time::Duration::from_millis(duration as u64),
+        bank,
+        flags: 0,
+    }
+}
+
+/// Channel number, prefixed with its bank on all but the first
+fn channel(num: u8, bank: u8) -> String {
+    let channel = match bank {
+        0 => num.to_string(),
+        bank => format!("{bank}:{num}"),
+    };
+    format!("{channel:>5}")
+}
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".into(), |v| v.to_string())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    // no bus needed
+    match &args.command {
+        Command::Build {
+            device_types,
+            hw_rev_min,
+            hw_rev_max,
+            firmware,
+            output,
+        } => {
+            let Some(mut header) = ImageHeader::new(device_types) else {
+                bail!("at most {MAX_DEVICE_TYPES} device types");
+            };
+            header.hw_rev_min = *hw_rev_min;
+            header.hw_rev_max = *hw_rev_max;
+            return build(&header, firmware, output);
+        }
+        Command::Inspect { image } => return inspect(image),
+        Command::Keygen { key, public } => return keygen(key, public.as_deref()),
+        Command::Sign {
+            key,
+            firmware,
+            output,
+        } => return sign(key, firmware, output),
+        _ => {}
+    }
+
+    let bus = SocketCan::open(&args.interface)
+        .with_context(|| format!("cannot open {}", args.interface))?;
+    let gateway = Gateway::new(bus).with_timeout(Duration::from_millis(args.timeout));
+
+    match args.command {
+        Command::Scan => {
+            println!("type  id  uid           hw  version   custom string");
+            for node in gateway.scan().await? {
+                let params = gateway
+                    .request_parameters(node.device_type, node.device_id)
+                    .await
+                    .unwrap_or_default();
+                println!(
+                    "{:>4} {:>3}  {:<12}  {:>2}  {:<8}  {}",
+                    node.device_type,
+                    node.device_id,
+                    or_dash(params.uid0.map(|uid| format!("{uid:012x}"))),
+                    or_dash(params.hw_rev),
+                    or_dash(params.version),
+                    params.custom_string.unwrap_or_default(),
+                );
+            }
+        }
+        Command::Ping { node } => {
+            let rtt = gateway.ping(node.device_type, node.device_id).await?;
+            println!("{:.1} ms", rtt.as_secs_f64() * 1000.0);
+        }
+        Command::Info { node } => {
+            let params = gateway
+                .request_parameters(node.device_type, node.device_id)
+                .await?;
+            println!(
+                "uid:           {}",
+                or_dash(params.uid0.map(|uid| format!("{uid:012x}")))
+            );
+            println!("version:       {}", or_dash(params.version));
+            println!("hw rev:        {}", or_dash(params.hw_rev));
+            println!("uptime:        {} min", or_dash(params.uptime_minutes));
+            println!("custom string: {}", or_dash(params.custom_string));
+        }
+        Command::Log { node } => {
+            let log = gateway.read_log(node.device_type, node.device_id).await?;
+            for entry in log {
+                let report = entry.report;
+                println!(
+                    "{:>8} s  {:?} {:?} {:?} {}  {:02x?}",
+                    entry.uptime,
+                    report.severity,
+                    report.component,
+                    report.code,
+                    report.local_code,
+                    report.details
+                );
+            }
+        }
+        Command::Assign { node, new } => {
+            gateway
+                .assign(
+                    node.device_type,
+                    node.device_id,
+                    new.device_type,
+                    new.device_id,
+                )
+                .await?;
+            println!("now at {}:{}", new.device_type, new.device_id);
+        }
+        Command::Get { node, key } => match key {
+            Key::CustomString => {
+                let value = gateway
+                    .read_string(node.device_type, node.device_id, key.msg_type())
+                    .await?;
+                println!("{value}");
+            }
+            _ => {
+                let value = gateway
+                    .read_u8(node.device_type, node.device_id, key.msg_type())
+                    .await?;
+                println!("{value}");
+            }
+        },
+        Command::Set { node, key, value } => match key {
+            Key::CustomString => {
+                gateway
+                    .write_string(node.device_type, node.device_id, key.msg_type(), &value)
+                    .await?
+            }
+            Key::HwRev | Key::Baudrate | Key::RelaisMode | Key::ExtensionMode => {
+                let value: u8 = value.parse().context("value must be 0..=255")?;
+                // the node would reject it and keep running unchanged
+                if matches!(key, Key::RelaisMode) && RelaisMode::try_from(value).is_err() {
+                    bail!("relais mode must be 0 (relais) or 1, 2 (shutters)");
+                }
+                gateway
+                    .write_u8(node.device_type, node.device_id, key.msg_type(), value)
+                    .await?
+            }
+        },
+        Command::Relais {
+            node,
+            num,
+            state,
+            duration,
+            bank,
+        } => {
+            let state = match state {
+                Switch::On => RelaisState::On,
+                Switch::Off => RelaisState::Off,
+                Switch::Toggle => RelaisState::Toggle,
+                Switch::Pulse => RelaisState::Pulse,
+                Switch::DelayedOn => RelaisState::DelayedOn,
+                Switch::DelayedOff => RelaisState::DelayedOff,
+            };
+            let msg = relais_message(num, state, duration, bank);
+            gateway
+                .relais(node.device_type, node.device_id, msg)
+                .await?;
+        }
+        Command::Shutter {
+            node,
+            num,
+            direction,
+            duration,
+            bank,
+        } => {
+            let state = match direction {
+                Direction::Up => RelaisState::Up,
+                Direction::Down => RelaisState::Down,
+                Direction::Stop => RelaisState::Off,
+            };
+            let msg = relais_message(num, state, duration, bank);
+            gateway
+                .rollershutter(node.device_type, node.device_id, msg)
+                .await?;
+        }
+        Command::ShutterPosition {
+            node,
+            num,
+            percent,
+            bank,
+        } => {
+            gateway
+                .shutter_position(node.device_type, node.device_id, num, percent, bank)
+                .await?;
+        }
+        Command::ShutterTilt {
+            node,
+            num,
+            percent,
+            bank,
+        } => {
+            gateway
+                .shutter_tilt(node.device_type, node.device_id, num, percent, bank)
+                .await?;
+        }
+        Command::ShutterTiming {
+            node,
+            num: Some(num),
+            up: Some(up),
+            down: Some(down),
+            turn,
+            bank,
+        } => {
+            let millis = |ms: u32| embassy_time::Duration::from_millis(ms as u64);
+            let timing = ShutterTiming {
+                up: millis(up),
+                down: millis(down),
+                turn: millis(turn),
+            };
+            gateway
+                .write_shutter_timing(node.device_type, node.device_id, num, timing, bank)
+                .await?;
+        }
+        Command::ShutterTiming { node, .. } => {
+            let timings = gateway
+                .read_shutter_timings(node.device_type, node.device_id)
+                .await?;
+            for (num, bank, timing) in timings {
+                let channel = channel(num, bank);
+                if timing.is_calibrated() {
+                    let (up, down) = (timing.up.as_millis(), timing.down.as_millis());
+                    print!("{channel}  up {up} ms  down {down} ms");
+                    if timing.has_slats() {
+                        print!("  turn {} ms", timing.turn.as_millis());
+                    }
+                    println!();
+                } else {
+                    println!("{channel}  not calibrated");
+                }
+            }
+        }
+        Command::ShutterDeadTime { node, ms: Some(ms) } => {
+            let dead_time = embassy_time::Duration::from_millis(ms as u64);
+            gateway
+                .write_shutter_dead_time(node.device_type, node.device_id, dead_time)
+                .await?;
+        }
+        Command::ShutterDeadTime { node, ms: None } => {
+            let dead_time = gateway
+                .read_shutter_dead_time(node.device_type, node.device_id)
+                .await?;
+            println!("{} ms", dead_time.as_millis());
+        }
+        Command::RelaisPowerOn {
+            node,
+            num: Some(num),
+            power_on: Some(power_on),
+            bank,
+        } => {
+            let power_on = match power_on {
+                Startup::Off => PowerOn::Off,
+                Startup::On => PowerOn::On,
+                Startup::Last => PowerOn::Last,
+            };
+            gateway
+                .write_power_on(node.device_type, node.device_id, num, power_on, bank)
+                .await?;
+        }
+        Command::RelaisPowerOn { node, .. } => {
+            let power_on = gateway
+                .read_power_on(node.device_type, node.device_id)
+                .await?;
+            for (num, bank, power_on) in power_on {
+                println!("{}  {power_on:?}", channel(num, bank));
+            }
+        }
+        Command::RelaisLimits {
+            node,
+            num: Some(num),
+            max_on: Some(max_on),
+            min_off: Some(min_off),
+            bank,
+        } => {
+            let secs = |s: u16| embassy_time::Duration::from_secs(s as u64);
+            let limits = RelaisLimits {
+                max_on: secs(max_on),
+                min_off: secs(min_off),
+            };
+            gateway
+                .write_limits(node.device_type, node.device_id, num, limits, bank)
+                .await?;
+        }
+        Command::RelaisLimits { node, .. } => {
+            let limits = gateway
+                .read_limits(node.device_type, node.device_id)
+                .await?;
+            for (num, bank, limits) in limits {
+                let secs = |d: embassy_time::Duration| match d.as_secs() {
+                    0 => "none".to_string(),
+                    s => format!("{s} s"),
+                };
+                println!(
+                    "{}  max on {}  min off {}",
+                    channel(num, bank),
+                    secs(limits.max_on),
+                    secs(limits.min_off)
+                );
+            }
+        }
+        Command::RelaisStats {
+            node,
+            reset: Some(num),
+            bank,
+        } => {
+            let stats = RelaisStats::default();
+            gateway
+                .write_stats(node.device_type, node.device_id, num, stats, bank)
+                .await?;
+        }
+        Command::RelaisStats { node, .. } => {
+            let stats = gateway.read_stats(node.device_type, node.device_id).await?;
+            for (num, bank, stats) in stats {
+                let hours = stats.on_time.as_secs() / 3600;
+                println!(
+                    "{}  {} cycles  {hours} h on",
+                    channel(num, bank),
+                    stats.cycles
+                );
+            }
+        }
+        Command::RelaisCycleLimit {
+            node,
+            cycles: Some(cycles),
+        } => {
+            gateway
+                .write_cycle_limit(node.device_type, node.device_id, cycles)
+                .await?;
+        }
+        Command::RelaisCycleLimit { node, cycles: None } => {
+            let cycles = gateway
+                .read_cycle_limit(node.device_type, node.device_id)
+                .await?;
+            println!("{cycles} cycles");
+        }
+        Command::RelaisState { node } => {
+            let states = gateway
+                .read_relais_states(node.device_type, node.device_id)
+                .await?;
+            for status in states {
+                print!("{}  {:?}", channel(status.num, status.bank), status.state);
+                print_remaining(status.remaining);
+                println!();
+            }
+        }
+        Command::ShutterState { node } => {
+            let states = gateway
+                .read_shutter_states(node.device_type, node.device_id)
+                .await?;
+            for status in states {
+                print!("{}  {:?}", channel(status.num, status.bank), status.state);
+                match status.position {
+                    Some(percent) => print!("  {percent} % closed"),
+                    None => print!("  position unknown"),
+                }
+                if let Some(percent) = status.tilt {
+                    print!("  tilt {percent} %");
+                }
+                print_remaining(status.remaining);
+                println!();
+            }
+        }
+        Command::Build { .. }
+        | Command::Inspect { .. }
+        | Command::Keygen { .. }
+        | Command::Sign { .. } => unreachable!(),
+        Command::Multicast {
+            image,
+            mut nodes,
+            device_type,
+            pacing,
+            retries,
+            no_silence,
+        } => {
+            let image = read_image(&image)?;
+            if let Some(device_type) = device_type {
+                for node in gateway.scan().await? {
+                    if node.device_type == device_type && !nodes.contains(&node) {
+                        nodes.push(node);
+                    }
+                }
+            }
+            if nodes.is_empty() {
+                bail!("no nodes to update");
+            }
+            let total = image.len();
+            let results = MulticastUploader::new(&gateway, &nodes)
+                .with_pacing(Duration::from_millis(pacing))
+                .with_retries(retries)
+                .with_silence(!no_silence)
+                .upload(&image, |sent| {
+                    print!("\r{sent}/{total} bytes");
+                    let _ = std::io::stdout().flush();
+                })
+                .await?;
+            println!();
+            let mut failed = 0;
+            for (node, result) in nodes.iter().zip(results) {
+                let status = match result {
+                    Ok(()) => String::from("ok"),
+                    Err(e) => {
+                        failed += 1;
+                        e.to_string()
+                    }
+                };
+                println!("{:>4} {:>3}  {status}", node.device_type, node.device_id);
+            }
+            if failed > 0 {
+                bail!("{failed} of {} nodes failed", nodes.len());
+            }
+        }
+        Command::Flash { node, command } => {
+            let uploader = Uploader::new(&gateway, node.device_type, node.device_id);
+            flash(uploader, command).await?
+        }
+    }
+    Ok(())
+}
+
+async fn flash(uploader: Uploader<'_, SocketCan>, command: FlashCommand) -> Result<()> {
+    match command {
+        FlashCommand::Upload {
+            image,
+            pacing,
+            retries,
+            slot,
+            no_silence,
+            compress,
+        } => {
+            let image = read_image(&image)?;
+            // progress counts what goes over the bus
+            let total = if compress {
+                image::compress(&image).len()
+            } else {
+                image.len()
+            };
+            let mut uploader = uploader
+                .with_pacing(Duration::from_millis(pacing))
+                .with_retries(retries)
+                .with_silence(!no_silence)
+                .with_compression(compress);
+            if let Some(slot) = slot {
+                uploader = uploader.with_slot(slot);
+            }
+            uploader
+                .upload(&image, |written| {
+                    print!("\r{written}/{total} bytes");
+                    let _ = std::io::stdout().flush();
+                })
+                .await?;
+            println!();
+        }
+        FlashCommand::Progress => {
+            let (written, total) = uploader.progress().await?;
+            println!("{written}/{total} bytes");
+        }
+        FlashCommand::Select { slot: Some(slot) } => uploader.select(slot).await?,
+        FlashCommand::Select { slot: None } => println!("{}", uploader.selected_slot().await?),
+        FlashCommand::Erase { slot } => uploader.erase(slot).await?,
+        FlashCommand::Read { offset, len } => {
+            let data = uploader.read(offset, len as usize).await?;
+            for (i, line) in data.chunks(16).enumerate() {
+                let hex: Vec<_> = line.iter().map(|b| format!("{b:02x}")).collect();
+                println!("{:08x}  {}", offset as usize + 16 * i, hex.join(" "));
+            }
+        }
+        FlashCommand::Verify => {
+            let (crc, valid) = uploader.verify().await?;
+            println!("crc {crc:08x}, {}", if valid { "valid" } else { "invalid" });
+            if !valid {
+                bail!("image does not match");
+            }
+        }
+        FlashCommand::Status => print_boot_status(uploader.boot_status().await?),
+        FlashCommand::Confirm => print_boot_status(uploader.confirm().await?),
+    }
+    Ok(())
+}
+
+/// Reads an image ready for upload, with header and signature
+fn read_image(path: &Path) -> Result<Vec<u8>> {
+    let image = std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    if image.is_empty() {
+        bail!("image is empty");
+    }
+    if ImageHeader::from_bytes(&image).is_err() {
+        bail!("image has no header, see cc-tool build");
+    }
+    if !is_signed(&image) {
+        bail!("image is not signed, see cc-tool sign");
+    }
+    Ok(image)
+}
+
+fn build(header: &ImageHeader, firmware: &Path, output: &Path) -> Result<()> {
+    let data =
+        std::fs::read(firmware).with_context(|| format!("cannot read {}", firmware.display()))?;
+    if ImageHeader::from_bytes(&data).is_ok() {
+        bail!("{} has a header already", firmware.display());
+    }
+    std::fs::write(output, image::build(header, &data))
+        .with_context(|| format!("cannot write {}", output.display()))?;
+    Ok(())
+}
+
+fn inspect(path: &Path) -> Result<()> {
+    let data = std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let info = image::inspect(&data);
+    match &info.header {
+        Some(header) => {
+            println!("protocol:     {}", header.protocol);
+            println!("device types: {:?}", header.device_types.as_slice());
+            println!(
+                "hw rev:       {}..={}",
+                header.hw_rev_min, header.hw_rev_max
+            );
+        }
+        None => println!("header:       missing"),
+    }
+    println!("firmware:     {} bytes", info.firmware_len);
+    println!("signed:       {}", if info.signed { "yes" } else { "no" });
+    println!("crc:          {:08x}", info.crc);
+    Ok(())
+}
+
+fn keygen(path: &Path, public: Option<&Path>) -> Result<()> {
+    if path.exists() {
+        bail!("{} exists already", path.display());
+    }
+    let secret = image::generate_key()?;
+    image::write_key(path, &secret).with_context(|| format!("cannot write {}", path.display()))?;
+    let key = image::public_key(&secret);
+    if let Some(public) = public {
+        image::write_key(public, &key)
+            .with_context(|| format!("cannot write {}", public.display()))?;
+    }
+    println!("{}", image::to_hex(&key));
+    Ok(())
+}
+
+fn sign(key: &Path, firmware: &Path, output: &Path) -> Result<()> {
+    let secret = image::read_key(key)?;
+    let data =
+        std::fs::read(firmware).with_context(|| format!("cannot read {}", firmware.display()))?;
+    if is_signed(&data) {
+        bail!("{} is signed already", firmware.display());
+    }
+    std::fs::write(output, image::sign(&data, &secret))
+        .with_context(|| format!("cannot write {}", output.display()))?;
+    Ok(())
+}
+
+fn is_signed(image: &[u8]) -> bool {
+    signature::split(image).is_ok_and(|(_, trailer)| trailer.ends_with(&MAGIC))
+}
+
+fn print_boot_status(status: BootStatus) {
+    let slot = or_dash(status.slot);
+    println!(
+        "slot {slot}, {:?} after {} boot(s)",
+        status.state, status.attempts
+    );
+}
+
+/// Time until a timer changes the channel, nothing if none is running
+fn print_remaining(remaining: embassy_time::Duration) {
+    if remaining.as_millis() > 0 {
+        print!("  {} ms left", remaining.as_millis());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(
+            parse_node("4:17"),
+            Ok(Node {
+                device_type: 4,
+                device_id: 17
+            })
+        );
+        assert!(parse_node("4").is_err());
+        assert!(parse_node("64:1").is_err());
+        assert!(parse_node("4:256").is_err());
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("4096"), Ok(4096));
+        assert_eq!(parse_number("0x1000"), Ok(4096));
+        assert!(parse_number("x").is_err());
+    }
+
+    #[test]
+    fn test_args() {
+        Args::command().debug_assert();
+    }
+}